@@ -1,6 +1,19 @@
+/* Flash layout, shared with the bootloader (see ../bootloader/memory.x)
+ *
+ * 0x10000000 BOOT2             256B
+ * 0x10000100 bootloader        24K - 256B
+ * 0x10006000 BOOTLOADER_STATE  4K
+ * 0x10007000 ACTIVE            756K   <- this application
+ * 0x100C4000 STORAGE           240K   <- settings and counts, see storage/mod.rs
+ * 0x10100000 cyw43 firmware    264K   <- flashed separately, see net/mod.rs
+ * 0x10142000 DFU               760K   <- staging area for updates
+ */
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    FLASH : ORIGIN = 0x10007000, LENGTH = 756K
+    STORAGE : ORIGIN = 0x100C4000, LENGTH = 240K
+    DFU : ORIGIN = 0x10142000, LENGTH = 760K
 
     /* Pick one of the two options for RAM layout     */
 
@@ -15,3 +28,14 @@ MEMORY {
     /* SCRATCH_A: ORIGIN = 0x20040000, LENGTH = 4K    */
     /* SCRATCH_B: ORIGIN = 0x20041000, LENGTH = 4K    */
 }
+
+/* Offsets from the start of flash, as used by embassy-boot */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
+
+/* Offsets from the start of flash, as used by storage/mod.rs */
+__storage_start = ORIGIN(STORAGE) - ORIGIN(BOOT2);
+__storage_end = ORIGIN(STORAGE) + LENGTH(STORAGE) - ORIGIN(BOOT2);
@@ -3,12 +3,16 @@
 use cyw43_pio::{DEFAULT_CLOCK_DIVIDER, PioSpi};
 use embassy_rp::Peripherals;
 use embassy_rp::adc::{self, Adc, Channel};
+use embassy_rp::flash::{self, Flash};
 use embassy_rp::gpio::{Flex, Input, Level, Output, Pull};
 use embassy_rp::i2c::{self, I2c};
 use embassy_rp::interrupt::typelevel::{ADC_IRQ_FIFO, Binding, I2C0_IRQ, PIO0_IRQ_0};
-use embassy_rp::peripherals::{DMA_CH0, I2C0, PIO0};
+use embassy_rp::peripherals::{DMA_CH0, FLASH, I2C0, PIO0};
 use embassy_rp::pio::Pio;
 use embassy_rp::pio::{self};
+use embassy_rp::watchdog::Watchdog;
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
 
 #[allow(dead_code)]
 pub struct Automation2040W<'d> {
@@ -40,6 +44,8 @@ pub struct Automation2040W<'d> {
     pub pwr: Output<'d>,
     pub spi: PioSpi<'d, PIO0, 0, DMA_CH0>,
     pub adc: Adc<'d, adc::Async>,
+    pub flash: Flash<'d, FLASH, flash::Async, FLASH_SIZE>,
+    pub watchdog: Watchdog,
 }
 
 impl Automation2040W<'_> {
@@ -100,6 +106,9 @@ impl Automation2040W<'_> {
             p.DMA_CH0,
         );
 
+        let flash = Flash::new(p.FLASH, p.DMA_CH1);
+        let watchdog = Watchdog::new(p.WATCHDOG);
+
         Self {
             gp0,
             gp1,
@@ -129,6 +138,8 @@ impl Automation2040W<'_> {
             pwr,
             spi,
             adc,
+            flash,
+            watchdog,
         }
     }
 }
@@ -0,0 +1,30 @@
+//! The ticket dispenser, driven from `output_1` with its notch sensor on
+//! `in_buffered_4`.
+
+use embassy_rp::gpio::{Input, Output};
+use symmetrical_octo_chainsaw_shared::tickets::Dispenser;
+
+pub struct BoardDispenser<'d> {
+    drive: Output<'d>,
+    notch: Input<'d>,
+}
+
+impl<'d> BoardDispenser<'d> {
+    pub fn new(drive: Output<'d>, notch: Input<'d>) -> Self {
+        Self { drive, notch }
+    }
+}
+
+impl Dispenser for BoardDispenser<'_> {
+    fn set_running(&mut self, running: bool) {
+        self.drive.set_level(running.into());
+    }
+
+    fn at_notch(&mut self) -> bool {
+        self.notch.is_high()
+    }
+
+    async fn wait_for_notch(&mut self) {
+        self.notch.wait_for_rising_edge().await;
+    }
+}
@@ -2,11 +2,20 @@
 #![no_main]
 
 mod automation_2040w;
+mod dispenser;
 mod mcp23017;
+mod mqtt;
 mod net;
+mod ota;
 mod rats_nest;
+mod sntp;
+mod stack;
+mod storage;
 
+use crate::dispenser::BoardDispenser;
+use crate::ota::{BoardFlash, Ota};
 use crate::rats_nest::RatsNest;
+use crate::storage::FlashStorage;
 use defmt::*;
 use edge_nal::TcpBind;
 use edge_nal_embassy::TcpBuffers;
@@ -14,15 +23,35 @@ use embassy_executor::Spawner;
 use embassy_net::Stack;
 use embassy_rp::adc::{self};
 use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::gpio::Input;
 use embassy_rp::i2c::{self};
 use embassy_rp::peripherals::{I2C0, PIO0};
 use embassy_rp::pio::{self};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
 use embassy_sync::signal::Signal;
-use embassy_time::Timer;
+use embassy_sync::watch::Receiver;
+use embassy_time::{Instant, Timer};
 use static_cell::StaticCell;
+use symmetrical_octo_chainsaw_shared::arbiter::{self, Arbiter, Arbitration};
+use symmetrical_octo_chainsaw_shared::ball_flow::BallFlow;
+use symmetrical_octo_chainsaw_shared::bookkeeping::Bookkeeping;
+use symmetrical_octo_chainsaw_shared::clock::WallClock;
+use symmetrical_octo_chainsaw_shared::credits::{self, Credits};
+use symmetrical_octo_chainsaw_shared::events::{EventLog, Fault};
+use symmetrical_octo_chainsaw_shared::forces::InputForces;
+use symmetrical_octo_chainsaw_shared::health::SensorHealth;
+use symmetrical_octo_chainsaw_shared::http::auth::{Auth, AuthConfig};
 use symmetrical_octo_chainsaw_shared::http::run_server;
-use symmetrical_octo_chainsaw_shared::pac_man_ball::{Inputs, Io, Outputs};
+use symmetrical_octo_chainsaw_shared::metrics::Metrics;
+use symmetrical_octo_chainsaw_shared::modbus::{self, run_modbus_server, ModbusConfig};
+use symmetrical_octo_chainsaw_shared::mqtt::discovery::Discovery;
+use symmetrical_octo_chainsaw_shared::mqtt::MqttConfig;
+use symmetrical_octo_chainsaw_shared::pac_man_ball::{Inputs, Io, IoState, Outputs};
+use symmetrical_octo_chainsaw_shared::self_test::SelfTest;
+use symmetrical_octo_chainsaw_shared::tachometer::Tachometer;
+use symmetrical_octo_chainsaw_shared::tickets::Tickets;
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
@@ -31,11 +60,39 @@ bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
 });
 
+static CLOCK: WallClock = WallClock::new();
+static METRICS: Metrics = Metrics::new();
+static IO_STATE: IoState = IoState::new();
+static ARBITER: Arbiter = Arbiter::new();
+static FORCES: InputForces = InputForces::new();
+static BOOKKEEPING: Bookkeeping = Bookkeeping::new(&CLOCK);
+static CREDITS: Credits = Credits::new(&BOOKKEEPING);
+static SELF_TEST: SelfTest = SelfTest::new(&ARBITER, &IO_STATE, &CLOCK);
+static EVENTS: EventLog = EventLog::new(&CLOCK);
+static TACHOMETER: Tachometer = Tachometer::new(&ARBITER, &EVENTS, &CLOCK);
+static BALL_FLOW: BallFlow = BallFlow::new(&EVENTS);
+static HEALTH: SensorHealth = SensorHealth::new(&EVENTS, &TACHOMETER, &BALL_FLOW);
+static TICKETS: Tickets = Tickets::new(&EVENTS, &BOOKKEEPING);
+// What the I/O loop read and applied, for the MQTT bridge.
+static MQTT_INPUTS: Signal<CriticalSectionRawMutex, Inputs> = Signal::new();
+static MQTT_OUTPUTS: Signal<CriticalSectionRawMutex, Outputs> = Signal::new();
+
+// Log against the wall clock once SNTP has synchronised it. Until then the uptime is
+// logged instead, which shows up as a time on 1970-01-01.
+defmt::timestamp!(
+    "{=u64:iso8601ms}",
+    CLOCK
+        .now()
+        .map(|t| t.0)
+        .unwrap_or_else(|| embassy_time::Instant::now().as_millis())
+);
+
 #[embassy_executor::task]
 pub async fn http_task(
     stack: Stack<'static>,
-    ingress_signal: &'static Signal<CriticalSectionRawMutex, Outputs>,
     egress_signal: &'static Signal<CriticalSectionRawMutex, Inputs>,
+    ota: &'static Mutex<CriticalSectionRawMutex, Ota>,
+    auth: &'static Auth<'static>,
 ) -> ! {
     let addr = "0.0.0.0:80".parse().expect("invalid address");
 
@@ -49,8 +106,48 @@ pub async fn http_task(
             info!("Binding to {}", addr);
             tcp.bind(addr).await
         },
-        ingress_signal,
         egress_signal,
+        &CLOCK,
+        ota,
+        auth,
+        &METRICS,
+        &ARBITER,
+        &FORCES,
+        &SELF_TEST,
+        &TACHOMETER,
+        &BALL_FLOW,
+        &CREDITS,
+        &TICKETS,
+        &BOOKKEEPING,
+        &EVENTS,
+    )
+    .await
+}
+
+#[embassy_executor::task]
+async fn modbus_task(stack: Stack<'static>) -> ! {
+    let addr = core::net::SocketAddr::new(
+        core::net::Ipv4Addr::UNSPECIFIED.into(),
+        modbus::PORT,
+    );
+
+    static BUFFERS: StaticCell<TcpBuffers<2, 512, 512>> = StaticCell::new();
+    let buffers = BUFFERS.init(TcpBuffers::new());
+
+    let tcp = edge_nal_embassy::Tcp::new(stack, buffers);
+
+    run_modbus_server(
+        || async {
+            info!("Binding Modbus to {}", addr);
+            tcp.bind(addr).await
+        },
+        &ModbusConfig {
+            writers: option_env!("MODBUS_WRITERS").unwrap_or(""),
+        },
+        &IO_STATE,
+        &ARBITER,
+        &FORCES,
+        &METRICS,
     )
     .await
 }
@@ -58,25 +155,152 @@ pub async fn http_task(
 #[embassy_executor::task]
 async fn pipe_task(
     rats_nest: &'static mut RatsNest<'static, I2C0>,
-    ingress_signal: &'static Signal<CriticalSectionRawMutex, Outputs>,
     egress_signal: &'static Signal<CriticalSectionRawMutex, Inputs>,
+    mut arbitration: Receiver<'static, CriticalSectionRawMutex, Arbitration, { arbiter::MAX_WATCHERS }>,
 ) -> ! {
+    // The latest arbitration not yet applied, retried until it is, so that a
+    // failed write can't leave a safety claim off the hardware.
+    let mut pending: Option<Arbitration> = None;
     loop {
-        let inputs = unwrap!(rats_nest.inputs().await);
-        egress_signal.signal(inputs);
+        match rats_nest.inputs().await {
+            Ok(inputs) => {
+                let inputs = FORCES.apply(inputs);
+                METRICS.record_inputs(&inputs);
+                IO_STATE.set_inputs(&inputs);
+                EVENTS.record_inputs(&inputs, &FORCES.forces());
+                TACHOMETER.record_inputs(&inputs);
+                BALL_FLOW.record_inputs(&inputs);
+                HEALTH.record_inputs(&inputs);
+                BOOKKEEPING.record_inputs(&inputs);
+                MQTT_INPUTS.signal(inputs.clone());
+                egress_signal.signal(inputs);
+            }
+            Err(e) => {
+                warn!("Failed to read inputs: {:?}", e);
+                METRICS.record_i2c_error(e.address);
+                EVENTS.record_fault(Fault::I2c {
+                    expander: e.address,
+                });
+            }
+        }
 
-        if let Some(outputs) = ingress_signal.try_take() {
-            unwrap!(rats_nest.set_outputs(outputs).await);
+        if let Some(arbitration) = arbitration.try_changed() {
+            pending = Some(arbitration);
+        }
+        if let Some(arbitration) = &pending {
+            let outputs = arbitration.outputs.clone();
+            match rats_nest.set_outputs(outputs.clone()).await {
+                Ok(()) => {
+                    pending = None;
+                    METRICS.record_outputs(&outputs);
+                    IO_STATE.set_outputs(&outputs);
+                    EVENTS.record_outputs(&outputs);
+                    HEALTH.record_outputs(&outputs);
+                    TACHOMETER.record_outputs(&outputs);
+                    BALL_FLOW.record_outputs(&outputs);
+                    MQTT_OUTPUTS.signal(outputs);
+                }
+                Err(e) => {
+                    warn!("Failed to set outputs: {:?}", e);
+                    METRICS.record_i2c_error(e.address);
+                    EVENTS.record_fault(Fault::I2c {
+                        expander: e.address,
+                    });
+                }
+            }
         }
     }
 }
 
+#[embassy_executor::task]
+async fn self_test_task() -> ! {
+    SELF_TEST.run().await
+}
+
+#[embassy_executor::task]
+async fn forces_task(storage: &'static Mutex<CriticalSectionRawMutex, FlashStorage>) -> ! {
+    FORCES.persist(storage).await
+}
+
+#[embassy_executor::task]
+async fn credits_task(storage: &'static Mutex<CriticalSectionRawMutex, FlashStorage>) -> ! {
+    CREDITS.persist(storage).await
+}
+
+/// Time the pulses from one line of the coin mech.
+#[embassy_executor::task(pool_size = credits::LINES)]
+async fn coin_task(line: usize, mut input: Input<'static>) -> ! {
+    loop {
+        // An edge is latched, so one during a flash erase is still seen after it.
+        input.wait_for_rising_edge().await;
+        let start = Instant::now();
+        // Keep the flash from stalling the core until the pulse has been timed.
+        let _hold_off = storage::hold_off();
+        input.wait_for_low().await;
+        CREDITS.record_pulse(line, start.elapsed());
+    }
+}
+
+#[embassy_executor::task]
+async fn tickets_task(mut dispenser: BoardDispenser<'static>) -> ! {
+    TICKETS.run(&mut dispenser).await
+}
+
+#[embassy_executor::task]
+async fn tickets_persist_task(storage: &'static Mutex<CriticalSectionRawMutex, FlashStorage>) -> ! {
+    TICKETS.persist(storage).await
+}
+
+#[embassy_executor::task]
+async fn bookkeeping_task(storage: &'static Mutex<CriticalSectionRawMutex, FlashStorage>) -> ! {
+    BOOKKEEPING.run(storage).await
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    stack::paint();
+
     let p = embassy_rp::init(Default::default());
 
     let board = automation_2040w::Automation2040W::new(p, Irqs);
 
+    static FLASH: StaticCell<Mutex<CriticalSectionRawMutex, BoardFlash>> = StaticCell::new();
+    let flash = FLASH.init(Mutex::new(board.flash));
+
+    static OTA: StaticCell<Mutex<CriticalSectionRawMutex, Ota>> = StaticCell::new();
+    let ota = OTA.init(Mutex::new(Ota::new(flash)));
+
+    static STORAGE: StaticCell<Mutex<CriticalSectionRawMutex, FlashStorage>> = StaticCell::new();
+    let storage = STORAGE.init(Mutex::new(FlashStorage::new(flash)));
+    FORCES.restore(storage).await;
+    unwrap!(spawner.spawn(forces_task(storage)));
+    BOOKKEEPING.restore(storage).await;
+    unwrap!(spawner.spawn(bookkeeping_task(storage)));
+    CREDITS.restore(storage).await;
+    unwrap!(spawner.spawn(credits_task(storage)));
+    for (line, input) in [
+        board.in_buffered_1,
+        board.in_buffered_2,
+        board.in_buffered_3,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        unwrap!(spawner.spawn(coin_task(line, input)));
+    }
+    TICKETS.restore(storage).await;
+    unwrap!(spawner.spawn(tickets_persist_task(storage)));
+    unwrap!(spawner.spawn(tickets_task(BoardDispenser::new(
+        board.output_1,
+        board.in_buffered_4
+    ))));
+
+    let trial = ota.lock().await.is_trial_boot().await;
+    if trial {
+        info!("Trial boot of new firmware");
+    }
+    unwrap!(spawner.spawn(ota::watchdog_task(board.watchdog, trial)));
+
     let stack = net::init(
         spawner,
         option_env!("WIFI_SSID").unwrap_or("symmetrical-octo-chainsaw"),
@@ -84,26 +308,88 @@ async fn main(spawner: Spawner) {
         board.conn_led,
         board.pwr,
         board.spi,
+        &METRICS,
     )
     .await;
 
-    static INGRESS_SIGNAL: StaticCell<Signal<CriticalSectionRawMutex, Outputs>> = StaticCell::new();
-    let ingress_signal = INGRESS_SIGNAL.init(Signal::new());
+    // Before any WS session can take the last watcher.
+    let arbitration = unwrap!(ARBITER.subscribe());
+
     static EGRESS_SIGNAL: StaticCell<Signal<CriticalSectionRawMutex, Inputs>> = StaticCell::new();
     let egress_signal = EGRESS_SIGNAL.init(Signal::new());
 
-    unwrap!(spawner.spawn(http_task(stack, ingress_signal, egress_signal)));
+    unwrap!(spawner.spawn(sntp::sntp_task(
+        stack,
+        option_env!("NTP_SERVER").unwrap_or("pool.ntp.org"),
+        &CLOCK,
+    )));
+    let operator_pin = option_env!("OPERATOR_PIN").unwrap_or_else(|| {
+        warn!("OPERATOR_PIN not set, using the default");
+        "0000"
+    });
+    static AUTH: StaticCell<Auth> = StaticCell::new();
+    let auth = AUTH.init(Auth::new(AuthConfig {
+        operator_pin,
+        viewer_pin: option_env!("VIEWER_PIN"),
+        entropy: || RoscRng.next_u64(),
+    }));
+
+    unwrap!(spawner.spawn(http_task(stack, egress_signal, ota, auth)));
+
+    unwrap!(spawner.spawn(modbus_task(stack)));
+
+    match option_env!("MQTT_BROKER") {
+        Some(broker) => {
+            static MQTT_ID: StaticCell<heapless::String<12>> = StaticCell::new();
+            let id = match option_env!("MQTT_ID") {
+                Some(id) => id,
+                None => MQTT_ID.init(mqtt::default_id(stack)).as_str(),
+            };
+            unwrap!(spawner.spawn(mqtt::mqtt_task(
+                stack,
+                broker,
+                MqttConfig {
+                    id,
+                    username: option_env!("MQTT_USERNAME"),
+                    password: option_env!("MQTT_PASSWORD"),
+                    discovery: match option_env!("MQTT_DISCOVERY_PREFIX") {
+                        Some("") => None,
+                        prefix => Some(Discovery {
+                            prefix: prefix.unwrap_or("homeassistant"),
+                            sw_version: env!("CARGO_PKG_VERSION"),
+                            ip: None,
+                        }),
+                    },
+                },
+                &MQTT_INPUTS,
+                &MQTT_OUTPUTS,
+                &FORCES,
+                &ARBITER,
+                &CLOCK,
+            )));
+        }
+        None => info!("MQTT_BROKER not set, not bridging to MQTT"),
+    }
 
     let mut led = board.user_led_1;
     let i2c = board.i2c;
 
     static RATS_NEST: StaticCell<RatsNest<I2C0>> = StaticCell::new();
     let rats_nest = RATS_NEST.init(unwrap!(RatsNest::new(i2c).await));
+    for address in rats_nest::ADDRESSES {
+        METRICS.register_i2c_expander(address);
+    }
+
+    unwrap!(spawner.spawn(pipe_task(rats_nest, egress_signal, arbitration)));
+    unwrap!(spawner.spawn(self_test_task()));
 
-    unwrap!(spawner.spawn(pipe_task(rats_nest, ingress_signal, egress_signal)));
+    // Only keep this image once it has proven it can reach both the network and the I/O
+    stack.wait_config_up().await;
+    unwrap!(ota.lock().await.mark_booted().await);
 
     loop {
         Timer::after_secs(1).await;
         led.toggle();
+        METRICS.set_stack_free_bytes(stack::free());
     }
 }
@@ -0,0 +1,98 @@
+//! Runs the shared MQTT bridge over embassy-net.
+
+// The task's spawn function doesn't take an `allow` on the task itself.
+#![allow(clippy::too_many_arguments)]
+
+use core::fmt::Write as _;
+use core::net::{IpAddr, SocketAddr};
+
+use defmt::*;
+use edge_nal::TcpConnect;
+use edge_nal_embassy::{Tcp, TcpBuffers};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::{HardwareAddress, Stack};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use static_cell::StaticCell;
+use symmetrical_octo_chainsaw_shared::arbiter::Arbiter;
+use symmetrical_octo_chainsaw_shared::clock::WallClock;
+use symmetrical_octo_chainsaw_shared::forces::InputForces;
+use symmetrical_octo_chainsaw_shared::mqtt::{packet, run_bridge, MqttConfig};
+use symmetrical_octo_chainsaw_shared::pac_man_ball::{Inputs, Outputs};
+
+#[derive(Debug, Format)]
+enum Error {
+    Dns(embassy_net::dns::Error),
+    NoAddress,
+    Port,
+    Tcp(edge_nal_embassy::TcpError),
+}
+
+#[embassy_executor::task]
+pub async fn mqtt_task(
+    stack: Stack<'static>,
+    broker: &'static str,
+    mut config: MqttConfig<'static>,
+    inputs_signal: &'static Signal<CriticalSectionRawMutex, Inputs>,
+    outputs_signal: &'static Signal<CriticalSectionRawMutex, Outputs>,
+    forces: &'static InputForces,
+    arbiter: &'static Arbiter,
+    clock: &'static WallClock,
+) -> ! {
+    static BUFFERS: StaticCell<TcpBuffers<1, 512, 512>> = StaticCell::new();
+    let buffers = BUFFERS.init(TcpBuffers::new());
+
+    let tcp = Tcp::new(stack, buffers);
+
+    // Home Assistant links to the web UI, so wait to find out where it is.
+    if let Some(discovery) = &mut config.discovery {
+        stack.wait_config_up().await;
+        discovery.ip = stack
+            .config_v4()
+            .map(|config| IpAddr::V4(config.address.address()));
+    }
+
+    run_bridge(
+        || async {
+            stack.wait_config_up().await;
+            let address = resolve(stack, broker).await.inspect_err(|e| {
+                warn!("Failed to resolve MQTT broker {}: {:?}", broker, e);
+            })?;
+            info!("Connecting to MQTT broker at {}", address);
+            tcp.connect(address).await.map_err(Error::Tcp)
+        },
+        &config,
+        inputs_signal,
+        outputs_signal,
+        forces,
+        arbiter,
+        clock,
+    )
+    .await
+}
+
+/// Resolve `broker`, a host with an optional port.
+async fn resolve(stack: Stack<'static>, broker: &str) -> Result<SocketAddr, Error> {
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| Error::Port)?),
+        None => (broker, packet::PORT),
+    };
+    let address = *stack
+        .dns_query(host, DnsQueryType::A)
+        .await
+        .map_err(Error::Dns)?
+        .first()
+        .ok_or(Error::NoAddress)?;
+    Ok(SocketAddr::new(IpAddr::from(address), port))
+}
+
+/// The MAC address as hex, which is unique without any configuration.
+pub fn default_id(stack: Stack<'static>) -> heapless::String<12> {
+    let mut id = heapless::String::new();
+    if let HardwareAddress::Ethernet(address) = stack.hardware_address() {
+        for byte in address.as_bytes() {
+            unwrap!(core::write!(id, "{:02x}", byte).ok());
+        }
+    }
+    id
+}
@@ -1,4 +1,4 @@
-use cyw43::JoinOptions;
+use cyw43::{Control, JoinOptions, ScanOptions};
 use cyw43_pio::PioSpi;
 use defmt::*;
 use embassy_executor::Spawner;
@@ -9,6 +9,10 @@ use embassy_rp::{gpio::Output, peripherals::DMA_CH0};
 use embassy_time::Duration;
 use embassy_time::Timer;
 use static_cell::StaticCell;
+use symmetrical_octo_chainsaw_shared::metrics::Metrics;
+
+/// Measuring RSSI means a brief scan, so don't do it often.
+const RSSI_INTERVAL: Duration = Duration::from_secs(60);
 
 #[embassy_executor::task]
 async fn cyw43_task(
@@ -22,6 +26,34 @@ async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'sta
     runner.run().await
 }
 
+/// cyw43 has no direct RSSI query, so scan for the joined network instead.
+#[embassy_executor::task]
+async fn rssi_task(
+    mut control: Control<'static>,
+    ssid: &'static str,
+    stack: Stack<'static>,
+    metrics: &'static Metrics,
+) -> ! {
+    loop {
+        stack.wait_link_up().await;
+
+        let mut options = ScanOptions::default();
+        options.ssid = ssid.try_into().ok();
+        let mut scanner = control.scan(options).await;
+        let mut rssi = None;
+        while let Some(bss) = scanner.next().await {
+            rssi = rssi.max(Some(bss.rssi));
+        }
+        drop(scanner);
+        match rssi {
+            Some(rssi) => metrics.set_rssi_dbm(rssi),
+            None => warn!("Couldn't find '{}' to measure RSSI", ssid),
+        }
+
+        Timer::after(RSSI_INTERVAL).await;
+    }
+}
+
 #[embassy_executor::task]
 async fn conn_led_task(mut led: Output<'static>, stack: Stack<'static>) -> ! {
     loop {
@@ -58,11 +90,12 @@ async fn conn_led_task(mut led: Output<'static>, stack: Stack<'static>) -> ! {
 
 pub async fn init(
     spawner: Spawner,
-    ssid: &str,
+    ssid: &'static str,
     passphrase: &str,
     conn_led: Output<'static>,
     pwr: Output<'static>,
     spi: PioSpi<'static, PIO0, 0, DMA_CH0>,
+    metrics: &'static Metrics,
 ) -> Stack<'static> {
     #[cfg(feature = "include-cyw43-firmware")]
     let fw = include_bytes!("../../../cyw43-firmware/43439A0.bin");
@@ -88,7 +121,8 @@ pub async fn init(
         .set_power_management(cyw43::PowerManagementMode::PowerSave)
         .await;
 
-    static RESOURCES: StaticCell<StackResources<8>> = StaticCell::new();
+    // DHCP, DNS and SNTP take a socket each, on top of HTTP (4), MQTT (1) and Modbus (2).
+    static RESOURCES: StaticCell<StackResources<10>> = StaticCell::new();
     let (stack, runner) = embassy_net::new(
         net_device,
         Config::dhcpv4(Default::default()),
@@ -109,5 +143,7 @@ pub async fn init(
         .await
         .unwrap();
 
+    unwrap!(spawner.spawn(rssi_task(control, ssid, stack, metrics)));
+
     stack
 }
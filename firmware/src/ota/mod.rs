@@ -0,0 +1,125 @@
+//! Firmware updates via embassy-boot.
+//!
+//! The bootloader swaps a staged image into the active partition on the next
+//! boot. If that image never calls `mark_booted`, the watchdog resets the board
+//! and the bootloader swaps the previous image back in.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::*;
+use embassy_boot::FirmwareUpdaterError;
+use embassy_boot_rp::{AlignedBuffer, FirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_embedded_hal::flash::partition::Partition;
+use embassy_rp::flash::{self, Flash, ERASE_SIZE, WRITE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use static_cell::StaticCell;
+use symmetrical_octo_chainsaw_shared::http::ota::Updater;
+
+use crate::automation_2040w::FLASH_SIZE;
+
+pub type BoardFlash = Flash<'static, FLASH, flash::Async, FLASH_SIZE>;
+type FlashPartition = Partition<'static, CriticalSectionRawMutex, BoardFlash>;
+
+/// The bootloader gives up to the watchdog if it isn't fed for this long.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+const FEED_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a freshly swapped in image has to prove itself before we roll back.
+const TRIAL_DEADLINE: Duration = Duration::from_secs(120);
+
+static BOOTED: AtomicBool = AtomicBool::new(false);
+
+pub struct Ota {
+    updater: FirmwareUpdater<'static, FlashPartition, FlashPartition>,
+    capacity: usize,
+}
+
+impl Ota {
+    pub fn new(flash: &'static Mutex<CriticalSectionRawMutex, BoardFlash>) -> Self {
+        // Offsets from the start of flash, provided by memory.x
+        extern "C" {
+            static __bootloader_state_start: u32;
+            static __bootloader_state_end: u32;
+            static __bootloader_dfu_start: u32;
+            static __bootloader_dfu_end: u32;
+        }
+
+        let (dfu, state) = unsafe {
+            let dfu_start = &__bootloader_dfu_start as *const u32 as u32;
+            let dfu_end = &__bootloader_dfu_end as *const u32 as u32;
+            let state_start = &__bootloader_state_start as *const u32 as u32;
+            let state_end = &__bootloader_state_end as *const u32 as u32;
+            (
+                Partition::new(flash, dfu_start, dfu_end - dfu_start),
+                Partition::new(flash, state_start, state_end - state_start),
+            )
+        };
+
+        // The bootloader needs one spare page in the DFU partition to swap with.
+        let capacity = dfu.size() as usize - ERASE_SIZE;
+
+        static ALIGNED: StaticCell<AlignedBuffer<WRITE_SIZE>> = StaticCell::new();
+        let aligned = ALIGNED.init(AlignedBuffer([0; WRITE_SIZE]));
+
+        Self {
+            updater: FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned.0),
+            capacity,
+        }
+    }
+
+    /// Whether this is the first boot of an image the bootloader just swapped in.
+    pub async fn is_trial_boot(&mut self) -> bool {
+        matches!(self.updater.get_state().await, Ok(State::Swap))
+    }
+
+    /// Keep the running image, cancelling the rollback on the next reset.
+    pub async fn mark_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
+        self.updater.mark_booted().await?;
+        BOOTED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Updater for Ota {
+    type Error = FirmwareUpdaterError;
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        self.updater.write_firmware(offset, data).await
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.updater.read_dfu(offset as u32, buf).await
+    }
+
+    async fn mark_updated(&mut self) -> Result<(), Self::Error> {
+        self.updater.mark_updated().await
+    }
+
+    async fn reboot(&mut self) {
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+/// Feeds the watchdog the bootloader started, unless this is a trial boot of a
+/// new image that has not called `mark_booted` before the deadline.
+#[embassy_executor::task]
+pub async fn watchdog_task(mut watchdog: Watchdog, trial: bool) -> ! {
+    watchdog.start(WATCHDOG_TIMEOUT);
+    let deadline = Instant::now() + TRIAL_DEADLINE;
+
+    loop {
+        if trial && !BOOTED.load(Ordering::Relaxed) && Instant::now() > deadline {
+            error!("New firmware did not mark itself booted, rolling back");
+            core::future::pending::<()>().await;
+        }
+        watchdog.feed();
+        Timer::after(FEED_INTERVAL).await;
+    }
+}
@@ -1,24 +1,34 @@
+use defmt::Format;
 use embassy_rp::i2c::{Async, I2c};
 use symmetrical_octo_chainsaw_shared::pac_man_ball::{Inputs, Io, Outputs};
 
 use crate::mcp23017::{self, Direction, Mcp23017, PullUp};
 
-const ADDRESSES: [u8; 3] = [
+pub const ADDRESSES: [u8; 3] = [
     mcp23017::ADDR + 0x01,
     mcp23017::ADDR + 0x02,
     mcp23017::ADDR + 0x04,
 ];
 
+/// An I2C error, and which expander it came from.
+#[derive(Debug, Format)]
+pub struct Error {
+    pub address: u8,
+    pub error: embassy_rp::i2c::Error,
+}
+
 pub struct RatsNest<'d, I2C: embassy_rp::i2c::Instance> {
     i2c: I2c<'d, I2C, Async>,
 }
 
 impl<'d, I2C: embassy_rp::i2c::Instance> RatsNest<'d, I2C> {
-    pub async fn new(i2c: I2c<'d, I2C, Async>) -> Result<Self, embassy_rp::i2c::Error> {
+    pub async fn new(i2c: I2c<'d, I2C, Async>) -> Result<Self, Error> {
         let mut ha = Self { i2c };
         for address in ADDRESSES {
             let mut mcp = Mcp23017::new(&mut ha.i2c, address);
-            Self::configure(&mut mcp).await?;
+            Self::configure(&mut mcp)
+                .await
+                .map_err(|error| Error { address, error })?;
         }
         Ok(ha)
     }
@@ -32,19 +42,23 @@ impl<'d, I2C: embassy_rp::i2c::Instance> RatsNest<'d, I2C> {
         Ok(())
     }
 
-    async fn read_portb(&mut self, address: u8) -> Result<u8, embassy_rp::i2c::Error> {
+    async fn read_portb(&mut self, address: u8) -> Result<u8, Error> {
         let mut mcp = Mcp23017::new(&mut self.i2c, address);
-        mcp.read_gpiob().await
+        mcp.read_gpiob()
+            .await
+            .map_err(|error| Error { address, error })
     }
 
-    async fn write_porta(&mut self, address: u8, value: u8) -> Result<(), embassy_rp::i2c::Error> {
+    async fn write_porta(&mut self, address: u8, value: u8) -> Result<(), Error> {
         let mut mcp = Mcp23017::new(&mut self.i2c, address);
-        mcp.write_gpioa(value).await
+        mcp.write_gpioa(value)
+            .await
+            .map_err(|error| Error { address, error })
     }
 }
 
 impl<I2C: embassy_rp::i2c::Instance> Io for RatsNest<'_, I2C> {
-    type Error = embassy_rp::i2c::Error;
+    type Error = Error;
 
     async fn inputs(&mut self) -> Result<Inputs, Self::Error> {
         let values = [
@@ -0,0 +1,82 @@
+//! Periodically synchronises the shared wall clock against an SNTP server.
+
+use defmt::*;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use symmetrical_octo_chainsaw_shared::clock::{sntp, WallClock};
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Format)]
+enum Error {
+    Dns(embassy_net::dns::Error),
+    NoAddress,
+    Bind(embassy_net::udp::BindError),
+    Send(embassy_net::udp::SendError),
+    Recv(embassy_net::udp::RecvError),
+    Timeout,
+    Sntp(sntp::Error),
+}
+
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>, server: &'static str, clock: &'static WallClock) -> ! {
+    loop {
+        stack.wait_config_up().await;
+
+        match sync(stack, server, clock).await {
+            Ok(()) => Timer::after(SYNC_INTERVAL).await,
+            Err(e) => {
+                warn!("SNTP sync with {} failed: {:?}", server, e);
+                Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn sync(stack: Stack<'static>, server: &str, clock: &WallClock) -> Result<(), Error> {
+    let address = *stack
+        .dns_query(server, DnsQueryType::A)
+        .await
+        .map_err(Error::Dns)?
+        .first()
+        .ok_or(Error::NoAddress)?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; sntp::PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; sntp::PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).map_err(Error::Bind)?;
+
+    let mut buf = [0; sntp::PACKET_LEN];
+    let sent_at = Instant::now();
+    let nonce = sent_at.as_ticks();
+    sntp::request(&mut buf, nonce);
+    socket
+        .send_to(&buf, IpEndpoint::new(address, sntp::PORT))
+        .await
+        .map_err(Error::Send)?;
+
+    let (len, _) = with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Timeout)?
+        .map_err(Error::Recv)?;
+    let received_at = Instant::now();
+
+    let response = sntp::parse_response(&buf[..len], nonce).map_err(Error::Sntp)?;
+    clock.set_at(response.time_at_reception(received_at - sent_at), received_at);
+
+    debug!("SNTP sync with {} complete", server);
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! Stack usage estimate by painting.
+//!
+//! The unused stack is filled with a known pattern at boot. Whatever still holds
+//! the pattern later has never been used, giving the low-water mark.
+
+use core::ptr::{addr_of, addr_of_mut};
+
+const PAINT: u32 = 0xDEAD_BEEF;
+/// Leave the top of the stack alone, as interrupts may push onto it while painting.
+const MARGIN: usize = 1024;
+
+extern "C" {
+    // From cortex-m-rt: the stack grows down from _stack_start towards the end of
+    // the statics at __sheap.
+    static mut __sheap: u32;
+    static _stack_start: u32;
+}
+
+/// Paint the unused stack. Must be called early, before the stack grows deep.
+pub fn paint() {
+    let sp = cortex_m::register::msp::read() as usize;
+    unsafe {
+        let mut word = addr_of_mut!(__sheap);
+        while (word as usize) < sp - MARGIN {
+            word.write_volatile(PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// Bytes of stack that have never been used since `paint`.
+pub fn free() -> u32 {
+    unsafe {
+        let end = addr_of!(_stack_start) as usize;
+        let mut word = addr_of!(__sheap);
+        while (word as usize) < end && word.read_volatile() == PAINT {
+            word = word.add(1);
+        }
+        (word as usize - addr_of!(__sheap) as usize) as u32
+    }
+}
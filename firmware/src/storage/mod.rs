@@ -0,0 +1,260 @@
+//! `Storage` in the spare flash between the application and the cyw43 firmware.
+//!
+//! Each record has a ring of `SECTORS` sectors. Copies are appended one after
+//! another within a sector, numbered in sequence, and the latest whole copy wins,
+//! so a sector is only erased once it has filled up and the ring moves on to the
+//! next, spreading the wear. A power cut while a copy is being written leaves the
+//! copies before it to fall back on.
+//!
+//! Erasing and writing stall the core either way, as code runs from the same
+//! flash, so the blocking calls are used throughout. They wait for any `hold_off`
+//! first, so as not to stall in the middle of something timed, such as a coin
+//! pulse.
+
+use core::cell::Cell;
+
+use defmt::*;
+use embassy_rp::flash::{self, ERASE_SIZE};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Duration, Timer};
+use symmetrical_octo_chainsaw_shared::storage::{Record, Storage, MAX_RECORD_LEN};
+
+use crate::ota::BoardFlash;
+
+/// Marks a whole copy. Written last, so that a copy only counts once it's all there.
+const MAGIC: u32 = u32::from_le_bytes(*b"OCT1");
+/// The length, sequence number, checksum and magic, in that order.
+const HEADER_LEN: usize = 16;
+const SECTORS: usize = 8;
+/// An erased word, which is where the next copy goes.
+const ERASED: u32 = u32::MAX;
+/// The longest to put off erasing or writing for `hold_off`, longer than any
+/// coin pulse that would be accepted.
+const MAX_HOLD_OFF: Duration = Duration::from_millis(200);
+
+static HOLD_OFFS: BlockingMutex<CriticalSectionRawMutex, Cell<usize>> =
+    BlockingMutex::new(Cell::new(0));
+
+/// Puts off erasing and writing the flash while it's held.
+pub struct HoldOff(());
+
+pub fn hold_off() -> HoldOff {
+    HOLD_OFFS.lock(|holds| holds.set(holds.get() + 1));
+    HoldOff(())
+}
+
+impl Drop for HoldOff {
+    fn drop(&mut self) {
+        HOLD_OFFS.lock(|holds| holds.set(holds.get() - 1));
+    }
+}
+
+/// A whole copy of a record.
+#[derive(Clone, Copy)]
+struct Entry {
+    offset: u32,
+    sequence: u32,
+    len: usize,
+}
+
+/// Where a record's copies are.
+#[derive(Clone, Copy)]
+struct Head {
+    latest: Option<Entry>,
+    /// The sector being appended to.
+    sector: usize,
+    /// Where in it the next copy goes, or `None` if it's full.
+    free: Option<usize>,
+}
+
+pub struct FlashStorage {
+    flash: &'static Mutex<CriticalSectionRawMutex, BoardFlash>,
+    /// Offset of the storage region from the start of flash.
+    start: u32,
+    /// Indexed by `Record`, found on first use.
+    heads: [Option<Head>; Record::ALL.len()],
+}
+
+impl FlashStorage {
+    pub fn new(flash: &'static Mutex<CriticalSectionRawMutex, BoardFlash>) -> Self {
+        // Offsets from the start of flash, provided by memory.x
+        extern "C" {
+            static __storage_start: u32;
+            static __storage_end: u32;
+        }
+
+        let (start, end) = unsafe {
+            (
+                &__storage_start as *const u32 as u32,
+                &__storage_end as *const u32 as u32,
+            )
+        };
+        defmt::assert!((end - start) as usize >= Record::ALL.len() * SECTORS * ERASE_SIZE);
+
+        Self {
+            flash,
+            start,
+            heads: [None; Record::ALL.len()],
+        }
+    }
+
+    fn sector(&self, record: Record, sector: usize) -> u32 {
+        self.start + ((record as usize * SECTORS + sector) * ERASE_SIZE) as u32
+    }
+
+    /// Where `record`'s copies are, scanning its sectors the first time.
+    fn head(&mut self, flash: &mut BoardFlash, record: Record) -> Result<Head, flash::Error> {
+        if let Some(head) = self.heads[record as usize] {
+            return Ok(head);
+        }
+
+        let mut latest: Option<Entry> = None;
+        let mut free = [None; SECTORS];
+        for (sector, free) in free.iter_mut().enumerate() {
+            let start = self.sector(record, sector);
+            *free = scan(flash, start, |copy| {
+                if latest.is_none_or(|l| copy.sequence.wrapping_sub(l.sequence) as i32 > 0) {
+                    latest = Some(copy);
+                }
+            })?;
+        }
+        let sector = latest.map_or(0, |latest| {
+            (latest.offset - self.sector(record, 0)) as usize / ERASE_SIZE
+        });
+        let head = Head {
+            latest,
+            sector,
+            free: free[sector],
+        };
+        self.heads[record as usize] = Some(head);
+        Ok(head)
+    }
+}
+
+impl Storage for FlashStorage {
+    type Error = flash::Error;
+
+    async fn load(&mut self, record: Record, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        let mut flash = self.flash.lock().await;
+        let Some(latest) = self.head(&mut flash, record)?.latest else {
+            return Ok(None);
+        };
+        flash.blocking_read(latest.offset + HEADER_LEN as u32, &mut buf[..latest.len])?;
+        Ok(Some(latest.len))
+    }
+
+    async fn save(&mut self, record: Record, data: &[u8]) -> Result<(), Self::Error> {
+        defmt::assert!(data.len() <= MAX_RECORD_LEN);
+        let mut flash = self.flash.lock().await;
+        let mut head = self.head(&mut flash, record)?;
+        let sequence = head.latest.map_or(0, |latest| latest.sequence.wrapping_add(1));
+        let len = copy_len(data.len());
+
+        wait_for_hold_offs().await;
+        let at = match head.free {
+            Some(at) if at + len <= ERASE_SIZE => at,
+            _ => {
+                // Move on to the next sector, which holds the oldest copies.
+                head.sector = (head.sector + 1) % SECTORS;
+                let start = self.sector(record, head.sector);
+                // Forget the sector first, in case the erase fails part way.
+                head.free = None;
+                self.heads[record as usize] = Some(head);
+                flash.blocking_erase(start, start + ERASE_SIZE as u32)?;
+                debug!("Erased sector {} of {}", head.sector, record.name());
+                0
+            }
+        };
+        // Nothing more goes in this sector if the write fails part way.
+        head.free = None;
+        self.heads[record as usize] = Some(head);
+
+        let offset = self.sector(record, head.sector) + at as u32;
+        let mut header = [0_u8; HEADER_LEN];
+        for (bytes, word) in header.chunks_mut(4).zip([
+            data.len() as u32,
+            sequence,
+            checksum(data),
+            MAGIC,
+        ]) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        let (fields, magic) = header.split_at(HEADER_LEN - 4);
+        flash.blocking_write(offset, fields)?;
+        flash.blocking_write(offset + HEADER_LEN as u32, data)?;
+        flash.blocking_write(offset + fields.len() as u32, magic)?;
+
+        head.latest = Some(Entry {
+            offset,
+            sequence,
+            len: data.len(),
+        });
+        head.free = Some(at + len);
+        self.heads[record as usize] = Some(head);
+        debug!("Saved {} to sector {} at {}", record.name(), head.sector, at);
+        Ok(())
+    }
+}
+
+/// Wait until nothing is holding off the flash, or for `MAX_HOLD_OFF` at most.
+async fn wait_for_hold_offs() {
+    let _ = with_timeout(MAX_HOLD_OFF, async {
+        while HOLD_OFFS.lock(Cell::get) > 0 {
+            Timer::after_millis(1).await;
+        }
+    })
+    .await;
+}
+
+/// Pass each whole copy in the sector at `start` to `f`, returning where the next
+/// copy can go, or `None` if the sector is full.
+fn scan(
+    flash: &mut BoardFlash,
+    start: u32,
+    mut f: impl FnMut(Entry),
+) -> Result<Option<usize>, flash::Error> {
+    let mut at = 0;
+    while at + HEADER_LEN <= ERASE_SIZE {
+        let offset = start + at as u32;
+        let mut bytes = [0_u8; HEADER_LEN];
+        flash.blocking_read(offset, &mut bytes)?;
+        let mut words = bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        let [len, sequence, expected, magic] = core::array::from_fn(|_| words.next().unwrap());
+        if len == ERASED {
+            return Ok(Some(at));
+        }
+        // Without a length there's no telling where the next copy starts.
+        let len = len as usize;
+        if len > MAX_RECORD_LEN || at + copy_len(len) > ERASE_SIZE {
+            return Ok(None);
+        }
+
+        let mut data = [0_u8; MAX_RECORD_LEN];
+        let data = &mut data[..len];
+        flash.blocking_read(offset + HEADER_LEN as u32, data)?;
+        if magic == MAGIC && checksum(data) == expected {
+            f(Entry {
+                offset,
+                sequence,
+                len,
+            });
+        }
+        at += copy_len(len);
+    }
+    Ok(None)
+}
+
+/// The space a copy of `len` bytes takes, keeping the headers word aligned.
+const fn copy_len(len: usize) -> usize {
+    HEADER_LEN + len.next_multiple_of(4)
+}
+
+/// FNV-1a, enough to catch a write cut short.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
@@ -0,0 +1,84 @@
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+use embassy_time::{Duration, Instant, Timer};
+use symmetrical_octo_chainsaw_shared::pac_man_ball::{Inputs, Io, Outputs};
+
+use crate::{on_off, TICK};
+
+/// Stands in for the machine: the inputs follow the scenario, and the outputs are
+/// recorded for it to check.
+pub struct SimIo {
+    state: Rc<RefCell<State>>,
+}
+
+impl SimIo {
+    pub(crate) fn new(state: Rc<RefCell<State>>) -> Self {
+        Self { state }
+    }
+}
+
+impl Io for SimIo {
+    type Error = Infallible;
+
+    async fn inputs(&mut self) -> Result<Inputs, Self::Error> {
+        // In place of the I2C transaction, so that logic reading in a loop yields.
+        Timer::after(TICK).await;
+        Ok(self.state.borrow().inputs.clone())
+    }
+
+    async fn set_outputs(&mut self, outputs: Outputs) -> Result<(), Self::Error> {
+        let now = Duration::from_ticks(Instant::now().as_ticks());
+        self.state.borrow_mut().history.push((now, outputs));
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    pub inputs: Inputs,
+    /// Every `set_outputs`, and when it happened.
+    pub history: Vec<(Duration, Outputs)>,
+}
+
+impl State {
+    /// Whether `output` was on at `at`. Outputs are off until first set.
+    pub fn output_at(&self, output: &str, at: Duration) -> bool {
+        self.history
+            .iter()
+            .rev()
+            .find(|(set_at, _)| *set_at <= at)
+            .and_then(|(_, outputs)| outputs.get(output))
+            .unwrap_or_default()
+    }
+
+    /// Whether `output` was `on` at `from`, or got there by `until`.
+    pub fn output_reached(&self, output: &str, on: bool, from: Duration, until: Duration) -> bool {
+        self.output_at(output, from) == on
+            || self
+                .history
+                .iter()
+                .filter(|(set_at, _)| (from..=until).contains(set_at))
+                .any(|(_, outputs)| outputs.get(output) == Some(on))
+    }
+
+    /// Each change to an output, e.g. `512ms table_motor on`.
+    pub fn changes(&self) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut previous = Outputs::default();
+        for (at, outputs) in &self.history {
+            for ((output, on), was) in Outputs::FIELDS
+                .iter()
+                .zip(outputs.values())
+                .zip(previous.values())
+            {
+                if on != was {
+                    changes.push(format!("{}ms {output} {}", at.as_millis(), on_off(on)));
+                }
+            }
+            previous = outputs.clone();
+        }
+        changes
+    }
+}
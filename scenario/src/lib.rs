@@ -0,0 +1,306 @@
+//! Timeline tests for anything that drives the machine through `pac_man_ball::Io`,
+//! run against a virtual clock so that they need no hardware and take no real time.
+//!
+//! ```text
+//! at 0ms press enter_switch
+//! at 500ms expect table_motor on
+//! at 800ms pulse checker_3_sensor
+//! expect checker_3_led on within 50ms
+//! ```
+//!
+//! ```ignore
+//! let scenario = Scenario::parse(include_str!("start_game.scenario")).unwrap();
+//! scenario.run(|io| game::run(io)).unwrap();
+//! ```
+//!
+//! Time starts at zero for each run and moves on a `TICK` at a time. The logic
+//! sees the inputs through `SimIo`, which waits a tick before each read in place of
+//! the I2C transaction, so logic reading in a loop sees a change within a tick.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Wake, Waker};
+
+use embassy_time::{Duration, MockDriver};
+use symmetrical_octo_chainsaw_shared::pac_man_ball::{Inputs, Outputs};
+
+mod io;
+mod script;
+
+pub use io::SimIo;
+pub use script::ParseError;
+
+/// How far the virtual clock moves at a time.
+pub const TICK: Duration = Duration::from_millis(1);
+/// How long `pulse` holds an input active when no length is given in a script.
+pub const DEFAULT_PULSE: Duration = Duration::from_millis(50);
+/// Polls within one tick after which the logic is taken to be spinning.
+const MAX_POLLS: usize = 10_000;
+
+#[derive(Clone, Debug)]
+enum Action {
+    Set {
+        input: &'static str,
+        active: bool,
+    },
+    Expect {
+        output: &'static str,
+        on: bool,
+        within: Option<Duration>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Step {
+    at: Duration,
+    action: Action,
+}
+
+impl Step {
+    /// When the step can be carried out or checked.
+    fn due(&self) -> Duration {
+        match self.action {
+            Action::Expect {
+                within: Some(within),
+                ..
+            } => self.at + within,
+            _ => self.at,
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {}ms ", self.at.as_millis())?;
+        match self.action {
+            Action::Set {
+                input,
+                active: true,
+            } => write!(f, "press {input}"),
+            Action::Set {
+                input,
+                active: false,
+            } => write!(f, "release {input}"),
+            Action::Expect { output, on, within } => {
+                write!(f, "expect {output} {}", on_off(on))?;
+                if let Some(within) = within {
+                    write!(f, " within {}ms", within.as_millis())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A timeline of input changes, and of what the outputs should do in response.
+///
+/// Steps happen at the time last given to `at`, or at the start if it hasn't been
+/// called. Names that aren't inputs or outputs panic, as in a failed assertion.
+#[derive(Clone, Debug, Default)]
+pub struct Scenario {
+    steps: Vec<Step>,
+    cursor: Duration,
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put the following steps this long after the start.
+    pub fn at(mut self, at: Duration) -> Self {
+        self.cursor = at;
+        self
+    }
+
+    pub fn press(self, input: &str) -> Self {
+        let input = field(Inputs::FIELDS, input).unwrap_or_else(|| panic!("No input {input}"));
+        self.push(Action::Set {
+            input,
+            active: true,
+        })
+    }
+
+    pub fn release(self, input: &str) -> Self {
+        let input = field(Inputs::FIELDS, input).unwrap_or_else(|| panic!("No input {input}"));
+        self.push(Action::Set {
+            input,
+            active: false,
+        })
+    }
+
+    /// Press `input`, then release it `length` later.
+    pub fn pulse(self, input: &str, length: Duration) -> Self {
+        let cursor = self.cursor;
+        self.press(input)
+            .at(cursor + length)
+            .release(input)
+            .at(cursor)
+    }
+
+    /// Expect `output` to be `on` or off at this point.
+    pub fn expect(self, output: &str, on: bool) -> Self {
+        let output = field(Outputs::FIELDS, output).unwrap_or_else(|| panic!("No output {output}"));
+        self.push(Action::Expect {
+            output,
+            on,
+            within: None,
+        })
+    }
+
+    /// Expect `output` to be `on` or off at this point, or to get there by `within` later.
+    pub fn expect_within(self, output: &str, on: bool, within: Duration) -> Self {
+        let output = field(Outputs::FIELDS, output).unwrap_or_else(|| panic!("No output {output}"));
+        self.push(Action::Expect {
+            output,
+            on,
+            within: Some(within),
+        })
+    }
+
+    fn push(mut self, action: Action) -> Self {
+        self.steps.push(Step {
+            at: self.cursor,
+            action,
+        });
+        self
+    }
+
+    /// Run `logic` against the timeline, until the last step or the first that fails.
+    ///
+    /// The virtual clock is global, so runs on other threads wait for this one.
+    pub fn run<F, Fut>(&self, logic: F) -> Result<(), Failure>
+    where
+        F: FnOnce(SimIo) -> Fut,
+        Fut: Future,
+    {
+        static RUNNING: Mutex<()> = Mutex::new(());
+        let _running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let driver = MockDriver::get();
+        driver.reset();
+
+        // Stable sorts, so steps due at the same time keep the order they were given in.
+        let (mut changes, mut checks): (Vec<_>, Vec<_>) = self
+            .steps
+            .iter()
+            .partition(|step| matches!(step.action, Action::Set { .. }));
+        changes.sort_by_key(|step| step.due());
+        checks.sort_by_key(|step| step.due());
+        let mut changes = changes.into_iter().peekable();
+        let mut checks = checks.into_iter().peekable();
+
+        let state = Rc::new(RefCell::new(io::State::default()));
+        let mut logic = pin!(logic(SimIo::new(state.clone())));
+        let woken = Arc::new(Woken(AtomicBool::new(true)));
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut finished = false;
+
+        let mut now = Duration::MIN;
+        loop {
+            // Steps between ticks happen on the tick after.
+            while let Some(step) = changes.next_if(|step| step.due() <= now) {
+                if let Action::Set { input, active } = step.action {
+                    state.borrow_mut().inputs.set(input, active);
+                }
+            }
+
+            let mut polls = 0;
+            while !finished && woken.0.swap(false, Ordering::AcqRel) {
+                polls += 1;
+                if polls > MAX_POLLS {
+                    return Err(Failure {
+                        at: now,
+                        step: None,
+                        message: "The logic never waits, for the inputs or anything else".into(),
+                        changes: state.borrow().changes(),
+                    });
+                }
+                finished = logic.as_mut().poll(&mut cx).is_ready();
+            }
+
+            // Checked once the logic has had its turn, so they see what it did now.
+            while let Some(step) = checks.next_if(|step| step.due() <= now) {
+                if let Action::Expect { output, on, within } = step.action {
+                    let state = state.borrow();
+                    let met = match within {
+                        None => state.output_at(output, now) == on,
+                        Some(_) => state.output_reached(output, on, step.at, now),
+                    };
+                    if !met {
+                        let was = if within.is_some() { "stayed" } else { "was" };
+                        return Err(Failure {
+                            at: now,
+                            step: Some(step.to_string()),
+                            message: format!("{output} {was} {}", on_off(!on)),
+                            changes: state.changes(),
+                        });
+                    }
+                }
+            }
+
+            if changes.peek().is_none() && checks.peek().is_none() {
+                return Ok(());
+            }
+            driver.advance(TICK);
+            now += TICK;
+        }
+    }
+}
+
+/// Why a scenario failed, with what the outputs did up to then.
+#[derive(Debug)]
+pub struct Failure {
+    pub at: Duration,
+    /// The step that failed, as it would be written in a script.
+    pub step: Option<String>,
+    pub message: String,
+    /// Each change to the outputs, e.g. `512ms table_motor on`.
+    pub changes: Vec<String>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.step {
+            Some(step) => write!(f, "{step}: {}", self.message)?,
+            None => write!(f, "At {}ms: {}", self.at.as_millis(), self.message)?,
+        }
+        if self.changes.is_empty() {
+            return write!(f, "\nThe outputs never changed");
+        }
+        write!(f, "\nOutput changes:")?;
+        for change in &self.changes {
+            write!(f, "\n  {change}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Wakes the logic on the next round of polls.
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+fn field(fields: &[&'static str], name: &str) -> Option<&'static str> {
+    fields.iter().copied().find(|field| *field == name)
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
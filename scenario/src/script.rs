@@ -0,0 +1,124 @@
+//! Scenarios written out as text, one step per line or separated by `;`:
+//!
+//! ```text
+//! # Comments run to the end of the line.
+//! at 0ms press enter_switch
+//! at 500ms expect table_motor on
+//! at 800ms pulse checker_3_sensor for 20ms
+//! expect checker_3_led on within 50ms
+//! release enter_switch
+//! ```
+//!
+//! `at` is optional, and without it a step happens when the one before did.
+//! Durations are in `ms` or `s`, and pulses last `DEFAULT_PULSE` unless given `for`.
+
+use std::fmt;
+
+use embassy_time::Duration;
+use symmetrical_octo_chainsaw_shared::pac_man_ball::{Inputs, Outputs};
+
+use crate::{field, Scenario, DEFAULT_PULSE};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+    /// Counting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Scenario {
+    pub fn parse(script: &str) -> Result<Self, ParseError> {
+        let mut scenario = Self::new();
+        for (i, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            for step in line.split(';') {
+                scenario = parse_step(scenario, step).map_err(|message| ParseError {
+                    line: i + 1,
+                    message,
+                })?;
+            }
+        }
+        Ok(scenario)
+    }
+}
+
+fn parse_step(mut scenario: Scenario, step: &str) -> Result<Scenario, String> {
+    let mut words = step.split_whitespace();
+    let mut verb = words.next();
+    if verb == Some("at") {
+        scenario = scenario.at(duration(words.next())?);
+        verb = words.next();
+    }
+
+    let scenario = match verb {
+        None => return Ok(scenario),
+        Some("press") => scenario.press(input(words.next())?),
+        Some("release") => scenario.release(input(words.next())?),
+        Some("pulse") => {
+            let input = input(words.next())?;
+            let length = match words.next() {
+                Some("for") => duration(words.next())?,
+                Some(word) => return Err(format!("Expected for, got {word}")),
+                None => DEFAULT_PULSE,
+            };
+            scenario.pulse(input, length)
+        }
+        Some("expect") => {
+            let output = output(words.next())?;
+            let on = match words.next() {
+                Some("on") => true,
+                Some("off") => false,
+                Some(word) => return Err(format!("Expected on or off, got {word}")),
+                None => return Err("Expected on or off".into()),
+            };
+            match words.next() {
+                Some("within") => scenario.expect_within(output, on, duration(words.next())?),
+                Some(word) => return Err(format!("Expected within, got {word}")),
+                None => scenario.expect(output, on),
+            }
+        }
+        Some(word) => {
+            return Err(format!(
+                "Expected press, release, pulse or expect, got {word}"
+            ))
+        }
+    };
+
+    match words.next() {
+        Some(word) => Err(format!("Unexpected {word}")),
+        None => Ok(scenario),
+    }
+}
+
+fn input(word: Option<&str>) -> Result<&'static str, String> {
+    let word = word.ok_or("Expected an input")?;
+    field(Inputs::FIELDS, word).ok_or_else(|| format!("No input {word}"))
+}
+
+fn output(word: Option<&str>) -> Result<&'static str, String> {
+    let word = word.ok_or("Expected an output")?;
+    field(Outputs::FIELDS, word).ok_or_else(|| format!("No output {word}"))
+}
+
+fn duration(word: Option<&str>) -> Result<Duration, String> {
+    let word = word.ok_or("Expected a duration")?;
+    let (value, scale) = if let Some(ms) = word.strip_suffix("ms") {
+        (ms, 1)
+    } else if let Some(s) = word.strip_suffix('s') {
+        (s, 1000)
+    } else {
+        return Err(format!("Expected a duration in ms or s, got {word}"));
+    };
+    let value = value
+        .parse::<u64>()
+        .map_err(|_| format!("Expected a duration in ms or s, got {word}"))?;
+    Ok(Duration::from_millis(value * scale))
+}
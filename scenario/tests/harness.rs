@@ -0,0 +1,143 @@
+//! The harness itself, against logic simple enough to be obviously right.
+
+use embassy_time::{Duration, Timer};
+use scenario::{Scenario, SimIo};
+use symmetrical_octo_chainsaw_shared::pac_man_ball::{Io, Outputs};
+
+/// Runs the table while `enter_switch` is held, and lights each checker's LED
+/// while its sensor is active.
+async fn follow(mut io: SimIo) {
+    loop {
+        let inputs = io.inputs().await.unwrap();
+        let outputs = Outputs {
+            table_motor: inputs.enter_switch,
+            checker_3_led: inputs.checker_3_sensor,
+            ..Default::default()
+        };
+        io.set_outputs(outputs).await.unwrap();
+    }
+}
+
+/// Starts the table 20ms after `enter_switch` is first pressed.
+async fn slow_start(mut io: SimIo) {
+    while !io.inputs().await.unwrap().enter_switch {}
+    Timer::after(Duration::from_millis(20)).await;
+    io.set_outputs(Outputs {
+        table_motor: true,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    // Hold the outputs as they are until the scenario ends.
+    core::future::pending::<()>().await;
+}
+
+#[test]
+fn passes_when_the_outputs_follow() {
+    let scenario = Scenario::parse(
+        "at 0ms press enter_switch
+         expect table_motor on within 5ms
+         at 100ms pulse checker_3_sensor for 20ms
+         expect checker_3_led on within 5ms
+         at 130ms expect checker_3_led off
+         at 200ms release enter_switch
+         expect table_motor off within 5ms",
+    )
+    .unwrap();
+    scenario.run(follow).unwrap();
+}
+
+#[test]
+fn fails_with_the_step_and_the_changes() {
+    let scenario =
+        Scenario::parse("at 10ms press enter_switch; at 50ms expect table_motor off").unwrap();
+    let failure = scenario.run(follow).unwrap_err();
+    assert_eq!(failure.at, Duration::from_millis(50));
+    assert_eq!(
+        failure.step.as_deref(),
+        Some("at 50ms expect table_motor off")
+    );
+    assert_eq!(failure.message, "table_motor was on");
+    assert_eq!(failure.changes, ["10ms table_motor on"]);
+}
+
+#[test]
+fn the_first_read_takes_a_tick() {
+    let scenario = Scenario::new()
+        .press("enter_switch")
+        .expect("table_motor", true);
+    assert!(scenario.run(follow).is_err());
+}
+
+#[test]
+fn within_allows_time_to_respond() {
+    let too_soon = Scenario::new().press("enter_switch").expect_within(
+        "table_motor",
+        true,
+        Duration::from_millis(10),
+    );
+    let failure = too_soon.run(slow_start).unwrap_err();
+    assert_eq!(failure.message, "table_motor stayed off");
+    assert!(failure.changes.is_empty());
+
+    let in_time = Scenario::new().press("enter_switch").expect_within(
+        "table_motor",
+        true,
+        Duration::from_millis(30),
+    );
+    in_time.run(slow_start).unwrap();
+}
+
+#[test]
+fn steps_between_ticks_happen_on_the_next() {
+    let scenario = Scenario::new()
+        .at(Duration::from_micros(500))
+        .press("enter_switch")
+        .at(Duration::from_micros(2500))
+        .expect("table_motor", true);
+    scenario.run(follow).unwrap();
+}
+
+#[test]
+fn logic_that_never_waits_fails() {
+    /// Yields to the harness, but asks to be polled again straight away.
+    async fn spin() {
+        let mut yielded = false;
+        core::future::poll_fn(|cx| {
+            if core::mem::replace(&mut yielded, true) {
+                return core::task::Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            core::task::Poll::Pending
+        })
+        .await
+    }
+
+    let scenario = Scenario::new()
+        .at(Duration::from_millis(10))
+        .press("enter_switch");
+    let failure = scenario
+        .run(|_io| async {
+            loop {
+                spin().await;
+            }
+        })
+        .unwrap_err();
+    assert_eq!(failure.at, Duration::from_ticks(0));
+    assert_eq!(failure.step, None);
+}
+
+#[test]
+fn parse_errors_give_the_line() {
+    let error =
+        Scenario::parse("at 0ms press enter_switch\n\nexpect table_motor sideways").unwrap_err();
+    assert_eq!(error.line, 3);
+    assert_eq!(error.message, "Expected on or off, got sideways");
+
+    let error = Scenario::parse("# comment\nat 5 press enter_switch").unwrap_err();
+    assert_eq!(error.line, 2);
+    assert_eq!(error.message, "Expected a duration in ms or s, got 5");
+
+    let error = Scenario::parse("press no_such_input").unwrap_err();
+    assert_eq!(error.message, "No input no_such_input");
+}
@@ -0,0 +1,80 @@
+//! Bundles the web UI from `src/http/public` into the crate.
+//!
+//! Each asset is gzipped ahead of time, so only the compressed copy ends up in
+//! flash, and given an ETag derived from its content. The generated table is
+//! included by `http::assets`.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use std::path::PathBuf;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+
+const PUBLIC_DIR: &str = "src/http/public";
+
+struct Asset {
+    file: &'static str,
+    content_type: &'static str,
+    cache_control: &'static str,
+    /// Request paths the asset is served at.
+    paths: &'static [&'static str],
+}
+
+const ASSETS: &[Asset] = &[
+    Asset {
+        file: "index.html",
+        content_type: "text/html; charset=utf-8",
+        // Always revalidate, like every asset, so after a firmware update the new
+        // page isn't loaded with the old styles or scripts.
+        cache_control: "no-cache",
+        paths: &["/", "/index.html"],
+    },
+    Asset {
+        file: "style.css",
+        content_type: "text/css; charset=utf-8",
+        cache_control: "no-cache",
+        paths: &["/style.css"],
+    },
+    Asset {
+        file: "synth.js",
+        content_type: "text/javascript; charset=utf-8",
+        cache_control: "no-cache",
+        paths: &["/synth.js"],
+    },
+];
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let mut table = String::from("pub static ASSETS: &[Asset] = &[\n");
+
+    for asset in ASSETS {
+        let source = PathBuf::from(PUBLIC_DIR).join(asset.file);
+        println!("cargo:rerun-if-changed={}", source.display());
+
+        let content = fs::read(&source).unwrap();
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&content).unwrap();
+        let gzipped = out.join(format!("{}.gz", asset.file));
+        fs::write(&gzipped, encoder.finish().unwrap()).unwrap();
+
+        let digest = Sha256::digest(&content);
+        let etag: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+
+        writeln!(
+            table,
+            "    Asset {{ paths: &{:?}, content_type: {:?}, cache_control: {:?}, etag: \"\\\"{}\\\"\", body: include_bytes!({:?}) }},",
+            asset.paths, asset.content_type, asset.cache_control, etag, gzipped
+        )
+        .unwrap();
+    }
+
+    table.push_str("];\n");
+
+    fs::write(out.join("assets.rs"), table).unwrap();
+}
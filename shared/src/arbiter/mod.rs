@@ -0,0 +1,350 @@
+//! Decides which source drives each output, once there is more than one.
+//!
+//! Each source writes to its own `Layer`, claiming only the fields it wants to
+//! control. Every field follows the highest layer that has claimed it, and is off
+//! when none has, so that e.g. a safety interlock holds the table motor off whatever
+//! the game or an operator asks for, while leaving the lamps to them.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::watch::{Receiver, Watch};
+use serde::{Deserialize, Serialize};
+
+use crate::http::diagnostics::MAX_CLIENTS;
+use crate::pac_man_ball::Outputs;
+
+/// The WS sessions, and the task applying the outputs.
+pub const MAX_WATCHERS: usize = MAX_CLIENTS + 1;
+
+/// The sources of outputs, lowest priority first.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    /// Lights and motion while nobody is playing.
+    Attract,
+    Game,
+    /// The web UI, Modbus, MQTT and service mode, overriding the game field by field.
+    Manual,
+    /// The self-test, holding every output while it runs.
+    Test,
+    /// Interlocks, which nothing else can override.
+    Safety,
+}
+
+impl Layer {
+    pub const ALL: [Self; 5] = [
+        Self::Attract,
+        Self::Game,
+        Self::Manual,
+        Self::Test,
+        Self::Safety,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Attract => "attract",
+            Self::Game => "game",
+            Self::Manual => "manual",
+            Self::Test => "test",
+            Self::Safety => "safety",
+        }
+    }
+}
+
+/// Which fields of `Outputs` a layer claims, in the same order as `Outputs::FIELDS`.
+pub type Mask = [bool; Outputs::FIELDS.len()];
+
+/// Every field.
+pub const ALL: Mask = [true; Outputs::FIELDS.len()];
+
+/// Just the named fields, ignoring names that aren't outputs.
+pub fn mask(fields: &[&str]) -> Mask {
+    let mut mask = [false; Outputs::FIELDS.len()];
+    for (claimed, field) in mask.iter_mut().zip(Outputs::FIELDS) {
+        *claimed = fields.contains(field);
+    }
+    mask
+}
+
+/// The layer driving each field, if any, in the same order as `Outputs::FIELDS`.
+pub type Owners = [Option<Layer>; Outputs::FIELDS.len()];
+
+/// The outputs to apply, and where each field came from.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Arbitration {
+    pub outputs: Outputs,
+    pub owners: Owners,
+}
+
+#[derive(Clone, Default)]
+struct Claim {
+    mask: Mask,
+    values: [bool; Outputs::FIELDS.len()],
+}
+
+struct State {
+    /// Indexed by `Layer`.
+    claims: [Claim; Layer::ALL.len()],
+    /// `None` until anything has been claimed.
+    current: Option<Arbitration>,
+}
+
+impl State {
+    fn claim(&mut self, layer: Layer) -> &mut Claim {
+        &mut self.claims[layer as usize]
+    }
+
+    /// What the layers below `layer` make of `field`, or `None` if none claim it.
+    fn below(&self, layer: Layer, field: usize) -> Option<bool> {
+        self.claims[..layer as usize]
+            .iter()
+            .rev()
+            .find(|claim| claim.mask[field])
+            .map(|claim| claim.values[field])
+    }
+
+    /// Whether a layer above `layer` claims `field`.
+    fn above(&self, layer: Layer, field: usize) -> bool {
+        self.claims[layer as usize + 1..]
+            .iter()
+            .any(|claim| claim.mask[field])
+    }
+
+    fn arbitrate(&self) -> Arbitration {
+        let mut arbitration = Arbitration::default();
+        for (i, field) in Outputs::FIELDS.iter().enumerate() {
+            let owner = Layer::ALL
+                .into_iter()
+                .rev()
+                .find(|layer| self.claims[*layer as usize].mask[i]);
+            if let Some(owner) = owner {
+                arbitration
+                    .outputs
+                    .set(field, self.claims[owner as usize].values[i]);
+            }
+            arbitration.owners[i] = owner;
+        }
+        arbitration
+    }
+}
+
+pub struct Arbiter {
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+    arbitration: Watch<CriticalSectionRawMutex, Arbitration, MAX_WATCHERS>,
+}
+
+impl Arbiter {
+    pub const fn new() -> Self {
+        const NONE: Claim = Claim {
+            mask: [false; Outputs::FIELDS.len()],
+            values: [false; Outputs::FIELDS.len()],
+        };
+        Self {
+            state: Mutex::new(RefCell::new(State {
+                claims: [NONE; Layer::ALL.len()],
+                current: None,
+            })),
+            arbitration: Watch::new(),
+        }
+    }
+
+    /// Claim the fields in `mask` for `layer`, driving them as in `outputs`. Fields
+    /// outside `mask` stay as they were, claimed or not.
+    pub fn claim(&self, layer: Layer, mask: &Mask, outputs: &Outputs) {
+        self.update(|state| {
+            let claim = state.claim(layer);
+            for (i, value) in outputs.values().into_iter().enumerate() {
+                if mask[i] {
+                    claim.mask[i] = true;
+                    claim.values[i] = value;
+                }
+            }
+        });
+    }
+
+    /// Hand the fields in `mask` back to the layers below `layer`.
+    pub fn release(&self, layer: Layer, mask: &Mask) {
+        self.update(|state| {
+            let claim = state.claim(layer);
+            for (claimed, release) in claim.mask.iter_mut().zip(mask) {
+                *claimed &= !release;
+            }
+        });
+    }
+
+    /// Drive the fields in `mask` as a client asked to. Fields that differ from
+    /// what the layers below want are claimed for `Layer::Manual`, and fields set
+    /// back to it are released, so the override lasts only as long as it is needed.
+    ///
+    /// Fields held by a layer above, such as the self-test or a safety interlock,
+    /// are left alone: that layer overrules the client anyway, and a claim made now
+    /// would outlast it.
+    pub fn request(&self, mask: &Mask, outputs: &Outputs) {
+        self.update(|state| {
+            for (i, value) in outputs.values().into_iter().enumerate() {
+                if !mask[i] || state.above(Layer::Manual, i) {
+                    continue;
+                }
+                let overrides = state.below(Layer::Manual, i).unwrap_or_default() != value;
+                let claim = state.claim(Layer::Manual);
+                claim.mask[i] = overrides;
+                claim.values[i] = value;
+            }
+        });
+    }
+
+    /// The outputs as they should be now.
+    pub fn arbitration(&self) -> Arbitration {
+        self.state
+            .lock(|state| state.borrow().current.clone().unwrap_or_default())
+    }
+
+    /// Watch for changes to the outputs, or `None` if too many are already watching.
+    pub fn subscribe(
+        &self,
+    ) -> Option<Receiver<'_, CriticalSectionRawMutex, Arbitration, MAX_WATCHERS>> {
+        self.arbitration.receiver()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        let changed = self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            f(&mut state);
+            let arbitration = state.arbitrate();
+            if state.current.as_ref() == Some(&arbitration) {
+                return None;
+            }
+            state.current = Some(arbitration.clone());
+            Some(arbitration)
+        });
+        if let Some(arbitration) = changed {
+            self.arbitration.sender().send(arbitration);
+        }
+    }
+}
+
+impl Default for Arbiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE_MOTOR: usize = 7;
+    const RAY_LAMP: usize = 16;
+
+    fn outputs(on: &[&str]) -> Outputs {
+        let mut outputs = Outputs::default();
+        for field in on {
+            outputs.set(field, true);
+        }
+        outputs
+    }
+
+    #[test]
+    fn each_field_follows_the_highest_layer_claiming_it() {
+        let arbiter = Arbiter::new();
+        arbiter.claim(Layer::Game, &ALL, &outputs(&["table_motor", "ray_lamp"]));
+        arbiter.claim(Layer::Safety, &mask(&["table_motor"]), &outputs(&[]));
+
+        let arbitration = arbiter.arbitration();
+        assert!(!arbitration.outputs.table_motor);
+        assert!(arbitration.outputs.ray_lamp);
+        assert_eq!(arbitration.owners[TABLE_MOTOR], Some(Layer::Safety));
+        assert_eq!(arbitration.owners[RAY_LAMP], Some(Layer::Game));
+    }
+
+    #[test]
+    fn unclaimed_fields_are_off() {
+        let arbiter = Arbiter::new();
+        arbiter.claim(
+            Layer::Attract,
+            &mask(&["ray_lamp"]),
+            &outputs(&["ray_lamp"]),
+        );
+        let arbitration = arbiter.arbitration();
+        assert_eq!(arbitration.outputs, outputs(&["ray_lamp"]));
+        assert_eq!(arbitration.owners.iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn releasing_hands_back_to_the_layer_below() {
+        let arbiter = Arbiter::new();
+        arbiter.claim(Layer::Game, &ALL, &outputs(&["table_motor"]));
+        arbiter.claim(Layer::Test, &ALL, &outputs(&[]));
+        assert!(!arbiter.arbitration().outputs.table_motor);
+
+        arbiter.release(Layer::Test, &ALL);
+        let arbitration = arbiter.arbitration();
+        assert!(arbitration.outputs.table_motor);
+        assert_eq!(arbitration.owners[TABLE_MOTOR], Some(Layer::Game));
+    }
+
+    #[test]
+    fn requests_override_only_while_they_differ() {
+        let arbiter = Arbiter::new();
+        arbiter.claim(Layer::Game, &ALL, &outputs(&["table_motor"]));
+
+        arbiter.request(&mask(&["table_motor"]), &outputs(&[]));
+        let arbitration = arbiter.arbitration();
+        assert!(!arbitration.outputs.table_motor);
+        assert_eq!(arbitration.owners[TABLE_MOTOR], Some(Layer::Manual));
+
+        // Set back to what the game wants, the override goes.
+        arbiter.request(&mask(&["table_motor"]), &outputs(&["table_motor"]));
+        assert_eq!(arbiter.arbitration().owners[TABLE_MOTOR], Some(Layer::Game));
+    }
+
+    #[test]
+    fn requests_leave_fields_outside_the_mask() {
+        let arbiter = Arbiter::new();
+        arbiter.request(&mask(&["ray_lamp"]), &outputs(&["ray_lamp"]));
+        arbiter.request(&mask(&["table_motor"]), &outputs(&["table_motor"]));
+        assert_eq!(
+            arbiter.arbitration().outputs,
+            outputs(&["ray_lamp", "table_motor"])
+        );
+    }
+
+    #[test]
+    fn requests_leave_fields_held_above_manual() {
+        let arbiter = Arbiter::new();
+        arbiter.claim(Layer::Safety, &mask(&["table_motor"]), &outputs(&[]));
+
+        // A client sending every output from a snapshot taken before the
+        // interlock held the table, to turn the lamp on.
+        arbiter.request(&ALL, &outputs(&["table_motor", "ray_lamp"]));
+        assert!(!arbiter.arbitration().outputs.table_motor);
+        assert!(arbiter.arbitration().outputs.ray_lamp);
+
+        // The table isn't left on by a request made while it was held.
+        arbiter.release(Layer::Safety, &ALL);
+        let arbitration = arbiter.arbitration();
+        assert!(!arbitration.outputs.table_motor);
+        assert_eq!(arbitration.owners[TABLE_MOTOR], None);
+    }
+
+    #[test]
+    fn watchers_only_hear_of_changes() {
+        let arbiter = Arbiter::new();
+        let mut receiver = arbiter.subscribe().unwrap();
+
+        arbiter.claim(Layer::Game, &ALL, &outputs(&["ray_lamp"]));
+        assert_eq!(
+            receiver
+                .try_changed()
+                .map(|arbitration| arbitration.outputs),
+            Some(outputs(&["ray_lamp"]))
+        );
+
+        // The manual layer asking for what the game already has changes nothing.
+        arbiter.request(&mask(&["ray_lamp"]), &outputs(&["ray_lamp"]));
+        assert_eq!(receiver.try_changed(), None);
+    }
+}
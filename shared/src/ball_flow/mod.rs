@@ -0,0 +1,284 @@
+//! Following the balls through the machine.
+//!
+//! Each in lane has a pair of sensors a ball passes one after the other, so which
+//! fires first gives its direction. Balls coming in collect in the hopper on that
+//! side, which feeds them past its sensor to the out hopper, which feeds them past
+//! `hopper_out_sensor` to the player. Counting them along the way gives how many
+//! each hopper holds, as far as has been seen since starting, and a hopper that
+//! runs without a ball coming out is reported jammed.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::events::{EventLog, Fault};
+use crate::pac_man_ball::{Inputs, Outputs};
+
+/// The longest a hopper may run without a ball coming out.
+pub const JAM: Duration = Duration::from_secs(3);
+
+/// A lane's sensors, in the order an incoming ball passes them, and the hopper it
+/// feeds, indexing `HOPPERS`.
+struct LaneSensors {
+    name: &'static str,
+    sensors: [&'static str; 2],
+    hopper: usize,
+}
+
+const LANES: [LaneSensors; 2] = [
+    LaneSensors {
+        name: "left",
+        sensors: ["left_in_sensor_1", "left_in_sensor_2"],
+        hopper: 0,
+    },
+    LaneSensors {
+        name: "right",
+        sensors: ["right_in_sensor_1", "right_in_sensor_2"],
+        hopper: 1,
+    },
+];
+
+/// A hopper's motor, the sensor its balls leave past, and the hopper it feeds.
+struct HopperSensors {
+    name: &'static str,
+    output: &'static str,
+    sensor: &'static str,
+    feeds: Option<usize>,
+}
+
+const HOPPERS: [HopperSensors; 3] = [
+    HopperSensors {
+        name: "left",
+        output: "left_hopper",
+        sensor: "hopper_left_sensor",
+        feeds: Some(2),
+    },
+    HopperSensors {
+        name: "right",
+        output: "right_hopper",
+        sensor: "hopper_right_sensor",
+        feeds: Some(2),
+    },
+    HopperSensors {
+        name: "out",
+        output: "out_hopper",
+        sensor: "hopper_out_sensor",
+        feeds: None,
+    },
+];
+
+/// A ball passing a lane's sensors.
+#[derive(Clone, Copy)]
+enum Pass {
+    /// Only the sensor at this index has seen it so far.
+    Entered(usize),
+    /// Both sensors have seen it, having entered at this index, or `None` if they
+    /// saw it at once.
+    Crossed(Option<usize>),
+}
+
+#[derive(Clone, Copy)]
+struct Lane {
+    pass: Option<Pass>,
+    balls_in: u32,
+    balls_out: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Hopper {
+    held: u32,
+    dispensed: u32,
+    running: bool,
+    /// When the hopper started, or the last ball came out while running.
+    since: Option<Instant>,
+    jammed: bool,
+}
+
+struct State {
+    inputs: Option<Inputs>,
+    lanes: [Lane; LANES.len()],
+    hoppers: [Hopper; HOPPERS.len()],
+}
+
+pub struct BallFlow<'a> {
+    events: &'a EventLog<'a>,
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+}
+
+impl<'a> BallFlow<'a> {
+    pub const fn new(events: &'a EventLog<'a>) -> Self {
+        const LANE: Lane = Lane {
+            pass: None,
+            balls_in: 0,
+            balls_out: 0,
+        };
+        const HOPPER: Hopper = Hopper {
+            held: 0,
+            dispensed: 0,
+            running: false,
+            since: None,
+            jammed: false,
+        };
+        Self {
+            events,
+            state: Mutex::new(RefCell::new(State {
+                inputs: None,
+                lanes: [LANE; LANES.len()],
+                hoppers: [HOPPER; HOPPERS.len()],
+            })),
+        }
+    }
+
+    /// Count the balls that have moved since the last call, and check the running
+    /// hoppers are delivering.
+    pub fn record_inputs(&self, inputs: &Inputs) {
+        let now = Instant::now();
+        let mut jams = heapless::Vec::<&'static str, { HOPPERS.len() }>::new();
+        self.lock(|state| {
+            let Some(previous) = state.inputs.replace(inputs.clone()) else {
+                return;
+            };
+            let active = |sensor| inputs.get(sensor).unwrap_or_default();
+            let was_active = |sensor| previous.get(sensor).unwrap_or_default();
+
+            for (sensors, lane) in LANES.iter().zip(state.lanes.iter_mut()) {
+                let [first, second] = sensors.sensors.map(active);
+                let hopper = &mut state.hoppers[sensors.hopper];
+                lane.pass = match (lane.pass, first || second) {
+                    (None, false) => None,
+                    (None, true) if first && second => Some(Pass::Crossed(None)),
+                    (None, true) => Some(Pass::Entered(usize::from(second))),
+                    (Some(Pass::Entered(entered)), true) if [first, second][1 - entered] => {
+                        Some(Pass::Crossed(Some(entered)))
+                    }
+                    (Some(Pass::Entered(entered)), true) => Some(Pass::Entered(entered)),
+                    (Some(Pass::Crossed(entered)), true) => Some(Pass::Crossed(entered)),
+                    // Backed out the way it came.
+                    (Some(Pass::Entered(_)), false) => None,
+                    (Some(Pass::Crossed(entered)), false) => {
+                        match entered {
+                            Some(0) => {
+                                lane.balls_in = lane.balls_in.wrapping_add(1);
+                                hopper.held = hopper.held.saturating_add(1);
+                            }
+                            Some(_) => {
+                                lane.balls_out = lane.balls_out.wrapping_add(1);
+                                hopper.held = hopper.held.saturating_sub(1);
+                            }
+                            None => {}
+                        }
+                        None
+                    }
+                };
+            }
+
+            for (i, sensors) in HOPPERS.iter().enumerate() {
+                if active(sensors.sensor) && !was_active(sensors.sensor) {
+                    let hopper = &mut state.hoppers[i];
+                    hopper.held = hopper.held.saturating_sub(1);
+                    hopper.dispensed = hopper.dispensed.wrapping_add(1);
+                    if hopper.running {
+                        hopper.since = Some(now);
+                    }
+                    if hopper.jammed {
+                        info!("The {} hopper is delivering again", sensors.name);
+                        hopper.jammed = false;
+                    }
+                    if let Some(feeds) = sensors.feeds {
+                        let fed = &mut state.hoppers[feeds];
+                        fed.held = fed.held.saturating_add(1);
+                    }
+                }
+
+                let hopper = &mut state.hoppers[i];
+                let Some(since) = hopper.since.filter(|_| hopper.running) else {
+                    continue;
+                };
+                if !hopper.jammed && now - since > JAM {
+                    warn!(
+                        "The {} hopper is running but no ball came out",
+                        sensors.name
+                    );
+                    hopper.jammed = true;
+                    let _ = jams.push(sensors.output);
+                }
+            }
+        });
+        for hopper in jams {
+            self.events.record_fault(Fault::HopperJam { hopper });
+        }
+    }
+
+    /// Note which hoppers are running, starting the clock for their first ball.
+    pub fn record_outputs(&self, outputs: &Outputs) {
+        let now = Instant::now();
+        self.lock(|state| {
+            for (sensors, hopper) in HOPPERS.iter().zip(state.hoppers.iter_mut()) {
+                let running = outputs.get(sensors.output).unwrap_or_default();
+                if running && !hopper.running {
+                    hopper.since = Some(now);
+                    hopper.jammed = false;
+                }
+                hopper.running = running;
+            }
+        });
+    }
+
+    /// Whether the hopper driven by `output` has been reported jammed, and hasn't
+    /// delivered since.
+    pub fn jammed(&self, output: &str) -> bool {
+        self.lock(|state| {
+            HOPPERS
+                .iter()
+                .zip(state.hoppers.iter())
+                .any(|(sensors, hopper)| sensors.output == output && hopper.jammed)
+        })
+    }
+
+    /// e.g. `{"lanes":[{"name":"left","in":12,"out":1},...],"hoppers":[{"name":"left","held":3,"dispensed":8,"jammed":false},...]}`.
+    pub fn report(&self) -> Report {
+        self.lock(|state| Report {
+            lanes: core::array::from_fn(|i| LaneReport {
+                name: LANES[i].name,
+                balls_in: state.lanes[i].balls_in,
+                balls_out: state.lanes[i].balls_out,
+            }),
+            hoppers: core::array::from_fn(|i| HopperReport {
+                name: HOPPERS[i].name,
+                held: state.hoppers[i].held,
+                dispensed: state.hoppers[i].dispensed,
+                jammed: state.hoppers[i].jammed,
+            }),
+        })
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub lanes: [LaneReport; LANES.len()],
+    pub hoppers: [HopperReport; HOPPERS.len()],
+}
+
+#[derive(Serialize)]
+pub struct LaneReport {
+    pub name: &'static str,
+    #[serde(rename = "in")]
+    pub balls_in: u32,
+    #[serde(rename = "out")]
+    pub balls_out: u32,
+}
+
+#[derive(Serialize)]
+pub struct HopperReport {
+    pub name: &'static str,
+    /// Balls counted in less those counted out, since starting.
+    pub held: u32,
+    pub dispensed: u32,
+    pub jammed: bool,
+}
@@ -0,0 +1,244 @@
+//! The operator's audits: games, coins, credits, tickets, balls into each checker,
+//! tilts and time powered on.
+//!
+//! Every count is kept twice, once since an operator last reset the audits, and
+//! once over the machine's lifetime, which can't be reset. Coins, credits and
+//! games are saved as they happen, as they're money, and the rest along with the
+//! time powered on every `SAVE_INTERVAL`, to spare the flash.
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Timestamp, WallClock};
+use crate::pac_man_ball::Inputs;
+use crate::storage::{self, Record, Storage};
+
+/// The checkers' sensors, each seeing the balls that land in it.
+pub const CHECKERS: [&str; 7] = [
+    "checker_0_sensor",
+    "checker_1_sensor",
+    "checker_2_sensor",
+    "checker_3_sensor",
+    "checker_4_sensor",
+    "checker_5_sensor",
+    "checker_6_sensor",
+];
+
+/// The names `Counters::checker_balls` are exported under.
+const CHECKER_BALLS: [&str; CHECKERS.len()] = [
+    "checker_0_balls",
+    "checker_1_balls",
+    "checker_2_balls",
+    "checker_3_balls",
+    "checker_4_balls",
+    "checker_5_balls",
+    "checker_6_balls",
+];
+
+/// The most counting not yet saved that a power cut can lose.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Counters {
+    pub games: u32,
+    pub coins: u32,
+    pub credits: u32,
+    pub tickets: u32,
+    /// Indexed as `CHECKERS`.
+    pub checker_balls: [u32; CHECKERS.len()],
+    pub tilts: u32,
+    pub power_on_minutes: u32,
+}
+
+impl Counters {
+    pub const ZERO: Self = Self {
+        games: 0,
+        coins: 0,
+        credits: 0,
+        tickets: 0,
+        checker_balls: [0; CHECKERS.len()],
+        tilts: 0,
+        power_on_minutes: 0,
+    };
+
+    /// Each counter's name and value, in the order they're exported.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u32)> + '_ {
+        [
+            ("games", self.games),
+            ("coins", self.coins),
+            ("credits", self.credits),
+            ("tickets", self.tickets),
+        ]
+        .into_iter()
+        .chain(CHECKER_BALLS.into_iter().zip(self.checker_balls))
+        .chain([
+            ("tilts", self.tilts),
+            ("power_on_minutes", self.power_on_minutes),
+        ])
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Saved {
+    since_reset: Counters,
+    lifetime: Counters,
+    /// When the audits were last reset, if the wall clock had been set.
+    reset_at: Option<Timestamp>,
+}
+
+struct State {
+    saved: Saved,
+    inputs: Option<Inputs>,
+}
+
+pub struct Bookkeeping<'a> {
+    clock: &'a WallClock,
+    state: BlockingMutex<CriticalSectionRawMutex, RefCell<State>>,
+    /// Raised when something worth saving at once has been counted.
+    persist: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<'a> Bookkeeping<'a> {
+    pub const fn new(clock: &'a WallClock) -> Self {
+        Self {
+            clock,
+            state: BlockingMutex::new(RefCell::new(State {
+                saved: Saved {
+                    since_reset: Counters::ZERO,
+                    lifetime: Counters::ZERO,
+                    reset_at: None,
+                },
+                inputs: None,
+            })),
+            persist: Signal::new(),
+        }
+    }
+
+    /// Count a coin accepted, and the credits it bought.
+    pub fn record_coin(&self, credits: u32) {
+        self.count(|counters| {
+            counters.coins = counters.coins.wrapping_add(1);
+            counters.credits = counters.credits.wrapping_add(credits);
+        });
+        self.persist.signal(());
+    }
+
+    /// Count credits bought other than by a coin, e.g. by banked units when the
+    /// price comes down.
+    pub fn record_credits(&self, credits: u32) {
+        self.count(|counters| counters.credits = counters.credits.wrapping_add(credits));
+        self.persist.signal(());
+    }
+
+    pub fn record_game(&self) {
+        self.count(|counters| counters.games = counters.games.wrapping_add(1));
+        self.persist.signal(());
+    }
+
+    pub fn record_ticket(&self) {
+        self.count(|counters| counters.tickets = counters.tickets.wrapping_add(1));
+    }
+
+    /// Count the balls landing in the checkers, and tilts.
+    pub fn record_inputs(&self, inputs: &Inputs) {
+        self.lock(|state| {
+            let Some(previous) = state.inputs.replace(inputs.clone()) else {
+                return;
+            };
+            let rose = |sensor| {
+                inputs.get(sensor).unwrap_or_default() && !previous.get(sensor).unwrap_or_default()
+            };
+            for counters in [&mut state.saved.since_reset, &mut state.saved.lifetime] {
+                for (balls, sensor) in counters.checker_balls.iter_mut().zip(CHECKERS) {
+                    if rose(sensor) {
+                        *balls = balls.wrapping_add(1);
+                    }
+                }
+                if rose("tilt_switch") {
+                    counters.tilts = counters.tilts.wrapping_add(1);
+                }
+            }
+        });
+    }
+
+    /// Zero the counters since the last reset. The lifetime ones carry on.
+    pub fn reset(&self) {
+        let reset_at = self.clock.now();
+        self.lock(|state| {
+            state.saved.since_reset = Counters::ZERO;
+            state.saved.reset_at = reset_at;
+        });
+        info!("Audits reset");
+        self.persist.signal(());
+    }
+
+    /// e.g. `{"since_reset":{"games":3,...},"lifetime":{"games":120,...},"reset_at":...}`.
+    pub fn report(&self) -> Report {
+        self.lock(|state| Report {
+            since_reset: state.saved.since_reset,
+            lifetime: state.saved.lifetime,
+            reset_at: state.saved.reset_at,
+        })
+    }
+
+    /// Pick up the counters from before the last power cut.
+    pub async fn restore<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) {
+        let Some(saved) = storage::load::<Saved, _>(storage, Record::Bookkeeping).await else {
+            return;
+        };
+        info!("Restored the audits, {} games", saved.lifetime.games);
+        self.lock(|state| state.saved = saved);
+    }
+
+    /// Count the minutes powered on, and save the counters as they need it.
+    pub async fn run<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) -> ! {
+        let minutes_per_save = (SAVE_INTERVAL.as_secs() / MINUTE.as_secs()) as u32;
+        let mut ticker = Ticker::every(MINUTE);
+        let mut unsaved_minutes = 0;
+        loop {
+            match select(self.persist.wait(), ticker.next()).await {
+                Either::First(()) => {}
+                Either::Second(()) => {
+                    self.count(|counters| {
+                        counters.power_on_minutes = counters.power_on_minutes.wrapping_add(1)
+                    });
+                    unsaved_minutes += 1;
+                    if unsaved_minutes < minutes_per_save {
+                        continue;
+                    }
+                }
+            }
+            unsaved_minutes = 0;
+            let saved = self.lock(|state| state.saved);
+            storage::save(storage, Record::Bookkeeping, &saved).await;
+        }
+    }
+
+    /// Apply `f` to both sets of counters.
+    fn count(&self, f: impl Fn(&mut Counters)) {
+        self.lock(|state| {
+            f(&mut state.saved.since_reset);
+            f(&mut state.saved.lifetime);
+        });
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub since_reset: Counters,
+    pub lifetime: Counters,
+    pub reset_at: Option<Timestamp>,
+}
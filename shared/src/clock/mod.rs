@@ -0,0 +1,72 @@
+//! Wall-clock time layered on top of the `embassy_time` uptime clock.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use serde::{Deserialize, Serialize};
+
+pub mod sntp;
+
+/// Milliseconds since the Unix epoch.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(transparent)]
+pub struct Timestamp(pub u64);
+
+/// Maps uptime onto wall-clock time, once something (e.g. SNTP) has told it the time.
+///
+/// Until then `now` returns `None`, so callers can tell an unsynchronised clock apart
+/// from one that is merely early.
+pub struct WallClock {
+    /// Wall-clock time at uptime zero, in milliseconds since the Unix epoch.
+    epoch: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>>,
+}
+
+impl WallClock {
+    pub const fn new() -> Self {
+        Self {
+            epoch: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Set the wall-clock time as observed right now.
+    pub fn set(&self, now: Timestamp) {
+        self.set_at(now, Instant::now());
+    }
+
+    /// Set the wall-clock time as observed at `instant`.
+    pub fn set_at(&self, time: Timestamp, instant: Instant) {
+        let epoch = time.0.saturating_sub(instant.as_millis());
+        let previous = self.epoch.lock(|e| e.replace(Some(epoch)));
+        match previous {
+            None => info!("Wall clock set to {}", time.0),
+            Some(previous) if previous.abs_diff(epoch) > 1000 => {
+                warn!("Wall clock stepped by {}ms", epoch as i64 - previous as i64)
+            }
+            Some(_) => {}
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.epoch.lock(|e| e.get().is_some())
+    }
+
+    /// The current wall-clock time, if the clock has been set.
+    pub fn now(&self) -> Option<Timestamp> {
+        self.at(Instant::now())
+    }
+
+    /// The wall-clock time at `instant`, if the clock has been set.
+    pub fn at(&self, instant: Instant) -> Option<Timestamp> {
+        self.epoch
+            .lock(|e| e.get())
+            .map(|epoch| Timestamp(epoch + instant.as_millis()))
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,100 @@
+//! Packet encoding for a minimal SNTPv4 (RFC 4330) client.
+//!
+//! Only the codec lives here; the socket handling is target specific.
+
+use embassy_time::Duration;
+
+use super::Timestamp;
+
+pub const PORT: u16 = 123;
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds between the NTP era 0 epoch (1900) and the Unix epoch (1970).
+const UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONISED: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Truncated,
+    NotServer,
+    KissOfDeath,
+    Unsynchronised,
+    OriginateMismatch,
+}
+
+/// The server timestamps from a validated response.
+#[derive(Debug, Clone, Copy)]
+pub struct Response {
+    /// When the server received the request.
+    pub receive: Timestamp,
+    /// When the server sent the response.
+    pub transmit: Timestamp,
+}
+
+impl Response {
+    /// Estimate the wall-clock time at which the response arrived, given the round
+    /// trip measured locally, assuming the network delay is symmetric.
+    pub fn time_at_reception(&self, round_trip: Duration) -> Timestamp {
+        let server_delay = self.transmit.0.saturating_sub(self.receive.0);
+        let network_delay = round_trip.as_millis().saturating_sub(server_delay);
+        Timestamp(self.transmit.0 + network_delay / 2)
+    }
+}
+
+/// Build a client request. `nonce` is echoed back by the server in the originate
+/// field and must be passed to `parse_response`.
+pub fn request(buf: &mut [u8; PACKET_LEN], nonce: u64) {
+    buf.fill(0);
+    buf[0] = (VERSION << 3) | MODE_CLIENT;
+    buf[40..48].copy_from_slice(&nonce.to_be_bytes());
+}
+
+pub fn parse_response(buf: &[u8], nonce: u64) -> Result<Response, Error> {
+    if buf.len() < PACKET_LEN {
+        return Err(Error::Truncated);
+    }
+
+    let leap = buf[0] >> 6;
+    let mode = buf[0] & 0x07;
+    let stratum = buf[1];
+
+    if mode != MODE_SERVER {
+        return Err(Error::NotServer);
+    }
+    if stratum == 0 {
+        return Err(Error::KissOfDeath);
+    }
+    if leap == LEAP_UNSYNCHRONISED {
+        return Err(Error::Unsynchronised);
+    }
+    if read_u64(&buf[24..32]) != nonce {
+        return Err(Error::OriginateMismatch);
+    }
+
+    Ok(Response {
+        receive: to_unix(read_u64(&buf[32..40])),
+        transmit: to_unix(read_u64(&buf[40..48])),
+    })
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf);
+    u64::from_be_bytes(bytes)
+}
+
+/// Convert a 32.32 fixed point NTP timestamp to Unix milliseconds.
+fn to_unix(ntp: u64) -> Timestamp {
+    let mut secs = ntp >> 32;
+    // Era 0 ends in 2036; anything before 1970 must be from era 1.
+    if secs < UNIX_OFFSET_SECS {
+        secs += 1 << 32;
+    }
+    let millis = ((ntp & 0xffff_ffff) * 1000) >> 32;
+    Timestamp((secs - UNIX_OFFSET_SECS) * 1000 + millis)
+}
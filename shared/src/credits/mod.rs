@@ -0,0 +1,230 @@
+//! Credits from a coin mech on the board's buffered inputs, for starting games.
+//!
+//! Each of the `LINES` pulses once per coin of its own value. A pulse shorter than
+//! `MIN_PULSE` is noise, and one longer than `MAX_PULSE` a stuck line or someone
+//! fishing for credits, so both are rejected. Coins are worth `coin_values` units,
+//! `units_per_credit` of which make a credit, and what's left over is banked
+//! towards the next. The credits are saved whenever they change, so that they
+//! survive a power cut.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::bookkeeping::Bookkeeping;
+use crate::storage::{self, Record, Storage};
+
+/// The coin lines, `in_buffered_1..3` on the board. `in_buffered_4` is the ticket
+/// dispenser's notch sensor.
+pub const LINES: usize = 3;
+
+pub const MIN_PULSE: Duration = Duration::from_millis(20);
+pub const MAX_PULSE: Duration = Duration::from_millis(150);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Settings {
+    /// What a coin on each line is worth, or 0 to ignore the line.
+    pub coin_values: [u16; LINES],
+    pub units_per_credit: u16,
+    pub credits_per_play: u16,
+    /// Start games without taking credits.
+    pub free_play: bool,
+}
+
+impl Settings {
+    /// A credit per coin, on any line.
+    pub const DEFAULT: Self = Self {
+        coin_values: [1; LINES],
+        units_per_credit: 1,
+        credits_per_play: 1,
+        free_play: false,
+    };
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Saved {
+    credits: u32,
+    /// Units towards the next credit.
+    bank: u16,
+    settings: Settings,
+}
+
+impl Saved {
+    /// Turn whole credits' worth of banked units into credits, returning how many.
+    fn settle(&mut self) -> u32 {
+        let units_per_credit = self.settings.units_per_credit.max(1);
+        let bought = u32::from(self.bank / units_per_credit);
+        self.credits = self.credits.saturating_add(bought);
+        self.bank %= units_per_credit;
+        bought
+    }
+}
+
+struct State {
+    saved: Saved,
+    /// Pulses rejected since starting.
+    rejected: u32,
+}
+
+pub struct Credits<'a> {
+    bookkeeping: &'a Bookkeeping<'a>,
+    state: BlockingMutex<CriticalSectionRawMutex, RefCell<State>>,
+    /// Raised when anything saved changes.
+    persist: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<'a> Credits<'a> {
+    pub const fn new(bookkeeping: &'a Bookkeeping<'a>) -> Self {
+        Self {
+            bookkeeping,
+            state: BlockingMutex::new(RefCell::new(State {
+                saved: Saved {
+                    credits: 0,
+                    bank: 0,
+                    settings: Settings::DEFAULT,
+                },
+                rejected: 0,
+            })),
+            persist: Signal::new(),
+        }
+    }
+
+    /// Count a pulse `width` long on coin `line`, returning whether it was accepted.
+    pub fn record_pulse(&self, line: usize, width: Duration) -> bool {
+        if !(MIN_PULSE..=MAX_PULSE).contains(&width) {
+            warn!(
+                "Rejected a {}ms pulse on coin line {}",
+                width.as_millis(),
+                line
+            );
+            self.lock(|state| state.rejected = state.rejected.wrapping_add(1));
+            return false;
+        }
+
+        let credits = self.lock(|state| {
+            let value = state.saved.settings.coin_values.get(line).copied()?;
+            if value == 0 {
+                return None;
+            }
+            state.saved.bank = state.saved.bank.saturating_add(value);
+            let bought = state.saved.settle();
+            Some((bought, state.saved.credits))
+        });
+        let Some((bought, credits)) = credits else {
+            debug!("Ignoring a coin on disabled line {}", line);
+            return false;
+        };
+        info!("Coin on line {}, {} credits", line, credits);
+        self.bookkeeping.record_coin(bought);
+        self.persist.signal(());
+        true
+    }
+
+    /// Whether a game can be started now.
+    pub fn can_play(&self) -> bool {
+        self.lock(|state| {
+            let settings = &state.saved.settings;
+            settings.free_play || state.saved.credits >= u32::from(settings.credits_per_play)
+        })
+    }
+
+    /// Take the credits for a game, returning `false` if there aren't enough. Free
+    /// play takes none.
+    pub fn start_play(&self) -> bool {
+        let taken = self.lock(|state| {
+            let settings = state.saved.settings;
+            if settings.free_play {
+                return Some(false);
+            }
+            let cost = u32::from(settings.credits_per_play);
+            if state.saved.credits < cost {
+                return None;
+            }
+            state.saved.credits -= cost;
+            Some(true)
+        });
+        let Some(taken) = taken else {
+            return false;
+        };
+        if taken {
+            self.persist.signal(());
+        }
+        self.bookkeeping.record_game();
+        true
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.lock(|state| state.saved.settings)
+    }
+
+    /// Change the settings, returning `false` if they don't make sense.
+    pub fn set_settings(&self, settings: Settings) -> bool {
+        if settings.units_per_credit == 0 || settings.credits_per_play == 0 {
+            return false;
+        }
+        let bought = self.lock(|state| {
+            state.saved.settings = settings;
+            state.saved.settle()
+        });
+        info!("Credit settings changed to {:?}", settings);
+        if bought > 0 {
+            self.bookkeeping.record_credits(bought);
+        }
+        self.persist.signal(());
+        true
+    }
+
+    /// e.g. `{"credits":3,"bank":0,"can_play":true,"rejected":0,"settings":{...}}`.
+    pub fn report(&self) -> Report {
+        let can_play = self.can_play();
+        self.lock(|state| Report {
+            credits: state.saved.credits,
+            bank: state.saved.bank,
+            can_play,
+            rejected: state.rejected,
+            settings: state.saved.settings,
+        })
+    }
+
+    /// Pick up the credits and settings from before the last power cut.
+    pub async fn restore<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) {
+        let Some(saved) = storage::load::<Saved, _>(storage, Record::Credits).await else {
+            return;
+        };
+        info!("Restored {} credits", saved.credits);
+        self.lock(|state| state.saved = saved);
+    }
+
+    /// Save the credits and settings whenever they change.
+    pub async fn persist<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) -> ! {
+        loop {
+            self.persist.wait().await;
+            let saved = self.lock(|state| state.saved);
+            storage::save(storage, Record::Credits, &saved).await;
+        }
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub credits: u32,
+    pub bank: u16,
+    pub can_play: bool,
+    pub rejected: u32,
+    pub settings: Settings,
+}
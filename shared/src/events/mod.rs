@@ -0,0 +1,214 @@
+//! A log of recent input changes, output changes and faults, streamed to clients
+//! as Server-Sent Events from `GET /api/events`.
+//!
+//! Events are numbered so that a client that reconnects with `Last-Event-ID` can
+//! pick up where it left off, as long as what it missed is still in the log.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::watch::{Receiver, Watch};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+use crate::clock::{Timestamp, WallClock};
+use crate::forces::Forces;
+use crate::http::diagnostics::MAX_CLIENTS;
+use crate::pac_man_ball::{Inputs, Outputs};
+
+/// Events kept for clients to catch up on.
+pub const CAPACITY: usize = 64;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// An I2C transaction with the expander at this address failed.
+    I2c { expander: u8 },
+    /// A sensor that should be toggling has read `active` for too long.
+    StuckSensor { input: &'static str, active: bool },
+    /// The table stopped turning while its motor was on.
+    TableStall,
+    /// A hopper ran without a ball coming out.
+    HopperJam { hopper: &'static str },
+    /// The ticket dispenser ran out of tickets.
+    TicketsEmpty,
+    /// A ticket jammed in the dispenser.
+    TicketJam,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EventKind {
+    /// `forced` if the input reads as an operator forced it, not as its sensor says.
+    Input {
+        input: &'static str,
+        active: bool,
+        forced: bool,
+    },
+    Output {
+        output: &'static str,
+        on: bool,
+    },
+    Fault(Fault),
+}
+
+impl EventKind {
+    /// The SSE event type.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Input { .. } => "input",
+            Self::Output { .. } => "output",
+            Self::Fault(_) => "fault",
+        }
+    }
+}
+
+/// Serializes as the event's data, e.g.
+/// `{"timestamp":...,"input":"tilt_switch","active":true,"forced":false}`.
+/// The id and kind go in the SSE `id` and `event` fields.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Event {
+    pub id: u32,
+    /// When the event happened, if the wall clock has been set.
+    pub timestamp: Option<Timestamp>,
+    pub kind: EventKind,
+}
+
+impl Serialize for Event {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Event", 4)?;
+        s.serialize_field("timestamp", &self.timestamp)?;
+        match &self.kind {
+            EventKind::Input {
+                input,
+                active,
+                forced,
+            } => {
+                s.serialize_field("input", input)?;
+                s.serialize_field("active", active)?;
+                s.serialize_field("forced", forced)?;
+            }
+            EventKind::Output { output, on } => {
+                s.serialize_field("output", output)?;
+                s.serialize_field("on", on)?;
+            }
+            EventKind::Fault(fault) => s.serialize_field("fault", fault)?,
+        }
+        s.end()
+    }
+}
+
+struct State {
+    events: heapless::Deque<Event, CAPACITY>,
+    next_id: u32,
+    inputs: Option<[bool; Inputs::FIELDS.len()]>,
+    outputs: Option<[bool; Outputs::FIELDS.len()]>,
+}
+
+pub struct EventLog<'a> {
+    clock: &'a WallClock,
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+    /// The id of the latest event, for waking the streams.
+    latest: Watch<CriticalSectionRawMutex, u32, MAX_CLIENTS>,
+}
+
+impl<'a> EventLog<'a> {
+    pub const fn new(clock: &'a WallClock) -> Self {
+        Self {
+            clock,
+            state: Mutex::new(RefCell::new(State {
+                events: heapless::Deque::new(),
+                // Starting at 1 leaves 0 to mean "nothing seen yet" in Last-Event-ID.
+                next_id: 1,
+                inputs: None,
+                outputs: None,
+            })),
+            latest: Watch::new(),
+        }
+    }
+
+    /// Log the inputs that changed since the last call, flagging those that changed
+    /// because of `forces`.
+    pub fn record_inputs(&self, inputs: &Inputs, forces: &Forces) {
+        let values = inputs.values();
+        let previous = self.lock(|state| state.inputs.replace(values));
+        let Some(previous) = previous else {
+            return;
+        };
+        for (i, (input, active)) in Inputs::FIELDS.iter().zip(values).enumerate() {
+            if active != previous[i] {
+                self.record(EventKind::Input {
+                    input,
+                    active,
+                    forced: forces[i].is_some(),
+                });
+            }
+        }
+    }
+
+    /// Log the outputs that changed since the last call.
+    pub fn record_outputs(&self, outputs: &Outputs) {
+        let values = outputs.values();
+        let previous = self.lock(|state| state.outputs.replace(values));
+        let Some(previous) = previous else {
+            return;
+        };
+        for ((output, on), was) in Outputs::FIELDS.iter().zip(values).zip(previous) {
+            if on != was {
+                self.record(EventKind::Output { output, on });
+            }
+        }
+    }
+
+    pub fn record_fault(&self, fault: Fault) {
+        self.record(EventKind::Fault(fault));
+    }
+
+    pub fn record(&self, kind: EventKind) {
+        let timestamp = self.clock.now();
+        let id = self.lock(|state| {
+            let id = state.next_id;
+            state.next_id = id.wrapping_add(1).max(1);
+            if state.events.is_full() {
+                state.events.pop_front();
+            }
+            let _ = state.events.push_back(Event {
+                id,
+                timestamp,
+                kind,
+            });
+            id
+        });
+        self.latest.sender().send(id);
+    }
+
+    /// The first event after `id`, or `None` if there isn't one yet.
+    ///
+    /// If events after `id` have already been dropped from the log, this is the
+    /// oldest event still in it.
+    pub fn after(&self, id: u32) -> Option<Event> {
+        self.lock(|state| {
+            state
+                .events
+                .iter()
+                .find(|event| event.id.wrapping_sub(id) as i32 > 0)
+                .copied()
+        })
+    }
+
+    /// The id of the latest event, or 0 if there hasn't been one.
+    pub fn latest_id(&self) -> u32 {
+        self.lock(|state| state.events.back().map_or(0, |event| event.id))
+    }
+
+    /// Watch for new events, or `None` if too many are already watching.
+    pub fn subscribe(&self) -> Option<Receiver<'_, CriticalSectionRawMutex, u32, MAX_CLIENTS>> {
+        self.latest.receiver()
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
@@ -0,0 +1,200 @@
+//! Overrides for inputs that can't be trusted, so that the machine keeps running
+//! on a failed sensor until it is fixed.
+//!
+//! A forced input reads as on or off whatever the sensor says, and an ignored one
+//! holds the value it had when it was ignored, so a sensor that chatters doesn't
+//! generate changes. Forces are lost on restart unless they were set to persist.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::pac_man_ball::Inputs;
+use crate::storage::{self, Record, Storage};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Force {
+    On,
+    Off,
+    /// Hold the value the input had when it was ignored, or off if it was restored
+    /// before the inputs were first read.
+    Ignore,
+}
+
+impl Force {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::On => "on",
+            Self::Off => "off",
+            Self::Ignore => "ignore",
+        }
+    }
+}
+
+/// The force on each input, if any, in the same order as `Inputs::FIELDS`.
+pub type Forces = [Option<Force>; Inputs::FIELDS.len()];
+
+struct State {
+    forces: Forces,
+    /// Which forces outlast a restart.
+    persist: [bool; Inputs::FIELDS.len()],
+    /// The last inputs read, before forcing, for ignoring an input at.
+    raw: Option<Inputs>,
+    /// What ignored inputs read as.
+    held: [bool; Inputs::FIELDS.len()],
+}
+
+pub struct InputForces {
+    state: BlockingMutex<CriticalSectionRawMutex, RefCell<State>>,
+    /// Raised when the persistent forces change.
+    persist: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl InputForces {
+    pub const fn new() -> Self {
+        Self {
+            state: BlockingMutex::new(RefCell::new(State {
+                forces: [None; Inputs::FIELDS.len()],
+                persist: [false; Inputs::FIELDS.len()],
+                raw: None,
+                held: [false; Inputs::FIELDS.len()],
+            })),
+            persist: Signal::new(),
+        }
+    }
+
+    /// The inputs as the rest of the firmware should see them.
+    pub fn apply(&self, inputs: Inputs) -> Inputs {
+        self.lock(|state| {
+            let mut forced = inputs.clone();
+            for (i, field) in Inputs::FIELDS.iter().enumerate() {
+                if let Some(force) = state.forces[i] {
+                    let value = match force {
+                        Force::On => true,
+                        Force::Off => false,
+                        Force::Ignore => state.held[i],
+                    };
+                    forced.set(field, value);
+                }
+            }
+            state.raw = Some(inputs);
+            forced
+        })
+    }
+
+    /// Force `input`, or clear its force with `None`. Returns `false` if there is no
+    /// such input.
+    pub fn set(&self, input: &str, force: Option<Force>, persist: bool) -> bool {
+        let Some(persisted) = self.update(input, force, persist) else {
+            return false;
+        };
+        if persisted {
+            self.persist.signal(());
+        }
+        true
+    }
+
+    /// Set the force on `input`, returning whether that changed what is persisted,
+    /// or `None` if there is no such input.
+    fn update(&self, input: &str, force: Option<Force>, persist: bool) -> Option<bool> {
+        let i = Inputs::FIELDS.iter().position(|field| *field == input)?;
+        let persisted = self.lock(|state| {
+            if force == Some(Force::Ignore) && state.forces[i] != Some(Force::Ignore) {
+                state.held[i] = state
+                    .raw
+                    .as_ref()
+                    .and_then(|raw| raw.get(input))
+                    .unwrap_or_default();
+            }
+            let persisted = state.persist[i];
+            state.forces[i] = force;
+            state.persist[i] = persist && force.is_some();
+            persisted || state.persist[i]
+        });
+        match force {
+            Some(force) => warn!("Forcing {} to {:?}", input, force),
+            None => info!("Cleared the force on {}", input),
+        }
+        Some(persisted)
+    }
+
+    pub fn forces(&self) -> Forces {
+        self.lock(|state| state.forces)
+    }
+
+    /// Each forced input and its force, e.g. `{"hopper_out_sensor":{"force":"on","persist":true}}`.
+    pub fn report(&self) -> ForcesReport {
+        self.lock(|state| ForcesReport {
+            forces: state.forces,
+            persist: state.persist,
+        })
+    }
+
+    /// Reapply the forces that were set to persist.
+    pub async fn restore<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) {
+        let Some(forces) = storage::load::<Forces, _>(storage, Record::InputForces).await else {
+            return;
+        };
+        for (field, force) in Inputs::FIELDS.iter().zip(forces) {
+            if force.is_some() {
+                self.update(field, force, true);
+            }
+        }
+    }
+
+    /// Save the persistent forces whenever they change.
+    pub async fn persist<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) -> ! {
+        loop {
+            self.persist.wait().await;
+            let forces = self.lock(|state| {
+                let mut forces = state.forces;
+                for (force, persist) in forces.iter_mut().zip(state.persist) {
+                    if !persist {
+                        *force = None;
+                    }
+                }
+                forces
+            });
+            storage::save(storage, Record::InputForces, &forces).await;
+        }
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+impl Default for InputForces {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ForcesReport {
+    forces: Forces,
+    persist: [bool; Inputs::FIELDS.len()],
+}
+
+impl Serialize for ForcesReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Entry {
+            force: Force,
+            persist: bool,
+        }
+
+        let mut map = serializer.serialize_map(None)?;
+        for ((field, force), persist) in Inputs::FIELDS.iter().zip(self.forces).zip(self.persist) {
+            if let Some(force) = force {
+                map.serialize_entry(field, &Entry { force, persist })?;
+            }
+        }
+        map.end()
+    }
+}
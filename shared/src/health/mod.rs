@@ -0,0 +1,204 @@
+//! Spotting failed sensors. An optical sensor that fails, or whose wire breaks,
+//! just reads permanently active or inactive, which looks the same as nothing
+//! happening, so each `Rule` names an output that should make a sensor toggle.
+//!
+//! While the output is on, the gaps between the sensor's edges are learned, and a
+//! sensor that goes much longer than usual without one, or never toggles at all,
+//! is reported once as a `Fault::StuckSensor` until it toggles again.
+//!
+//! A stuck sensor also stops the table or a hopper delivering, so while the
+//! `Tachometer` holds a stall, or `BallFlow` a jam, for a rule's output, it's
+//! left to that fault rather than being reported twice.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::ball_flow::{self, BallFlow};
+use crate::events::{EventLog, Fault};
+use crate::pac_man_ball::{Inputs, Outputs};
+use crate::tachometer::{self, Tachometer};
+
+/// How many times the usual gap between edges a sensor may go without one.
+const STUCK_FACTOR: u32 = 4;
+
+/// A sensor that should toggle while an output is on.
+pub struct Rule {
+    pub input: &'static str,
+    pub output: &'static str,
+    /// The shortest time the sensor may be stuck before it's reported, however
+    /// quickly it usually toggles.
+    pub min: Duration,
+    /// The longest, and how long is allowed before the usual gap is learned.
+    pub max: Duration,
+}
+
+// No sooner than the stall or jam the same sensor would cause, so that fault is
+// raised first.
+pub const RULES: [Rule; 4] = [
+    // The table has a single flag per revolution.
+    Rule {
+        input: "table_sensor",
+        output: "table_motor",
+        min: tachometer::STALL,
+        max: Duration::from_secs(10),
+    },
+    Rule {
+        input: "hopper_left_sensor",
+        output: "left_hopper",
+        min: ball_flow::JAM,
+        max: Duration::from_secs(10),
+    },
+    Rule {
+        input: "hopper_right_sensor",
+        output: "right_hopper",
+        min: ball_flow::JAM,
+        max: Duration::from_secs(10),
+    },
+    Rule {
+        input: "hopper_out_sensor",
+        output: "out_hopper",
+        min: ball_flow::JAM,
+        max: Duration::from_secs(10),
+    },
+];
+
+#[derive(Clone, Copy)]
+struct Watch {
+    /// The output's last known state.
+    running: bool,
+    /// The sensor's last known value.
+    active: Option<bool>,
+    /// The last edge, or when the output came on if there hasn't been one since.
+    since: Option<Instant>,
+    /// Whether `since` was an edge, so that the time to spin up isn't learned.
+    edged: bool,
+    /// The usual gap between edges while running, once one has been seen.
+    usual: Option<Duration>,
+    /// Whether the sensor has already been reported stuck.
+    stuck: bool,
+}
+
+impl Watch {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            active: None,
+            since: None,
+            edged: false,
+            usual: None,
+            stuck: false,
+        }
+    }
+
+    fn limit(&self, rule: &Rule) -> Duration {
+        match self.usual {
+            Some(usual) => (usual * STUCK_FACTOR).max(rule.min).min(rule.max),
+            None => rule.max,
+        }
+    }
+}
+
+pub struct SensorHealth<'a> {
+    events: &'a EventLog<'a>,
+    tachometer: &'a Tachometer<'a>,
+    ball_flow: &'a BallFlow<'a>,
+    watches: Mutex<CriticalSectionRawMutex, RefCell<[Watch; RULES.len()]>>,
+}
+
+impl<'a> SensorHealth<'a> {
+    pub const fn new(
+        events: &'a EventLog<'a>,
+        tachometer: &'a Tachometer<'a>,
+        ball_flow: &'a BallFlow<'a>,
+    ) -> Self {
+        Self {
+            events,
+            tachometer,
+            ball_flow,
+            watches: Mutex::new(RefCell::new([Watch::new(); RULES.len()])),
+        }
+    }
+
+    /// Note which outputs are on, starting the clock on sensors that should now be
+    /// toggling.
+    pub fn record_outputs(&self, outputs: &Outputs) {
+        let now = Instant::now();
+        self.lock(|watches| {
+            for (rule, watch) in RULES.iter().zip(watches.iter_mut()) {
+                let running = outputs.get(rule.output).unwrap_or_default();
+                if running && !watch.running {
+                    watch.since = Some(now);
+                    watch.edged = false;
+                }
+                watch.running = running;
+            }
+        });
+    }
+
+    /// Check the sensors against the outputs, reporting any that have stuck.
+    pub fn record_inputs(&self, inputs: &Inputs) {
+        let now = Instant::now();
+        let mut faults = heapless::Vec::<Fault, { RULES.len() }>::new();
+        let covered = RULES.each_ref().map(|rule| self.covered(rule));
+        self.lock(|watches| {
+            for ((rule, watch), covered) in RULES.iter().zip(watches.iter_mut()).zip(covered) {
+                let active = inputs.get(rule.input).unwrap_or_default();
+                let since = *watch.since.get_or_insert(now);
+                let edge = watch
+                    .active
+                    .replace(active)
+                    .is_some_and(|was| was != active);
+
+                if !edge {
+                    if watch.running && !watch.stuck && !covered && now - since > watch.limit(rule)
+                    {
+                        warn!(
+                            "{} stuck {} while {} is on",
+                            rule.input,
+                            if active { "active" } else { "inactive" },
+                            rule.output
+                        );
+                        watch.stuck = true;
+                        let _ = faults.push(Fault::StuckSensor {
+                            input: rule.input,
+                            active,
+                        });
+                    }
+                    continue;
+                }
+
+                // Only whole gaps short enough to be healthy count towards the usual one.
+                let gap = now - since;
+                if watch.running && watch.edged && gap <= rule.max {
+                    watch.usual = Some(match watch.usual {
+                        Some(usual) => (usual * 7 + gap) / 8,
+                        None => gap,
+                    });
+                }
+                watch.since = Some(now);
+                watch.edged = true;
+                if watch.stuck {
+                    info!("{} is toggling again", rule.input);
+                    watch.stuck = false;
+                }
+            }
+        });
+        for fault in faults {
+            self.events.record_fault(fault);
+        }
+    }
+
+    /// Whether a stall or jam is already reported for `rule`'s output.
+    fn covered(&self, rule: &Rule) -> bool {
+        match rule.output {
+            "table_motor" => self.tachometer.stalled(),
+            hopper => self.ball_flow.jammed(hopper),
+        }
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut [Watch; RULES.len()]) -> R) -> R {
+        self.watches.lock(|watches| f(&mut watches.borrow_mut()))
+    }
+}
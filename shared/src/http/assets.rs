@@ -0,0 +1,81 @@
+//! Static files for the web UI, bundled and gzipped by the build script.
+
+use edge_http::io::server::Connection;
+use embedded_io_async::{Read, Write};
+
+use crate::http::ws::Error;
+
+pub struct Asset {
+    /// Request paths the asset is served at.
+    pub paths: &'static [&'static str],
+    pub content_type: &'static str,
+    pub cache_control: &'static str,
+    /// Quoted, as it appears in the `ETag` header.
+    pub etag: &'static str,
+    /// The gzipped content.
+    pub body: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// The asset served at a request path, ignoring any query string.
+pub fn find(path: &str) -> Option<&'static Asset> {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    ASSETS.iter().find(|asset| asset.paths.contains(&path))
+}
+
+pub(crate) async fn handle_asset<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    asset: &Asset,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = &conn.headers()?.headers;
+
+    let not_modified = headers
+        .get("If-None-Match")
+        .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == asset.etag));
+    // Only the compressed copy is kept, so there is nothing to offer clients that can't take it.
+    let accepts_gzip = headers.get("Accept-Encoding").is_some_and(|encodings| {
+        encodings
+            .split(',')
+            .any(|encoding| encoding.split(';').next().unwrap_or_default().trim() == "gzip")
+    });
+
+    let caching = [
+        ("Cache-Control", asset.cache_control),
+        ("ETag", asset.etag),
+        ("Vary", "Accept-Encoding"),
+    ];
+
+    if not_modified {
+        conn.initiate_response(304, Some("Not Modified"), &caching)
+            .await?;
+    } else if !accepts_gzip {
+        conn.initiate_response(
+            406,
+            Some("Not Acceptable"),
+            &[("Content-Type", "text/plain")],
+        )
+        .await?;
+        conn.write_all(b"Only gzip encoding is available\n").await?;
+    } else {
+        let [cache_control, etag, vary] = caching;
+        conn.initiate_response(
+            200,
+            Some("OK"),
+            &[
+                ("Content-Type", asset.content_type),
+                ("Content-Encoding", "gzip"),
+                cache_control,
+                etag,
+                vary,
+            ],
+        )
+        .await?;
+        conn.write_all(asset.body).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! Access control for the HTTP server.
+//!
+//! Logging in with a PIN (`POST /login`, PIN as the body) hands out a session
+//! token as both a cookie and a JSON body. Requests present it either as that
+//! cookie, which is what browsers send on the WS upgrade, or as an
+//! `Authorization: Bearer` header for scripts.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use edge_http::io::server::Connection;
+use edge_http::Headers;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use embedded_io_async::{Read, Write};
+use serde::{Deserialize, Serialize};
+
+use crate::http::ws::Error;
+
+const MAX_SESSIONS: usize = 8;
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+const MAX_FAILED_LOGINS: u8 = 5;
+const LOCKOUT: Duration = Duration::from_secs(30);
+const COOKIE_NAME: &str = "session";
+const MAX_PIN_LEN: usize = 64;
+
+pub type Token = heapless::String<32>;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can watch the inputs, but not drive the outputs.
+    Viewer,
+    Operator,
+}
+
+pub struct AuthConfig<'a> {
+    /// PIN or password granting the operator role.
+    pub operator_pin: &'a str,
+    /// PIN granting the viewer role, or `None` to let anyone watch without logging in.
+    pub viewer_pin: Option<&'a str>,
+    /// Source of randomness for session tokens.
+    pub entropy: fn() -> u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginResponse {
+    pub token: Token,
+    pub role: Role,
+}
+
+struct Session {
+    token: Token,
+    role: Role,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: [Option<Session>; MAX_SESSIONS],
+    failed_logins: u8,
+    locked_until: Option<Instant>,
+}
+
+pub struct Auth<'a> {
+    config: AuthConfig<'a>,
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+}
+
+impl<'a> Auth<'a> {
+    pub fn new(config: AuthConfig<'a>) -> Self {
+        Self {
+            config,
+            state: Mutex::new(RefCell::new(State::default())),
+        }
+    }
+
+    /// Exchange a PIN for a new session, or `None` if it is wrong or logins are locked out.
+    pub fn login(&self, pin: &str) -> Option<LoginResponse> {
+        let now = Instant::now();
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+
+            if state.locked_until.is_some_and(|until| now < until) {
+                warn!("Login attempt while locked out");
+                return None;
+            }
+
+            let role = if constant_time_eq(pin, self.config.operator_pin) {
+                Role::Operator
+            } else if self
+                .config
+                .viewer_pin
+                .is_some_and(|viewer_pin| constant_time_eq(pin, viewer_pin))
+            {
+                Role::Viewer
+            } else {
+                state.failed_logins += 1;
+                if state.failed_logins >= MAX_FAILED_LOGINS {
+                    warn!("Too many failed logins, locking out");
+                    state.failed_logins = 0;
+                    state.locked_until = Some(now + LOCKOUT);
+                }
+                return None;
+            };
+            state.failed_logins = 0;
+
+            let token = self.new_token();
+            let session = Session {
+                token: token.clone(),
+                role,
+                last_used: now,
+            };
+
+            // Take a free or expired slot, otherwise evict the least recently used session.
+            let slot = state
+                .sessions
+                .iter_mut()
+                .min_by_key(|slot| match slot {
+                    Some(session) if now - session.last_used < SESSION_IDLE_TIMEOUT => {
+                        Some(session.last_used)
+                    }
+                    _ => None,
+                })
+                .unwrap();
+            *slot = Some(session);
+
+            info!("New {:?} session", role);
+
+            Some(LoginResponse { token, role })
+        })
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.state.lock(|state| {
+            for slot in state.borrow_mut().sessions.iter_mut() {
+                if slot
+                    .as_ref()
+                    .is_some_and(|session| constant_time_eq(&session.token, token))
+                {
+                    *slot = None;
+                }
+            }
+        })
+    }
+
+    /// The role granted to a request, or `None` if it must log in first.
+    pub fn authenticate<const N: usize>(&self, headers: &Headers<'_, N>) -> Option<Role> {
+        let role = session_token(headers).and_then(|token| self.check(token));
+        match (role, self.config.viewer_pin) {
+            (Some(role), _) => Some(role),
+            (None, None) => Some(Role::Viewer),
+            (None, Some(_)) => None,
+        }
+    }
+
+    fn check(&self, token: &str) -> Option<Role> {
+        let now = Instant::now();
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            let session = state
+                .sessions
+                .iter_mut()
+                .flatten()
+                .find(|session| constant_time_eq(&session.token, token))?;
+            if now - session.last_used >= SESSION_IDLE_TIMEOUT {
+                return None;
+            }
+            session.last_used = now;
+            Some(session.role)
+        })
+    }
+
+    fn new_token(&self) -> Token {
+        let mut token = Token::new();
+        for _ in 0..2 {
+            write_unwrap!(token, "{:016x}", (self.config.entropy)());
+        }
+        token
+    }
+}
+
+pub(crate) async fn handle_login<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let mut buf = [0_u8; MAX_PIN_LEN];
+    let mut len = 0;
+    while len < buf.len() {
+        let read = conn.read(&mut buf[len..]).await?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+
+    let pin = core::str::from_utf8(&buf[..len]).unwrap_or_default().trim();
+    let Some(response) = auth.login(pin) else {
+        conn.initiate_response(401, Some("Unauthorized"), &[])
+            .await?;
+        return Ok(());
+    };
+
+    let mut cookie = heapless::String::<96>::new();
+    write_unwrap!(
+        cookie,
+        "{}={}; Path=/; HttpOnly; SameSite=Strict",
+        COOKIE_NAME,
+        response.token
+    );
+
+    let mut buf = [0_u8; 128];
+    let size = serde_json_core::to_slice(&response, &mut buf)?;
+    conn.initiate_response(
+        200,
+        Some("OK"),
+        &[
+            ("Content-Type", "application/json"),
+            ("Set-Cookie", &cookie),
+        ],
+    )
+    .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
+
+pub(crate) async fn handle_logout<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    if let Some(token) = session_token(&conn.headers()?.headers) {
+        auth.logout(token);
+    }
+
+    let mut cookie = heapless::String::<64>::new();
+    write_unwrap!(cookie, "{}=; Path=/; Max-Age=0", COOKIE_NAME);
+    conn.initiate_response(200, Some("OK"), &[("Set-Cookie", &cookie)])
+        .await?;
+
+    Ok(())
+}
+
+/// The session token from either the `Authorization` header or the session cookie.
+fn session_token<'h, const N: usize>(headers: &'h Headers<'_, N>) -> Option<&'h str> {
+    headers
+        .get("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get("Cookie")?
+                .split(';')
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(name, _)| *name == COOKIE_NAME)
+                .map(|(_, value)| value)
+        })
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,26 @@
+//! Ball counts over REST, from `GET /api/balls`.
+
+use edge_http::io::server::Connection;
+use embedded_io_async::{Read, Write};
+
+use crate::ball_flow::BallFlow;
+use crate::http::ws::Error;
+
+/// Room for every count at its largest.
+const MAX_REPORT_LEN: usize = 512;
+
+pub(crate) async fn handle_balls<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    ball_flow: &BallFlow<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let mut buf = [0_u8; MAX_REPORT_LEN];
+    let size = serde_json_core::to_slice(&ball_flow.report(), &mut buf)?;
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", "application/json")])
+        .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! The audits over REST. `GET /api/bookkeeping` reports the counters as JSON, and
+//! `GET /api/bookkeeping.csv` as a spreadsheet, a row per counter. An operator
+//! resets them with `POST /api/bookkeeping`.
+
+use core::fmt::Write as _;
+
+use edge_http::io::server::Connection;
+use edge_http::Method;
+use embedded_io_async::{Read, Write};
+
+use crate::bookkeeping::Bookkeeping;
+use crate::http::auth::{Auth, Role};
+use crate::http::respond;
+use crate::http::ws::Error;
+
+const MAX_REPORT_LEN: usize = 640;
+
+pub(crate) async fn handle_bookkeeping<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    bookkeeping: &Bookkeeping<'_>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = conn.headers()?;
+    let role = auth.authenticate(&headers.headers);
+    match headers.method {
+        Method::Get if role.is_none() => {
+            return respond(conn, 401, "Unauthorized", "Login required\n").await;
+        }
+        Method::Get => {}
+        Method::Post if role != Some(Role::Operator) => {
+            return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+        }
+        Method::Post => bookkeeping.reset(),
+        _ => {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let mut buf = [0_u8; MAX_REPORT_LEN];
+    let size = serde_json_core::to_slice(&bookkeeping.report(), &mut buf)?;
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", "application/json")])
+        .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
+
+pub(crate) async fn handle_bookkeeping_csv<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    bookkeeping: &Bookkeeping<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let report = bookkeeping.report();
+
+    conn.initiate_response(
+        200,
+        Some("OK"),
+        &[
+            ("Content-Type", "text/csv"),
+            ("Content-Disposition", "attachment; filename=\"audits.csv\""),
+        ],
+    )
+    .await?;
+
+    let mut line = heapless::String::<64>::new();
+    conn.write_all(b"counter,since_reset,lifetime\n").await?;
+    for ((counter, since_reset), (_, lifetime)) in
+        report.since_reset.iter().zip(report.lifetime.iter())
+    {
+        line.clear();
+        write_unwrap!(line, "{},{},{}\n", counter, since_reset, lifetime);
+        conn.write_all(line.as_bytes()).await?;
+    }
+
+    Ok(())
+}
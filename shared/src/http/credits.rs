@@ -0,0 +1,118 @@
+//! Credits over REST. `GET /api/credits` reports the credits and settings, and
+//! `POST /api/credits` with e.g.
+//! `{"coin_values":[1,2,5],"units_per_credit":5,"credits_per_play":1,"free_play":false}`
+//! changes the settings. Game logic starts a game with `POST /api/credits/play`,
+//! which takes the credits for it.
+
+use edge_http::io::server::Connection;
+use edge_http::Method;
+use embedded_io_async::{Read, Write};
+
+use crate::credits::{Credits, Settings};
+use crate::http::auth::{Auth, Role};
+use crate::http::respond;
+use crate::http::ws::Error;
+
+const MAX_REPORT_LEN: usize = 256;
+const MAX_REQUEST_LEN: usize = 192;
+
+pub(crate) async fn handle_credits<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    credits: &Credits<'_>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = conn.headers()?;
+    let role = auth.authenticate(&headers.headers);
+    match headers.method {
+        Method::Get if role.is_none() => {
+            return respond(conn, 401, "Unauthorized", "Login required\n").await;
+        }
+        Method::Get => {}
+        Method::Post if role != Some(Role::Operator) => {
+            return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+        }
+        Method::Post => {
+            let mut buf = [0_u8; MAX_REQUEST_LEN];
+            let mut len = 0;
+            while len < buf.len() {
+                let read = conn.read(&mut buf[len..]).await?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            let Ok((settings, _)) = serde_json_core::from_slice::<Settings>(&buf[..len]) else {
+                return respond(
+                    conn,
+                    400,
+                    "Bad Request",
+                    "Expected {\"coin_values\":[...],\"units_per_credit\":...,\"credits_per_play\":...,\"free_play\":...}\n",
+                )
+                .await;
+            };
+            if !credits.set_settings(settings) {
+                return respond(
+                    conn,
+                    400,
+                    "Bad Request",
+                    "units_per_credit and credits_per_play must be at least 1\n",
+                )
+                .await;
+            }
+        }
+        _ => {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+            return Ok(());
+        }
+    }
+
+    respond_report(conn, credits).await
+}
+
+pub(crate) async fn handle_play<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    credits: &Credits<'_>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = conn.headers()?;
+    let role = auth.authenticate(&headers.headers);
+    match headers.method {
+        Method::Post if role != Some(Role::Operator) => {
+            return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+        }
+        Method::Post if !credits.start_play() => {
+            return respond(conn, 402, "Payment Required", "Not enough credits\n").await;
+        }
+        Method::Post => {}
+        _ => {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+            return Ok(());
+        }
+    }
+
+    respond_report(conn, credits).await
+}
+
+async fn respond_report<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    credits: &Credits<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let mut buf = [0_u8; MAX_REPORT_LEN];
+    let size = serde_json_core::to_slice(&credits.report(), &mut buf)?;
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", "application/json")])
+        .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! Per-client statistics for the connected WS clients, served as JSON from
+//! `GET /diagnostics`.
+
+use core::cell::RefCell;
+
+use edge_http::io::server::Connection;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embedded_io_async::{Read, Write};
+use serde::{Deserialize, Serialize};
+
+use crate::http::auth::Role;
+use crate::http::ws::{Error, ErrorCode, Protocol};
+
+/// One per HTTP handler task, the most that can be connected at once.
+pub const MAX_CLIENTS: usize = 4;
+
+/// Protocol errors reported to a client, by error code.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProtocolErrors {
+    pub forbidden: u32,
+    pub malformed: u32,
+    pub unknown_field: u32,
+    pub invalid_value: u32,
+}
+
+impl ProtocolErrors {
+    fn record(&mut self, code: ErrorCode) {
+        let count = match code {
+            ErrorCode::Forbidden => &mut self.forbidden,
+            ErrorCode::Malformed => &mut self.malformed,
+            ErrorCode::UnknownField => &mut self.unknown_field,
+            ErrorCode::InvalidValue => &mut self.invalid_value,
+        };
+        *count = count.saturating_add(1);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClientDiagnostics {
+    /// Increments with each connection, so a reconnecting client gets a new id.
+    pub id: u32,
+    pub role: Role,
+    pub protocol: Protocol,
+    pub protocol_errors: ProtocolErrors,
+    pub frames_sent: u32,
+    pub frames_received: u32,
+}
+
+/// WS frame counts across all clients, past and present.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrameTotals {
+    pub sent: u64,
+    pub received: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Diagnostics {
+    pub clients: heapless::Vec<ClientDiagnostics, MAX_CLIENTS>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u32,
+    clients: [Option<ClientDiagnostics>; MAX_CLIENTS],
+    frames: FrameTotals,
+}
+
+/// The connected WS clients.
+pub struct Clients {
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(State::default())),
+        }
+    }
+
+    /// Track a newly connected client until the returned guard is dropped.
+    pub fn connect(&self, role: Role, protocol: Protocol) -> Client<'_> {
+        let slot = self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            let id = state.next_id;
+            state.next_id = id.wrapping_add(1);
+            let slot = state.clients.iter().position(Option::is_none)?;
+            state.clients[slot] = Some(ClientDiagnostics {
+                id,
+                role,
+                protocol,
+                protocol_errors: ProtocolErrors::default(),
+                frames_sent: 0,
+                frames_received: 0,
+            });
+            Some(slot)
+        });
+        if slot.is_none() {
+            warn!("Too many WS clients to track");
+        }
+        Client {
+            clients: self,
+            slot,
+        }
+    }
+
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.state.lock(|state| Diagnostics {
+            clients: state.borrow().clients.iter().flatten().cloned().collect(),
+        })
+    }
+
+    pub fn connected(&self) -> usize {
+        self.state
+            .lock(|state| state.borrow().clients.iter().flatten().count())
+    }
+
+    pub fn frame_totals(&self) -> FrameTotals {
+        self.state.lock(|state| state.borrow().frames)
+    }
+
+    fn update(
+        &self,
+        slot: Option<usize>,
+        f: impl FnOnce(&mut FrameTotals, Option<&mut ClientDiagnostics>),
+    ) {
+        self.state.lock(|state| {
+            let State {
+                clients, frames, ..
+            } = &mut *state.borrow_mut();
+            f(frames, slot.and_then(|slot| clients[slot].as_mut()));
+        });
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Client<'a> {
+    clients: &'a Clients,
+    slot: Option<usize>,
+}
+
+impl Client<'_> {
+    pub fn record_error(&self, code: ErrorCode) {
+        self.clients.update(self.slot, |_, client| {
+            if let Some(client) = client {
+                client.protocol_errors.record(code);
+            }
+        });
+    }
+
+    pub fn record_frame_sent(&self) {
+        self.clients.update(self.slot, |totals, client| {
+            totals.sent += 1;
+            if let Some(client) = client {
+                client.frames_sent = client.frames_sent.wrapping_add(1);
+            }
+        });
+    }
+
+    pub fn record_frame_received(&self) {
+        self.clients.update(self.slot, |totals, client| {
+            totals.received += 1;
+            if let Some(client) = client {
+                client.frames_received = client.frames_received.wrapping_add(1);
+            }
+        });
+    }
+}
+
+impl Drop for Client<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            self.clients
+                .state
+                .lock(|state| state.borrow_mut().clients[slot] = None);
+        }
+    }
+}
+
+pub(crate) async fn handle_diagnostics<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    clients: &Clients,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let mut buf = [0_u8; 1024];
+    let size = serde_json_core::to_slice(&clients.diagnostics(), &mut buf)?;
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", "application/json")])
+        .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
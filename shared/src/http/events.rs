@@ -0,0 +1,83 @@
+//! Streams the event log as Server-Sent Events, for clients that can't speak WS.
+
+use core::fmt::Write as _;
+
+use edge_http::io::server::Connection;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+
+use crate::events::EventLog;
+use crate::http::ws::Error;
+
+/// Comment lines sent while nothing is happening, so that a client that has gone
+/// away is noticed, and proxies don't time the stream out.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+pub(crate) async fn handle_events<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    events: &EventLog<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    // Subscribe before looking at the log, so no event can slip in between.
+    let Some(mut latest) = events.subscribe() else {
+        warn!("Too many event streams");
+        conn.initiate_response(503, Some("Service Unavailable"), &[])
+            .await?;
+        return Ok(());
+    };
+
+    // Without Last-Event-ID, a client only gets what happens from now on. An id
+    // ahead of the log is from before a restart, which started the ids over, so
+    // the client has missed nothing it can be sent.
+    let latest_id = events.latest_id();
+    let mut cursor = conn
+        .headers()?
+        .headers
+        .get("Last-Event-ID")
+        .and_then(|id| id.trim().parse::<u32>().ok())
+        .filter(|id| id.wrapping_sub(latest_id) as i32 <= 0)
+        .unwrap_or(latest_id);
+
+    conn.initiate_response(
+        200,
+        Some("OK"),
+        &[
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+        ],
+    )
+    .await?;
+
+    let mut data = [0_u8; 128];
+    let mut line = heapless::String::<48>::new();
+    loop {
+        while let Some(event) = events.after(cursor) {
+            if event.id.wrapping_sub(cursor) > 1 && cursor != 0 {
+                warn!("Event stream fell behind the log, skipping to {}", event.id);
+            }
+            cursor = event.id;
+
+            let size = serde_json_core::to_slice(&event, &mut data)?;
+            line.clear();
+            write_unwrap!(
+                line,
+                "id: {}\nevent: {}\ndata: ",
+                event.id,
+                event.kind.name()
+            );
+            conn.write_all(line.as_bytes()).await?;
+            conn.write_all(&data[..size]).await?;
+            conn.write_all(b"\n\n").await?;
+        }
+        conn.flush().await?;
+
+        if let Either::Second(()) =
+            select(latest.changed(), Timer::after(KEEP_ALIVE_INTERVAL)).await
+        {
+            conn.write_all(b": keep-alive\n\n").await?;
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Input forcing over REST. `GET /api/forces` lists the forced inputs, and
+//! `POST /api/forces` with e.g. `{"input":"hopper_out_sensor","force":"on","persist":true}`
+//! forces one, or clears its force with `"force":null`.
+
+use edge_http::io::server::Connection;
+use edge_http::Method;
+use embedded_io_async::{Read, Write};
+use serde::Deserialize;
+
+use crate::forces::{Force, InputForces};
+use crate::http::auth::{Auth, Role};
+use crate::http::respond;
+use crate::http::ws::Error;
+
+/// Room for every input forced, with the longest names.
+const MAX_REPORT_LEN: usize = 1536;
+const MAX_REQUEST_LEN: usize = 128;
+
+#[derive(Deserialize)]
+struct ForceRequest<'a> {
+    input: &'a str,
+    force: Option<Force>,
+    #[serde(default)]
+    persist: bool,
+}
+
+pub(crate) async fn handle_forces<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    forces: &InputForces,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = conn.headers()?;
+    let role = auth.authenticate(&headers.headers);
+    match headers.method {
+        Method::Get if role.is_none() => {
+            return respond(conn, 401, "Unauthorized", "Login required\n").await;
+        }
+        Method::Get => {}
+        Method::Post if role != Some(Role::Operator) => {
+            return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+        }
+        Method::Post => {
+            let mut buf = [0_u8; MAX_REQUEST_LEN];
+            let mut len = 0;
+            while len < buf.len() {
+                let read = conn.read(&mut buf[len..]).await?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            let Ok((request, _)) = serde_json_core::from_slice::<ForceRequest>(&buf[..len]) else {
+                return respond(
+                    conn,
+                    400,
+                    "Bad Request",
+                    "Expected {\"input\":...,\"force\":\"on\", \"off\", \"ignore\" or null}\n",
+                )
+                .await;
+            };
+            if !forces.set(request.input, request.force, request.persist) {
+                return respond(conn, 400, "Bad Request", "No such input\n").await;
+            }
+        }
+        _ => {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let mut buf = [0_u8; MAX_REPORT_LEN];
+    let size = serde_json_core::to_slice(&forces.report(), &mut buf)?;
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", "application/json")])
+        .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
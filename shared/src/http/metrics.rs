@@ -0,0 +1,110 @@
+//! Renders the metrics in the Prometheus text exposition format.
+
+use core::fmt::Write as _;
+
+use edge_http::io::server::Connection;
+use embassy_time::Duration;
+use embedded_io_async::{Read, Write};
+
+use crate::http::diagnostics::Clients;
+use crate::http::ws::Error;
+use crate::metrics::Metrics;
+use crate::pac_man_ball::{Inputs, Outputs};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+pub(crate) async fn handle_metrics<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    metrics: &Metrics,
+    clients: &Clients,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let snapshot = metrics.snapshot();
+    let frames = clients.frame_totals();
+
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", CONTENT_TYPE)])
+        .await?;
+
+    // Rendered a line at a time, as the whole page is several KB.
+    let mut line = heapless::String::<128>::new();
+    macro_rules! line {
+        ($($arg:tt)*) => {{
+            line.clear();
+            write_unwrap!(line, $($arg)*);
+            line.push('\n').unwrap();
+            conn.write_all(line.as_bytes()).await?;
+        }};
+    }
+
+    line!("# HELP pacman_sensor_activations_total Times each sensor has become active.");
+    line!("# TYPE pacman_sensor_activations_total counter");
+    for (sensor, count) in Inputs::FIELDS.iter().zip(snapshot.activations) {
+        line!(
+            "pacman_sensor_activations_total{{sensor=\"{}\"}} {}",
+            sensor,
+            count
+        );
+    }
+
+    line!("# HELP pacman_output_on_seconds_total Time each output has spent on.");
+    line!("# TYPE pacman_output_on_seconds_total counter");
+    for (output, on_time) in Outputs::FIELDS.iter().zip(snapshot.on_time) {
+        line!(
+            "pacman_output_on_seconds_total{{output=\"{}\"}} {}",
+            output,
+            Seconds(on_time)
+        );
+    }
+
+    line!("# HELP pacman_i2c_errors_total Failed I2C transactions with each expander.");
+    line!("# TYPE pacman_i2c_errors_total counter");
+    for (address, count) in snapshot.i2c_errors.iter() {
+        line!(
+            "pacman_i2c_errors_total{{expander=\"0x{:02x}\"}} {}",
+            address,
+            count
+        );
+    }
+
+    line!("# HELP pacman_ws_clients WS clients currently connected.");
+    line!("# TYPE pacman_ws_clients gauge");
+    line!("pacman_ws_clients {}", clients.connected());
+
+    line!("# HELP pacman_ws_frames_sent_total WS frames sent to clients.");
+    line!("# TYPE pacman_ws_frames_sent_total counter");
+    line!("pacman_ws_frames_sent_total {}", frames.sent);
+
+    line!("# HELP pacman_ws_frames_received_total WS frames received from clients.");
+    line!("# TYPE pacman_ws_frames_received_total counter");
+    line!("pacman_ws_frames_received_total {}", frames.received);
+
+    line!("# HELP pacman_uptime_seconds Time since boot.");
+    line!("# TYPE pacman_uptime_seconds gauge");
+    line!("pacman_uptime_seconds {}", Seconds(snapshot.uptime));
+
+    // The rest depend on what the target can measure.
+    if let Some(rssi) = snapshot.rssi_dbm {
+        line!("# HELP pacman_wifi_rssi_dbm Signal strength of the WiFi access point.");
+        line!("# TYPE pacman_wifi_rssi_dbm gauge");
+        line!("pacman_wifi_rssi_dbm {}", rssi);
+    }
+    if let Some(bytes) = snapshot.stack_free_bytes {
+        line!("# HELP pacman_stack_free_bytes Stack never yet used, the low-water mark.");
+        line!("# TYPE pacman_stack_free_bytes gauge");
+        line!("pacman_stack_free_bytes {}", bytes);
+    }
+
+    Ok(())
+}
+
+/// Formats a duration as decimal seconds, to the millisecond.
+struct Seconds(Duration);
+
+impl core::fmt::Display for Seconds {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let millis = self.0.as_millis();
+        write!(f, "{}.{:03}", millis / 1000, millis % 1000)
+    }
+}
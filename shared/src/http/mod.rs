@@ -1,24 +1,63 @@
-use edge_http::io::server::DefaultServer;
+use edge_http::io::server::{Connection, DefaultServer};
 use edge_nal::TcpAccept;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
 use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
 
 use crate::{
-    http::ws::WsHandler,
-    pac_man_ball::{Inputs, Outputs},
+    arbiter::Arbiter,
+    ball_flow::BallFlow,
+    bookkeeping::Bookkeeping,
+    clock::WallClock,
+    credits::Credits,
+    events::EventLog,
+    forces::InputForces,
+    http::{auth::Auth, ota::Updater, ws::WsHandler},
+    metrics::Metrics,
+    pac_man_ball::Inputs,
+    self_test::SelfTest,
+    tachometer::Tachometer,
+    tickets::Tickets,
 };
 
+pub mod assets;
+pub mod auth;
+pub mod balls;
+pub mod bookkeeping;
+pub mod credits;
+pub mod diagnostics;
+pub mod events;
+pub mod forces;
+pub mod metrics;
+pub mod ota;
+pub mod self_test;
+pub mod table;
+pub mod tickets;
 pub mod ws;
 
-pub async fn run_server<F, Fut, A, E>(
+#[allow(clippy::too_many_arguments)]
+pub async fn run_server<F, Fut, A, E, U>(
     mut acceptor_fn: F,
-    ingress_signal: &Signal<CriticalSectionRawMutex, Outputs>,
     egress_signal: &Signal<CriticalSectionRawMutex, Inputs>,
+    clock: &WallClock,
+    updater: &Mutex<CriticalSectionRawMutex, U>,
+    auth: &Auth<'_>,
+    metrics: &Metrics,
+    arbiter: &Arbiter,
+    forces: &InputForces,
+    self_test: &SelfTest<'_>,
+    tachometer: &Tachometer<'_>,
+    ball_flow: &BallFlow<'_>,
+    credits: &Credits<'_>,
+    tickets: &Tickets<'_>,
+    bookkeeping: &Bookkeeping<'_>,
+    events: &EventLog<'_>,
 ) -> !
 where
     F: FnMut() -> Fut,
     Fut: core::future::Future<Output = Result<A, E>>,
     A: TcpAccept,
+    U: Updater,
 {
     loop {
         let acceptor = match acceptor_fn().await {
@@ -33,10 +72,41 @@ where
         info!("Server running");
 
         let mut server = DefaultServer::new();
-        let handler = WsHandler::new(ingress_signal, egress_signal);
+        let handler = WsHandler::new(
+            egress_signal,
+            clock,
+            updater,
+            auth,
+            metrics,
+            arbiter,
+            forces,
+            self_test,
+            tachometer,
+            ball_flow,
+            credits,
+            tickets,
+            bookkeeping,
+            events,
+        );
         if server.run(None, acceptor, handler).await.is_err() {
             warn!("Server error, restarting");
             Timer::after(Duration::from_secs(1)).await;
         }
     }
 }
+
+/// Respond with a plain text `body`, for errors worth explaining.
+pub(crate) async fn respond<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    status: u16,
+    message: &str,
+    body: &str,
+) -> Result<(), ws::Error<T::Error>>
+where
+    T: Read + Write,
+{
+    conn.initiate_response(status, Some(message), &[("Content-Type", "text/plain")])
+        .await?;
+    conn.write_all(body.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Over-the-air firmware updates, uploaded with `POST /update`.
+//!
+//! The image is streamed into a staging area, read back and checked against the
+//! SHA-256 given in the `X-Firmware-Sha256` header, then marked to be swapped in
+//! on the next boot. Rolling back an image that never marks itself booted is the
+//! bootloader's job, not ours.
+
+use edge_http::io::server::Connection;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use sha2::{Digest, Sha256};
+
+use crate::http::auth::{Auth, Role};
+use crate::http::respond;
+use crate::http::ws::Error;
+
+const CHUNK_LEN: usize = 1024;
+
+/// Give the response a chance to reach the client before rebooting.
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+
+/// The staging area for a new firmware image.
+#[allow(async_fn_in_trait)]
+pub trait Updater {
+    type Error: core::fmt::Debug;
+
+    /// The largest image that can be staged.
+    fn capacity(&self) -> usize;
+    async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+    /// Swap the staged image in on the next boot.
+    async fn mark_updated(&mut self) -> Result<(), Self::Error>;
+    async fn reboot(&mut self);
+}
+
+pub(crate) async fn handle_update<T, const N: usize, U>(
+    conn: &mut Connection<'_, T, N>,
+    updater: &Mutex<CriticalSectionRawMutex, U>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+    U: Updater,
+{
+    let headers = &conn.headers()?.headers;
+
+    if auth.authenticate(headers) != Some(Role::Operator) {
+        return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+    }
+
+    let Some(len) = headers.content_len() else {
+        return respond(conn, 411, "Length Required", "Content-Length is required\n").await;
+    };
+    let Some(expected) = headers.get("X-Firmware-Sha256").and_then(parse_sha256) else {
+        return respond(
+            conn,
+            400,
+            "Bad Request",
+            "Missing or malformed X-Firmware-Sha256\n",
+        )
+        .await;
+    };
+
+    let Ok(mut updater) = updater.try_lock() else {
+        return respond(conn, 409, "Conflict", "Update already in progress\n").await;
+    };
+
+    let len = len as usize;
+    if len > updater.capacity() {
+        return respond(conn, 413, "Payload Too Large", "Image too large\n").await;
+    }
+
+    info!("Receiving {} byte firmware image", len);
+
+    let mut buf = [0_u8; CHUNK_LEN];
+    let mut offset = 0;
+    while offset < len {
+        let read = conn.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        if let Err(e) = updater.write(offset, &buf[..read]).await {
+            warn!(
+                "Failed to write firmware at {}: {:?}",
+                offset,
+                debug2format!(e)
+            );
+            return respond(conn, 500, "Internal Error", "Failed to write image\n").await;
+        }
+        offset += read;
+    }
+    if offset != len {
+        return respond(conn, 400, "Bad Request", "Image truncated\n").await;
+    }
+
+    // Hash what actually landed in the staging area, not what we were sent.
+    let mut hasher = Sha256::new();
+    for offset in (0..len).step_by(CHUNK_LEN) {
+        let chunk = &mut buf[..CHUNK_LEN.min(len - offset)];
+        if let Err(e) = updater.read(offset, chunk).await {
+            warn!(
+                "Failed to read back firmware at {}: {:?}",
+                offset,
+                debug2format!(e)
+            );
+            return respond(conn, 500, "Internal Error", "Failed to verify image\n").await;
+        }
+        hasher.update(chunk);
+    }
+    if hasher.finalize()[..] != expected[..] {
+        warn!("Firmware checksum mismatch, discarding");
+        return respond(conn, 422, "Unprocessable Content", "Checksum mismatch\n").await;
+    }
+
+    if let Err(e) = updater.mark_updated().await {
+        warn!("Failed to mark firmware updated: {:?}", debug2format!(e));
+        return respond(conn, 500, "Internal Error", "Failed to stage image\n").await;
+    }
+
+    info!("Firmware image staged, rebooting");
+
+    respond(conn, 200, "OK", "Update staged, rebooting\n").await?;
+    conn.complete().await?;
+
+    Timer::after(REBOOT_DELAY).await;
+    updater.reboot().await;
+
+    Ok(())
+}
+
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.chunks(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(digest)
+}
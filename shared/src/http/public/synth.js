@@ -0,0 +1,79 @@
+// A tiny Web Audio stand-in for the parts of Tone.js the control panel used, so
+// the page doesn't need to reach a CDN. Notes are given as e.g. 'C5' and
+// durations as Tone-style note values ('8n', '16n') or seconds.
+
+(() => {
+    let context = null;
+
+    const NOTE_OFFSETS = { C: -9, D: -7, E: -5, F: -4, G: -2, A: 0, B: 2 };
+    // Tone's default tempo, which its note values are relative to
+    const BPM = 120;
+
+    function frequency(note) {
+        const [, name, accidental, octave] = /^([A-G])(#|b)?(-?\d)$/.exec(note);
+        const semitones = NOTE_OFFSETS[name] + (accidental === '#' ? 1 : accidental === 'b' ? -1 : 0)
+            + (Number(octave) - 4) * 12;
+        return 440 * Math.pow(2, semitones / 12);
+    }
+
+    function seconds(duration) {
+        const match = /^(\d+)n$/.exec(duration);
+        return match ? (60 / BPM) * (4 / Number(match[1])) : Number(duration);
+    }
+
+    // Attack, decay to the sustain level, hold for the duration, then release.
+    function envelope(gain, { attack, decay, sustain, release }, duration) {
+        const now = context.currentTime;
+        gain.gain.setValueAtTime(0, now);
+        gain.gain.linearRampToValueAtTime(1, now + attack);
+        gain.gain.linearRampToValueAtTime(sustain, now + attack + decay);
+        const end = now + Math.max(duration, attack + decay);
+        gain.gain.setValueAtTime(sustain, end);
+        gain.gain.linearRampToValueAtTime(0, end + release);
+        return end + release;
+    }
+
+    class Synth {
+        static start() {
+            context = context || new AudioContext();
+            return context.resume();
+        }
+
+        constructor({ oscillator, envelope }) {
+            this.type = oscillator.type;
+            this.envelope = envelope;
+        }
+
+        triggerAttackRelease(note, duration) {
+            if (!context) return;
+            const oscillator = new OscillatorNode(context, { type: this.type, frequency: frequency(note) });
+            const gain = new GainNode(context, { gain: 0 });
+            oscillator.connect(gain).connect(context.destination);
+            oscillator.start();
+            oscillator.stop(envelope(gain, this.envelope, seconds(duration)));
+        }
+    }
+
+    class NoiseSynth {
+        constructor({ envelope }) {
+            this.envelope = envelope;
+        }
+
+        triggerAttackRelease(duration) {
+            if (!context) return;
+            const buffer = new AudioBuffer({ length: context.sampleRate, sampleRate: context.sampleRate });
+            const samples = buffer.getChannelData(0);
+            for (let i = 0; i < samples.length; i++) {
+                samples[i] = Math.random() * 2 - 1;
+            }
+            const noise = new AudioBufferSourceNode(context, { buffer, loop: true });
+            const gain = new GainNode(context, { gain: 0 });
+            noise.connect(gain).connect(context.destination);
+            noise.start();
+            noise.stop(envelope(gain, this.envelope, seconds(duration)));
+        }
+    }
+
+    window.Synth = Synth;
+    window.NoiseSynth = NoiseSynth;
+})();
@@ -0,0 +1,58 @@
+//! The self-test over REST. `GET /api/self-test` reports how the last test went,
+//! or how the running one is going, and `POST /api/self-test` starts one.
+
+use edge_http::io::server::Connection;
+use edge_http::Method;
+use embedded_io_async::{Read, Write};
+
+use crate::http::auth::{Auth, Role};
+use crate::http::respond;
+use crate::http::ws::Error;
+use crate::self_test::SelfTest;
+
+/// Room for every subsystem and step, with the longest names.
+const MAX_REPORT_LEN: usize = 2048;
+
+pub(crate) async fn handle_self_test<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    self_test: &SelfTest<'_>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = conn.headers()?;
+    let role = auth.authenticate(&headers.headers);
+    let (status, message) = match headers.method {
+        Method::Get if role.is_none() => {
+            return respond(conn, 401, "Unauthorized", "Login required\n").await;
+        }
+        Method::Get => (200, "OK"),
+        Method::Post if role != Some(Role::Operator) => {
+            return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+        }
+        Method::Post => {
+            if !self_test.start() {
+                return respond(conn, 409, "Conflict", "Self-test already running\n").await;
+            }
+            (202, "Accepted")
+        }
+        _ => {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut buf = [0_u8; MAX_REPORT_LEN];
+    let size = serde_json_core::to_slice(&self_test.report(), &mut buf)?;
+    conn.initiate_response(
+        status,
+        Some(message),
+        &[("Content-Type", "application/json")],
+    )
+    .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
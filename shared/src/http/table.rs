@@ -0,0 +1,53 @@
+//! The table over REST. `GET /api/table` reports its speed and recent
+//! revolutions, and `POST /api/table` clears a stall so the motor can run again.
+
+use edge_http::io::server::Connection;
+use edge_http::Method;
+use embedded_io_async::{Read, Write};
+
+use crate::http::auth::{Auth, Role};
+use crate::http::respond;
+use crate::http::ws::Error;
+use crate::tachometer::{Tachometer, HISTORY};
+
+/// Room for a full history, with the longest timestamps and speeds.
+const MAX_REPORT_LEN: usize = 64 + HISTORY * 48;
+
+pub(crate) async fn handle_table<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    tachometer: &Tachometer<'_>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = conn.headers()?;
+    let role = auth.authenticate(&headers.headers);
+    match headers.method {
+        Method::Get if role.is_none() => {
+            return respond(conn, 401, "Unauthorized", "Login required\n").await;
+        }
+        Method::Get => {}
+        Method::Post if role != Some(Role::Operator) => {
+            return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+        }
+        Method::Post => {
+            if !tachometer.clear_stall() {
+                return respond(conn, 409, "Conflict", "The table hasn't stalled\n").await;
+            }
+        }
+        _ => {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let mut buf = [0_u8; MAX_REPORT_LEN];
+    let size = serde_json_core::to_slice(&tachometer.report(), &mut buf)?;
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", "application/json")])
+        .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
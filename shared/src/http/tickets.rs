@@ -0,0 +1,70 @@
+//! The ticket dispenser over REST. `GET /api/tickets` reports the tickets owed and
+//! paid, and `POST /api/tickets` with e.g. `{"pay":10}` pays out more and resumes
+//! the payout after a refill or jam, which `{"pay":0}` does alone.
+
+use edge_http::io::server::Connection;
+use edge_http::Method;
+use embedded_io_async::{Read, Write};
+use serde::Deserialize;
+
+use crate::http::auth::{Auth, Role};
+use crate::http::respond;
+use crate::http::ws::Error;
+use crate::tickets::Tickets;
+
+const MAX_REPORT_LEN: usize = 64;
+const MAX_REQUEST_LEN: usize = 32;
+
+#[derive(Deserialize)]
+struct Request {
+    pay: u32,
+}
+
+pub(crate) async fn handle_tickets<T, const N: usize>(
+    conn: &mut Connection<'_, T, N>,
+    tickets: &Tickets<'_>,
+    auth: &Auth<'_>,
+) -> Result<(), Error<T::Error>>
+where
+    T: Read + Write,
+{
+    let headers = conn.headers()?;
+    let role = auth.authenticate(&headers.headers);
+    match headers.method {
+        Method::Get if role.is_none() => {
+            return respond(conn, 401, "Unauthorized", "Login required\n").await;
+        }
+        Method::Get => {}
+        Method::Post if role != Some(Role::Operator) => {
+            return respond(conn, 401, "Unauthorized", "Operator login required\n").await;
+        }
+        Method::Post => {
+            let mut buf = [0_u8; MAX_REQUEST_LEN];
+            let mut len = 0;
+            while len < buf.len() {
+                let read = conn.read(&mut buf[len..]).await?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            let Ok((request, _)) = serde_json_core::from_slice::<Request>(&buf[..len]) else {
+                return respond(conn, 400, "Bad Request", "Expected {\"pay\":...}\n").await;
+            };
+            tickets.pay(request.pay);
+        }
+        _ => {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let mut buf = [0_u8; MAX_REPORT_LEN];
+    let size = serde_json_core::to_slice(&tickets.report(), &mut buf)?;
+    conn.initiate_response(200, Some("OK"), &[("Content-Type", "application/json")])
+        .await?;
+    conn.write_all(&buf[..size]).await?;
+
+    Ok(())
+}
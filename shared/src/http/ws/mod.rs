@@ -0,0 +1,653 @@
+use edge_http::io::server::{Connection, Handler};
+use edge_http::ws::{upgrade_response_headers, MAX_BASE64_KEY_RESPONSE_LEN};
+use edge_http::Method;
+use edge_ws::{FrameHeader, FrameType};
+use embassy_futures::select::{select4, Either4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker};
+use embedded_io_async::{Read, Write};
+use serde::{Deserialize, Serialize};
+
+use crate::arbiter::{Arbiter, Owners};
+use crate::ball_flow::BallFlow;
+use crate::bookkeeping::Bookkeeping;
+use crate::clock::{Timestamp, WallClock};
+use crate::credits::Credits;
+use crate::events::EventLog;
+use crate::forces::{Forces, InputForces};
+use crate::http::assets;
+use crate::http::auth::{self, Auth, Role};
+use crate::http::balls;
+use crate::http::bookkeeping;
+use crate::http::credits;
+use crate::http::diagnostics::{self, Client, Clients};
+use crate::http::events;
+use crate::http::forces;
+use crate::http::metrics;
+use crate::http::ota::{self, Updater};
+use crate::http::self_test;
+use crate::http::table;
+use crate::http::tickets;
+use crate::metrics::Metrics;
+use crate::pac_man_ball::{Inputs, Outputs};
+use crate::self_test::SelfTest;
+use crate::tachometer::Tachometer;
+use crate::tickets::Tickets;
+
+pub mod outputs;
+
+use outputs::{FieldName, ProtocolError};
+
+/// Large enough for any message in either protocol, with room to spare. Fragmented
+/// messages are reassembled up to this length.
+const MAX_MESSAGE_LEN: usize = 1024;
+/// RFC 6455 caps control frame payloads at 125 bytes.
+const MAX_CONTROL_LEN: usize = 125;
+/// How often to ping the client. A client that hasn't ponged by the next ping is dropped.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Status codes sent in Close frames, from RFC 6455 section 7.4.1.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u16)]
+pub enum CloseCode {
+    Normal = 1000,
+    GoingAway = 1001,
+    ProtocolError = 1002,
+    UnsupportedData = 1003,
+    MessageTooBig = 1009,
+}
+
+/// The encodings a client can ask for with `Sec-WebSocket-Protocol`. Both carry
+/// the same `ServerMessage`s and `Outputs`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// JSON in text frames, the default for browsers.
+    Json,
+    /// postcard in binary frames, a fraction of the size for constrained clients.
+    Postcard,
+}
+
+impl Protocol {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Postcard => "postcard",
+        }
+    }
+
+    /// The first of the client's comma separated `Sec-WebSocket-Protocol` offers we support.
+    pub fn negotiate(offered: &str) -> Option<Self> {
+        offered.split(',').map(str::trim).find_map(|name| {
+            [Self::Json, Self::Postcard]
+                .into_iter()
+                .find(|p| p.name() == name)
+        })
+    }
+
+    pub const fn frame_type(self) -> FrameType {
+        match self {
+            Self::Json => FrameType::Text(false),
+            Self::Postcard => FrameType::Binary(false),
+        }
+    }
+
+    /// Whether a data frame, fragmented or not, is of the type this protocol uses.
+    fn accepts(self, frame_type: FrameType) -> bool {
+        matches!(
+            (self, frame_type),
+            (Self::Json, FrameType::Text(_)) | (Self::Postcard, FrameType::Binary(_))
+        )
+    }
+
+    pub fn encode<'b, T: Serialize, E>(
+        self,
+        value: &T,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], Error<E>> {
+        match self {
+            Self::Json => {
+                let size = serde_json_core::to_slice(value, buf)?;
+                Ok(&buf[..size])
+            }
+            Self::Postcard => Ok(postcard::to_slice(value, buf).map_err(Error::Postcard)?),
+        }
+    }
+
+    pub fn decode<'de, T: Deserialize<'de>, E>(self, payload: &'de [u8]) -> Result<T, Error<E>> {
+        let (value, remaining) = match self {
+            Self::Json => {
+                let (value, length) = serde_json_core::from_slice(payload)?;
+                (value, payload.len() - length)
+            }
+            Self::Postcard => {
+                let (value, rest) = postcard::take_from_bytes(payload).map_err(Error::Postcard)?;
+                (value, rest.len())
+            }
+        };
+        if remaining != 0 {
+            return Err(Error::TrailingPayload);
+        }
+        Ok(value)
+    }
+}
+
+/// Messages pushed from the server to WS clients.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMessage {
+    Inputs {
+        /// Wall-clock time of the snapshot, if the clock has been synchronised.
+        timestamp: Option<Timestamp>,
+        /// As forced, so not necessarily what the sensors say.
+        inputs: Inputs,
+        /// The force on each input, if any.
+        forces: Forces,
+    },
+    /// Sent once on connect, so the client knows whether it may drive the outputs.
+    Session { role: Role },
+    /// A request was rejected. The session stays open.
+    Error {
+        code: ErrorCode,
+        message: heapless::String<64>,
+        /// The field at fault, where there is one.
+        field: Option<FieldName>,
+    },
+    /// Sent on connect, after `Session`, so the client can change one output
+    /// without resetting the rest, and again whenever the outputs change.
+    Outputs {
+        outputs: Outputs,
+        /// Which layer of the `Arbiter` drives each output, if any.
+        owners: Owners,
+    },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The session's role does not allow the request.
+    Forbidden,
+    /// The message could not be decoded at all.
+    Malformed,
+    /// The message names a field that doesn't exist.
+    UnknownField,
+    /// A field has a value of the wrong type.
+    InvalidValue,
+}
+
+pub struct WsHandler<'a, U> {
+    egress_signal: &'a Signal<CriticalSectionRawMutex, Inputs>,
+    clock: &'a WallClock,
+    updater: &'a Mutex<CriticalSectionRawMutex, U>,
+    auth: &'a Auth<'a>,
+    metrics: &'a Metrics,
+    arbiter: &'a Arbiter,
+    forces: &'a InputForces,
+    self_test: &'a SelfTest<'a>,
+    tachometer: &'a Tachometer<'a>,
+    ball_flow: &'a BallFlow<'a>,
+    credits: &'a Credits<'a>,
+    tickets: &'a Tickets<'a>,
+    bookkeeping: &'a Bookkeeping<'a>,
+    events: &'a EventLog<'a>,
+    clients: Clients,
+}
+
+impl<'a, U> WsHandler<'a, U> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        egress_signal: &'a Signal<CriticalSectionRawMutex, Inputs>,
+        clock: &'a WallClock,
+        updater: &'a Mutex<CriticalSectionRawMutex, U>,
+        auth: &'a Auth<'a>,
+        metrics: &'a Metrics,
+        arbiter: &'a Arbiter,
+        forces: &'a InputForces,
+        self_test: &'a SelfTest<'a>,
+        tachometer: &'a Tachometer<'a>,
+        ball_flow: &'a BallFlow<'a>,
+        credits: &'a Credits<'a>,
+        tickets: &'a Tickets<'a>,
+        bookkeeping: &'a Bookkeeping<'a>,
+        events: &'a EventLog<'a>,
+    ) -> Self {
+        Self {
+            egress_signal,
+            clock,
+            updater,
+            auth,
+            metrics,
+            arbiter,
+            forces,
+            self_test,
+            tachometer,
+            ball_flow,
+            credits,
+            tickets,
+            bookkeeping,
+            events,
+            clients: Clients::new(),
+        }
+    }
+
+    async fn session<S>(
+        &self,
+        socket: &mut S,
+        protocol: Protocol,
+        role: Role,
+    ) -> Result<(), Error<S::Error>>
+    where
+        S: Read + Write,
+    {
+        let mut message = [0_u8; MAX_MESSAGE_LEN];
+        let mut tx = [0_u8; MAX_MESSAGE_LEN];
+        let mut control = [0_u8; MAX_CONTROL_LEN];
+        // How much of a fragmented message has arrived so far, if one is in progress.
+        let mut reassembled: Option<usize> = None;
+        let mut awaiting_pong = false;
+        let mut ping_ticker = Ticker::every(PING_INTERVAL);
+        let client = self.clients.connect(role, protocol);
+
+        send(
+            socket,
+            &client,
+            &mut tx,
+            protocol,
+            &ServerMessage::Session { role },
+        )
+        .await?;
+        // There are never more sessions than watchers, but carry on without
+        // updates rather than failing if there were.
+        let mut arbitration = self.arbiter.subscribe();
+        if arbitration.is_none() {
+            warn!("Too many sessions to watch the outputs");
+        }
+        // Taken from the watch, so that it isn't sent again as a change.
+        let outputs = arbitration
+            .as_mut()
+            .and_then(|arbitration| arbitration.try_changed())
+            .unwrap_or_else(|| self.arbiter.arbitration());
+        send(
+            socket,
+            &client,
+            &mut tx,
+            protocol,
+            &ServerMessage::Outputs {
+                outputs: outputs.outputs,
+                owners: outputs.owners,
+            },
+        )
+        .await?;
+
+        loop {
+            let header = match select4(
+                FrameHeader::recv(&mut *socket),
+                self.egress_signal.wait(),
+                ping_ticker.next(),
+                async {
+                    match &mut arbitration {
+                        Some(arbitration) => arbitration.changed().await,
+                        None => core::future::pending().await,
+                    }
+                },
+            )
+            .await
+            {
+                Either4::First(header) => {
+                    let header = header.map_err(Error::Ws)?;
+                    client.record_frame_received();
+                    header
+                }
+                Either4::Second(inputs) => {
+                    let message = ServerMessage::Inputs {
+                        timestamp: self.clock.now(),
+                        inputs,
+                        forces: self.forces.forces(),
+                    };
+                    send(socket, &client, &mut tx, protocol, &message).await?;
+                    continue;
+                }
+                Either4::Third(()) => {
+                    if awaiting_pong {
+                        warn!("No pong since the last ping, dropping the connection");
+                        return Ok(());
+                    }
+                    awaiting_pong = true;
+                    send_frame(socket, &client, FrameType::Ping, &[]).await?;
+                    continue;
+                }
+                Either4::Fourth(outputs) => {
+                    let message = ServerMessage::Outputs {
+                        outputs: outputs.outputs,
+                        owners: outputs.owners,
+                    };
+                    send(socket, &client, &mut tx, protocol, &message).await?;
+                    continue;
+                }
+            };
+
+            if header.mask_key.is_none() {
+                warn!("Got unmasked {}, closing", header);
+                return close(socket, &client, CloseCode::ProtocolError).await;
+            }
+
+            match header.frame_type {
+                FrameType::Ping | FrameType::Pong | FrameType::Close => {
+                    if header.payload_len > MAX_CONTROL_LEN as u64 {
+                        warn!("Got oversized {}, closing", header);
+                        return close(socket, &client, CloseCode::ProtocolError).await;
+                    }
+                    let payload = header
+                        .recv_payload(&mut *socket, &mut control)
+                        .await
+                        .map_err(Error::Ws)?;
+                    match header.frame_type {
+                        FrameType::Ping => {
+                            send_frame(socket, &client, FrameType::Pong, payload).await?
+                        }
+                        FrameType::Pong => awaiting_pong = false,
+                        _ => {
+                            let code = payload
+                                .get(..2)
+                                .map(|code| u16::from_be_bytes([code[0], code[1]]));
+                            info!(
+                                "Got {}, client closed the connection with {:?}",
+                                header, code
+                            );
+                            // Echo the status code to complete the closing handshake.
+                            return send_frame(
+                                socket,
+                                &client,
+                                FrameType::Close,
+                                &payload[..payload.len().min(2)],
+                            )
+                            .await;
+                        }
+                    }
+                }
+                FrameType::Text(_) | FrameType::Binary(_) | FrameType::Continue(_) => {
+                    let offset = match (header.frame_type, reassembled) {
+                        (FrameType::Continue(_), Some(offset)) => offset,
+                        (FrameType::Continue(_), None) => {
+                            warn!("Got {} without a message to continue, closing", header);
+                            return close(socket, &client, CloseCode::ProtocolError).await;
+                        }
+                        (_, Some(_)) => {
+                            warn!(
+                                "Got {} before the previous message finished, closing",
+                                header
+                            );
+                            return close(socket, &client, CloseCode::ProtocolError).await;
+                        }
+                        (frame_type, None) if !protocol.accepts(frame_type) => {
+                            warn!("Got {}, but speaking {:?}, closing", header, protocol);
+                            return close(socket, &client, CloseCode::UnsupportedData).await;
+                        }
+                        (_, None) => 0,
+                    };
+
+                    if header.payload_len > (MAX_MESSAGE_LEN - offset) as u64 {
+                        warn!(
+                            "Got {}, message exceeds {} bytes, closing",
+                            header, MAX_MESSAGE_LEN
+                        );
+                        return close(socket, &client, CloseCode::MessageTooBig).await;
+                    }
+                    let len = offset
+                        + header
+                            .recv_payload(&mut *socket, &mut message[offset..])
+                            .await
+                            .map_err(Error::Ws)?
+                            .len();
+
+                    if !header.frame_type.is_final() {
+                        reassembled = Some(len);
+                        continue;
+                    }
+                    reassembled = None;
+
+                    self.receive_outputs(socket, &mut tx, protocol, &client, role, &message[..len])
+                        .await?;
+                }
+            }
+        }
+    }
+
+    async fn receive_outputs<S>(
+        &self,
+        socket: &mut S,
+        tx: &mut [u8],
+        protocol: Protocol,
+        client: &Client<'_>,
+        role: Role,
+        payload: &[u8],
+    ) -> Result<(), Error<S::Error>>
+    where
+        S: Write,
+    {
+        let error = match outputs::decode(protocol, payload) {
+            Ok((mask, outputs)) if role >= Role::Operator => {
+                info!("Got outputs {:?}", outputs);
+                self.arbiter.request(&mask, &outputs);
+                return Ok(());
+            }
+            Ok(_) => {
+                warn!("Ignoring outputs from {:?} session", role);
+                ProtocolError {
+                    code: ErrorCode::Forbidden,
+                    message: "Operator login required",
+                    field: None,
+                }
+            }
+            Err(error) => {
+                warn!("Rejected outputs: {:?}", error);
+                error
+            }
+        };
+
+        client.record_error(error.code);
+        let message = ServerMessage::Error {
+            code: error.code,
+            message: error.message.try_into().unwrap(),
+            field: error.field,
+        };
+        send(socket, client, tx, protocol, &message).await
+    }
+}
+
+impl<U: Updater> Handler for WsHandler<'_, U> {
+    type Error<E>
+        = Error<E>
+    where
+        E: core::fmt::Debug;
+
+    async fn handle<T, const N: usize>(
+        &self,
+        _task_id: impl core::fmt::Display + Clone,
+        conn: &mut Connection<'_, T, N>,
+    ) -> Result<(), Self::Error<T::Error>>
+    where
+        T: Read + Write,
+    {
+        let headers = conn.headers()?;
+
+        if headers.path == "/api/forces" {
+            forces::handle_forces(conn, self.forces, self.auth).await?;
+        } else if headers.path == "/api/self-test" {
+            self_test::handle_self_test(conn, self.self_test, self.auth).await?;
+        } else if headers.path == "/api/table" {
+            table::handle_table(conn, self.tachometer, self.auth).await?;
+        } else if headers.path == "/api/credits" {
+            credits::handle_credits(conn, self.credits, self.auth).await?;
+        } else if headers.path == "/api/credits/play" {
+            credits::handle_play(conn, self.credits, self.auth).await?;
+        } else if headers.path == "/api/tickets" {
+            tickets::handle_tickets(conn, self.tickets, self.auth).await?;
+        } else if headers.path == "/api/bookkeeping" {
+            bookkeeping::handle_bookkeeping(conn, self.bookkeeping, self.auth).await?;
+        } else if matches!(headers.path, "/update" | "/login" | "/logout") {
+            if headers.method != Method::Post {
+                conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                    .await?;
+            } else if headers.path == "/update" {
+                ota::handle_update(conn, self.updater, self.auth).await?;
+            } else if headers.path == "/login" {
+                auth::handle_login(conn, self.auth).await?;
+            } else {
+                auth::handle_logout(conn, self.auth).await?;
+            }
+        } else if headers.method != Method::Get {
+            conn.initiate_response(405, Some("Method Not Allowed"), &[])
+                .await?;
+        } else if matches!(
+            headers.path,
+            "/diagnostics" | "/metrics" | "/api/events" | "/api/balls" | "/api/bookkeeping.csv"
+        ) {
+            if self.auth.authenticate(&headers.headers).is_none() {
+                conn.initiate_response(401, Some("Unauthorized"), &[])
+                    .await?;
+            } else if headers.path == "/diagnostics" {
+                diagnostics::handle_diagnostics(conn, &self.clients).await?;
+            } else if headers.path == "/metrics" {
+                metrics::handle_metrics(conn, self.metrics, &self.clients).await?;
+            } else if headers.path == "/api/balls" {
+                balls::handle_balls(conn, self.ball_flow).await?;
+            } else if headers.path == "/api/bookkeeping.csv" {
+                bookkeeping::handle_bookkeeping_csv(conn, self.bookkeeping).await?;
+            } else {
+                events::handle_events(conn, self.events).await?;
+            }
+        } else if headers.path != "/" || !conn.is_ws_upgrade_request()? {
+            match assets::find(headers.path) {
+                Some(asset) => assets::handle_asset(conn, asset).await?,
+                None => conn.initiate_response(404, Some("Not Found"), &[]).await?,
+            }
+        } else {
+            let Some(role) = self.auth.authenticate(&headers.headers) else {
+                conn.initiate_response(401, Some("Unauthorized"), &[])
+                    .await?;
+                return Ok(());
+            };
+
+            // Clients that don't ask for a protocol get JSON, and aren't told so.
+            let offered = headers.headers.get("Sec-WebSocket-Protocol");
+            let protocol = offered.and_then(Protocol::negotiate);
+
+            let mut buf = [0_u8; MAX_BASE64_KEY_RESPONSE_LEN];
+            let upgrade = upgrade_response_headers(headers.headers.iter(), None, &mut buf)
+                .map_err(edge_http::io::Error::from)?;
+            let [content_length, connection, upgrade, accept] = upgrade;
+            let mut response =
+                heapless::Vec::<_, 5>::from_slice(&[content_length, connection, upgrade, accept])
+                    .unwrap();
+            if let Some(protocol) = protocol {
+                response
+                    .push(("Sec-WebSocket-Protocol", protocol.name()))
+                    .unwrap();
+            }
+            conn.initiate_response(101, None, &response).await?;
+
+            conn.complete().await?;
+
+            let protocol = protocol.unwrap_or(Protocol::Json);
+            info!("Connection upgraded to WS, speaking {:?}", protocol);
+
+            let mut socket = conn.unbind()?;
+
+            self.session(&mut socket, protocol, role).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn send<S>(
+    socket: &mut S,
+    client: &Client<'_>,
+    buf: &mut [u8],
+    protocol: Protocol,
+    message: &ServerMessage,
+) -> Result<(), Error<S::Error>>
+where
+    S: Write,
+{
+    let payload = protocol.encode(message, buf)?;
+    send_frame(socket, client, protocol.frame_type(), payload).await
+}
+
+async fn send_frame<S>(
+    socket: &mut S,
+    client: &Client<'_>,
+    frame_type: FrameType,
+    payload: &[u8],
+) -> Result<(), Error<S::Error>>
+where
+    S: Write,
+{
+    let header = FrameHeader {
+        frame_type,
+        payload_len: payload.len() as _,
+        mask_key: None,
+    };
+    header.send(&mut *socket).await.map_err(Error::Ws)?;
+    header
+        .send_payload(socket, payload)
+        .await
+        .map_err(Error::Ws)?;
+    client.record_frame_sent();
+    Ok(())
+}
+
+/// Start the closing handshake. The connection is dropped without waiting for the
+/// client's reply, which RFC 6455 allows of the server.
+async fn close<S>(
+    socket: &mut S,
+    client: &Client<'_>,
+    code: CloseCode,
+) -> Result<(), Error<S::Error>>
+where
+    S: Write,
+{
+    send_frame(
+        socket,
+        client,
+        FrameType::Close,
+        &(code as u16).to_be_bytes(),
+    )
+    .await
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Error<E> {
+    Connection(edge_http::io::Error<E>),
+    Ws(edge_ws::Error<E>),
+    JsonDe(serde_json_core::de::Error),
+    JsonSer(serde_json_core::ser::Error),
+    Postcard(postcard::Error),
+    TrailingPayload,
+}
+
+impl<E> From<edge_http::io::Error<E>> for Error<E> {
+    fn from(e: edge_http::io::Error<E>) -> Self {
+        Self::Connection(e)
+    }
+}
+
+impl<E> From<serde_json_core::de::Error> for Error<E> {
+    fn from(e: serde_json_core::de::Error) -> Self {
+        Self::JsonDe(e)
+    }
+}
+
+impl<E> From<serde_json_core::ser::Error> for Error<E> {
+    fn from(e: serde_json_core::ser::Error) -> Self {
+        Self::JsonSer(e)
+    }
+}
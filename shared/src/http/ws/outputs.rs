@@ -0,0 +1,118 @@
+//! Strict decoding of `Outputs` sent by clients, reporting what was wrong with a
+//! rejected message so the client can fix it.
+//!
+//! A JSON message names just the outputs to change, e.g. `{"table_motor":true}`.
+//! The derived `Deserialize` only says that decoding failed, so it is walked field
+//! by field here instead. postcard is positional, so a postcard message sets every
+//! output, and there are no field names to report.
+
+use core::cell::Cell;
+use core::fmt;
+
+use serde::de::{DeserializeSeed, Deserializer, MapAccess, Visitor};
+
+use super::{ErrorCode, Protocol};
+use crate::arbiter::{self, Mask};
+use crate::pac_man_ball::Outputs;
+
+pub type FieldName = heapless::String<32>;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: &'static str,
+    pub field: Option<FieldName>,
+}
+
+impl ProtocolError {
+    fn new(code: ErrorCode, message: &'static str, field: Option<&str>) -> Self {
+        Self {
+            code,
+            message,
+            // Unknown field names can be arbitrarily long, so keep what fits.
+            field: field.map(|field| {
+                let mut name = FieldName::new();
+                for c in field.chars() {
+                    if name.push(c).is_err() {
+                        break;
+                    }
+                }
+                name
+            }),
+        }
+    }
+
+    fn malformed() -> Self {
+        Self::new(ErrorCode::Malformed, "Could not decode outputs", None)
+    }
+}
+
+/// The outputs a client sent, and which of them it set.
+pub fn decode(protocol: Protocol, payload: &[u8]) -> Result<(Mask, Outputs), ProtocolError> {
+    match protocol {
+        Protocol::Json => {
+            let error = Cell::new(None);
+            let mut de = serde_json_core::de::Deserializer::new(payload, None);
+            let outputs = OutputsSeed { error: &error }
+                .deserialize(&mut de)
+                .map_err(|_| error.take().unwrap_or_else(ProtocolError::malformed))?;
+            de.end().map_err(|_| {
+                ProtocolError::new(ErrorCode::Malformed, "Unexpected data after outputs", None)
+            })?;
+            Ok(outputs)
+        }
+        Protocol::Postcard => protocol
+            .decode::<_, ()>(payload)
+            .map(|outputs| (arbiter::ALL, outputs))
+            .map_err(|_| ProtocolError::malformed()),
+    }
+}
+
+/// Deserializes `Outputs`, leaving the reason for any failure in `error`, as
+/// serde-json-core errors can't carry it.
+struct OutputsSeed<'a> {
+    error: &'a Cell<Option<ProtocolError>>,
+}
+
+impl<'a> OutputsSeed<'a> {
+    fn fail<E: serde::de::Error>(&self, code: ErrorCode, message: &'static str, field: &str) -> E {
+        self.error
+            .set(Some(ProtocolError::new(code, message, Some(field))));
+        E::custom(message)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for OutputsSeed<'_> {
+    type Value = (Mask, Outputs);
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for OutputsSeed<'_> {
+    type Value = (Mask, Outputs);
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object of output states")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut outputs = Outputs::default();
+        let mut seen = [false; Outputs::FIELDS.len()];
+
+        while let Some(key) = map.next_key::<&str>()? {
+            let Some(index) = Outputs::FIELDS.iter().position(|field| *field == key) else {
+                return Err(self.fail(ErrorCode::UnknownField, "No such output", key));
+            };
+            let Ok(value) = map.next_value::<bool>() else {
+                return Err(self.fail(ErrorCode::InvalidValue, "Expected true or false", key));
+            };
+            outputs.set(key, value);
+            seen[index] = true;
+        }
+
+        Ok((seen, outputs))
+    }
+}
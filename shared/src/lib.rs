@@ -3,5 +3,20 @@
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+pub mod arbiter;
+pub mod ball_flow;
+pub mod bookkeeping;
+pub mod clock;
+pub mod credits;
+pub mod events;
+pub mod forces;
+pub mod health;
 pub mod http;
+pub mod metrics;
+pub mod modbus;
+pub mod mqtt;
 pub mod pac_man_ball;
+pub mod self_test;
+pub mod storage;
+pub mod tachometer;
+pub mod tickets;
@@ -0,0 +1,153 @@
+//! Counters and gauges describing how the machine behaves in the field, served
+//! in Prometheus text format from `GET /metrics`.
+//!
+//! The I/O loop reports what it reads and writes, and each target reports
+//! whatever it can measure about itself.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::pac_man_ball::{Inputs, Outputs};
+
+/// I2C addresses tracked for errors; the board has three expanders.
+pub const MAX_EXPANDERS: usize = 8;
+
+const INPUTS: usize = Inputs::FIELDS.len();
+const OUTPUTS: usize = Outputs::FIELDS.len();
+
+/// A copy of the metrics at one instant.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// Rising edges seen on each input, in `Inputs::FIELDS` order.
+    pub activations: [u32; INPUTS],
+    /// Time each output has spent on, in `Outputs::FIELDS` order.
+    pub on_time: [Duration; OUTPUTS],
+    /// Failed transactions by I2C address.
+    pub i2c_errors: heapless::LinearMap<u8, u32, MAX_EXPANDERS>,
+    pub uptime: Duration,
+    pub rssi_dbm: Option<i16>,
+    pub stack_free_bytes: Option<u32>,
+}
+
+struct State {
+    inputs: Option<[bool; INPUTS]>,
+    activations: [u32; INPUTS],
+    outputs: [bool; OUTPUTS],
+    outputs_since: Instant,
+    on_time: [Duration; OUTPUTS],
+    i2c_errors: heapless::LinearMap<u8, u32, MAX_EXPANDERS>,
+    rssi_dbm: Option<i16>,
+    stack_free_bytes: Option<u32>,
+}
+
+pub struct Metrics {
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(State {
+                inputs: None,
+                activations: [0; INPUTS],
+                outputs: [false; OUTPUTS],
+                outputs_since: Instant::from_ticks(0),
+                on_time: [Duration::from_ticks(0); OUTPUTS],
+                i2c_errors: heapless::LinearMap::new(),
+                rssi_dbm: None,
+                stack_free_bytes: None,
+            })),
+        }
+    }
+
+    /// Count the inputs that have become active since the last call.
+    pub fn record_inputs(&self, inputs: &Inputs) {
+        let values = inputs.values();
+        self.lock(|state| {
+            if let Some(previous) = state.inputs {
+                for ((count, was), is) in state.activations.iter_mut().zip(previous).zip(values) {
+                    if is && !was {
+                        *count = count.wrapping_add(1);
+                    }
+                }
+            }
+            state.inputs = Some(values);
+        });
+    }
+
+    /// Note the outputs now applied, accumulating on-time for those that were on.
+    pub fn record_outputs(&self, outputs: &Outputs) {
+        let now = Instant::now();
+        self.lock(|state| {
+            state.accumulate_on_time(now);
+            state.outputs = outputs.values();
+        });
+    }
+
+    /// Start reporting errors for an expander, so it shows up before its first failure.
+    pub fn register_i2c_expander(&self, address: u8) {
+        self.lock(|state| {
+            if !state.i2c_errors.contains_key(&address) {
+                let _ = state.i2c_errors.insert(address, 0);
+            }
+        });
+    }
+
+    pub fn record_i2c_error(&self, address: u8) {
+        self.lock(|state| match state.i2c_errors.get_mut(&address) {
+            Some(count) => *count = count.wrapping_add(1),
+            None => {
+                if state.i2c_errors.insert(address, 1).is_err() {
+                    warn!("Too many I2C addresses to track errors for {}", address);
+                }
+            }
+        });
+    }
+
+    pub fn set_rssi_dbm(&self, rssi: i16) {
+        self.lock(|state| state.rssi_dbm = Some(rssi));
+    }
+
+    pub fn set_stack_free_bytes(&self, bytes: u32) {
+        self.lock(|state| state.stack_free_bytes = Some(bytes));
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let now = Instant::now();
+        self.lock(|state| {
+            state.accumulate_on_time(now);
+            Snapshot {
+                activations: state.activations,
+                on_time: state.on_time,
+                i2c_errors: state.i2c_errors.clone(),
+                uptime: now - Instant::from_ticks(0),
+                rssi_dbm: state.rssi_dbm,
+                stack_free_bytes: state.stack_free_bytes,
+            }
+        })
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn accumulate_on_time(&mut self, now: Instant) {
+        let elapsed = now - self.outputs_since;
+        for (on_time, on) in self.on_time.iter_mut().zip(self.outputs) {
+            if on {
+                *on_time += elapsed;
+            }
+        }
+        self.outputs_since = now;
+    }
+}
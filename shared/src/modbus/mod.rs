@@ -0,0 +1,665 @@
+//! Modbus TCP server, for integrating the cabinet into PLC-based control systems.
+//!
+//! Address map, all zero-based:
+//!
+//! | Table             | Address        | Contents                                    |
+//! |-------------------|----------------|---------------------------------------------|
+//! | Discrete inputs   | 0..=21         | `Inputs` fields, in `Inputs::FIELDS` order   |
+//! | Discrete inputs   | 100..=121      | Whether input n is forced                   |
+//! | Coils             | 0..=16         | `Outputs` fields, in `Outputs::FIELDS` order |
+//! | Holding registers | 0..=1          | Uptime in seconds                           |
+//! | Holding registers | 100 + 2n, +1   | Activations of input n                      |
+//! | Holding registers | 200 + 2n, +1   | Seconds output n has been on                |
+//!
+//! Counters are 32 bits across two registers, high word first, and are read only.
+//!
+//! Modbus has no authentication, so only the clients listed in
+//! `ModbusConfig::writers` may write coils; everyone else gets an illegal function
+//! exception, as a viewer on the WS would get a forbidden error. Writes are checked
+//! as strictly as the WS checks outputs and go the same way, through the arbiter,
+//! changing only the coils written.
+
+use core::net::{IpAddr, SocketAddr};
+
+use edge_nal::TcpAccept;
+use embassy_futures::select::select_array;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{Read, Write};
+
+use crate::arbiter::{self, Arbiter, Mask};
+use crate::forces::InputForces;
+use crate::metrics::{Metrics, Snapshot};
+use crate::pac_man_ball::{Inputs, IoState, Outputs};
+
+pub const PORT: u16 = 502;
+
+/// Clients that can be connected at once.
+const MAX_CONNECTIONS: usize = 2;
+/// Drop clients that go quiet, so one that vanished doesn't hold a slot forever.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MBAP_LEN: usize = 7;
+const MAX_PDU_LEN: usize = 253;
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+
+/// The most bits a read may ask for.
+const MAX_READ_BITS: u16 = 2000;
+/// The most bits a write may carry.
+const MAX_WRITE_BITS: u16 = 1968;
+/// The most registers a read may ask for.
+const MAX_READ_REGISTERS: u16 = 125;
+
+const COIL_ON: u16 = 0xFF00;
+const COIL_OFF: u16 = 0x0000;
+
+const FORCED_INPUTS: u16 = 100;
+
+const UPTIME_REGISTER: u16 = 0;
+const ACTIVATIONS_REGISTER: u16 = 100;
+const ON_TIME_REGISTER: u16 = 200;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    /// The inputs haven't been read yet.
+    ServerDeviceFailure = 0x04,
+}
+
+pub struct ModbusConfig<'a> {
+    /// IP addresses allowed to write coils, comma separated.
+    pub writers: &'a str,
+}
+
+impl ModbusConfig<'_> {
+    fn can_write(&self, peer: IpAddr) -> bool {
+        self.writers
+            .split(',')
+            .filter_map(|writer| writer.trim().parse::<IpAddr>().ok())
+            .any(|writer| writer == peer)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    /// The MBAP header isn't for Modbus, or the length is impossible.
+    Malformed,
+    Timeout,
+    Closed,
+}
+
+pub async fn run_modbus_server<F, Fut, A, E>(
+    mut acceptor_fn: F,
+    config: &ModbusConfig<'_>,
+    io_state: &IoState,
+    arbiter: &Arbiter,
+    forces: &InputForces,
+    metrics: &Metrics,
+) -> !
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<A, E>>,
+    A: TcpAccept,
+{
+    let server = Server {
+        config,
+        io_state,
+        arbiter,
+        forces,
+        metrics,
+    };
+
+    loop {
+        let acceptor = match acceptor_fn().await {
+            Ok(acceptor) => acceptor,
+            Err(_) => {
+                warn!("Failed to bind Modbus server, retrying");
+                Timer::after(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        info!("Modbus server running");
+
+        // Each slot accepts and serves one client at a time, and returns only if
+        // accepting fails.
+        let slots: [_; MAX_CONNECTIONS] = core::array::from_fn(|_| server.serve(&acceptor));
+        select_array(slots).await;
+        warn!("Modbus server error, restarting");
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+struct Server<'a> {
+    config: &'a ModbusConfig<'a>,
+    io_state: &'a IoState,
+    arbiter: &'a Arbiter,
+    forces: &'a InputForces,
+    metrics: &'a Metrics,
+}
+
+impl Server<'_> {
+    async fn serve<A: TcpAccept>(&self, acceptor: &A) {
+        while let Ok((peer, mut socket)) = acceptor.accept().await {
+            info!("Modbus client connected");
+            if self.connection(peer, &mut socket).await.is_err() {
+                info!("Modbus client disconnected");
+            }
+        }
+    }
+
+    async fn connection<S: Read + Write>(
+        &self,
+        peer: SocketAddr,
+        socket: &mut S,
+    ) -> Result<(), Error<S::Error>> {
+        let can_write = self.config.can_write(peer.ip());
+        let mut request = [0_u8; MBAP_LEN + MAX_PDU_LEN];
+        let mut response = [0_u8; MBAP_LEN + MAX_PDU_LEN];
+
+        loop {
+            let header = &mut request[..MBAP_LEN];
+            with_timeout(IDLE_TIMEOUT, read_exact(socket, header)).await??;
+            let protocol = u16::from_be_bytes([header[2], header[3]]);
+            // The length counts the unit id, which is in the header, and the PDU.
+            let length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+            if protocol != 0 || !(2..=MAX_PDU_LEN + 1).contains(&length) {
+                return Err(Error::Malformed);
+            }
+            let end = MBAP_LEN + length - 1;
+            read_exact(socket, &mut request[MBAP_LEN..end]).await?;
+
+            let pdu_len = self.respond(
+                can_write,
+                &request[MBAP_LEN..end],
+                &mut response[MBAP_LEN..],
+            );
+
+            // Echo the transaction and unit ids.
+            response[..MBAP_LEN].copy_from_slice(&request[..MBAP_LEN]);
+            response[4..6].copy_from_slice(&(pdu_len as u16 + 1).to_be_bytes());
+            socket
+                .write_all(&response[..MBAP_LEN + pdu_len])
+                .await
+                .map_err(Error::Io)?;
+            socket.flush().await.map_err(Error::Io)?;
+        }
+    }
+
+    /// Handle a request PDU, returning the length of the response PDU.
+    fn respond(&self, can_write: bool, request: &[u8], response: &mut [u8]) -> usize {
+        let function = request[0];
+        response[0] = function;
+        let result = match function {
+            READ_COILS => {
+                let outputs = self.io_state.outputs().unwrap_or_default();
+                read_bits(&request[1..], 0, &outputs.values(), &mut response[1..])
+            }
+            READ_DISCRETE_INPUTS => self.read_discrete_inputs(&request[1..], &mut response[1..]),
+            READ_HOLDING_REGISTERS => {
+                read_registers(&request[1..], &self.metrics.snapshot(), &mut response[1..])
+            }
+            WRITE_SINGLE_COIL | WRITE_MULTIPLE_COILS if !can_write => {
+                warn!("Refusing a Modbus coil write from a client not in the writers");
+                Err(Exception::IllegalFunction)
+            }
+            WRITE_SINGLE_COIL => self.write_single_coil(&request[1..], &mut response[1..]),
+            WRITE_MULTIPLE_COILS => self.write_multiple_coils(&request[1..], &mut response[1..]),
+            _ => Err(Exception::IllegalFunction),
+        };
+
+        match result {
+            Ok(len) => 1 + len,
+            Err(exception) => {
+                response[0] = function | 0x80;
+                response[1] = exception as u8;
+                2
+            }
+        }
+    }
+
+    fn read_discrete_inputs(
+        &self,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Exception> {
+        let [address, _] = words(request)?;
+        if address >= FORCED_INPUTS {
+            let forced = self.forces.forces().map(|force| force.is_some());
+            return read_bits(request, FORCED_INPUTS, &forced, response);
+        }
+        match self.io_state.inputs() {
+            Some(inputs) => read_bits(request, 0, &inputs.values(), response),
+            None => Err(Exception::ServerDeviceFailure),
+        }
+    }
+
+    fn write_single_coil(&self, request: &[u8], response: &mut [u8]) -> Result<usize, Exception> {
+        let [address, value] = words(request)?;
+        let value = match value {
+            COIL_ON => true,
+            COIL_OFF => false,
+            _ => return Err(Exception::IllegalDataValue),
+        };
+        let field = Outputs::FIELDS
+            .get(usize::from(address))
+            .ok_or(Exception::IllegalDataAddress)?;
+
+        let mut outputs = Outputs::default();
+        outputs.set(field, value);
+        self.arbiter.request(&arbiter::mask(&[field]), &outputs);
+
+        response[..4].copy_from_slice(&request[..4]);
+        Ok(4)
+    }
+
+    fn write_multiple_coils(
+        &self,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Exception> {
+        let [address, quantity] = words(request)?;
+        let byte_count = *request.get(4).ok_or(Exception::IllegalDataValue)?;
+        let values = &request[5..];
+        if !(1..=MAX_WRITE_BITS).contains(&quantity)
+            || usize::from(byte_count) != usize::from(quantity).div_ceil(8)
+            || values.len() != usize::from(byte_count)
+        {
+            return Err(Exception::IllegalDataValue);
+        }
+        let fields = range(address, quantity, Outputs::FIELDS.len())?;
+
+        let mut mask: Mask = [false; Outputs::FIELDS.len()];
+        mask[fields.clone()].fill(true);
+        let mut outputs = Outputs::default();
+        for (i, field) in Outputs::FIELDS[fields].iter().enumerate() {
+            outputs.set(field, values[i / 8] & (1 << (i % 8)) != 0);
+        }
+        self.arbiter.request(&mask, &outputs);
+
+        response[..4].copy_from_slice(&request[..4]);
+        Ok(4)
+    }
+}
+
+/// Read a run of bits from the block starting at `start`, packed eight to a byte,
+/// lowest address in the lowest bit.
+fn read_bits(
+    request: &[u8],
+    start: u16,
+    bits: &[bool],
+    response: &mut [u8],
+) -> Result<usize, Exception> {
+    let [address, quantity] = words(request)?;
+    if !(1..=MAX_READ_BITS).contains(&quantity) {
+        return Err(Exception::IllegalDataValue);
+    }
+    let address = address
+        .checked_sub(start)
+        .ok_or(Exception::IllegalDataAddress)?;
+    let bits = &bits[range(address, quantity, bits.len())?];
+
+    let byte_count = bits.len().div_ceil(8);
+    response[0] = byte_count as u8;
+    response[1..=byte_count].fill(0);
+    for (i, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        response[1 + i / 8] |= 1 << (i % 8);
+    }
+    Ok(1 + byte_count)
+}
+
+fn read_registers(
+    request: &[u8],
+    snapshot: &Snapshot,
+    response: &mut [u8],
+) -> Result<usize, Exception> {
+    let [address, quantity] = words(request)?;
+    if !(1..=MAX_READ_REGISTERS).contains(&quantity) {
+        return Err(Exception::IllegalDataValue);
+    }
+
+    let byte_count = usize::from(quantity) * 2;
+    response[0] = byte_count as u8;
+    for i in 0..quantity {
+        let register = address
+            .checked_add(i)
+            .and_then(|register| holding_register(snapshot, register))
+            .ok_or(Exception::IllegalDataAddress)?;
+        let offset = 1 + usize::from(i) * 2;
+        response[offset..offset + 2].copy_from_slice(&register.to_be_bytes());
+    }
+    Ok(1 + byte_count)
+}
+
+fn holding_register(snapshot: &Snapshot, register: u16) -> Option<u16> {
+    /// The given half of a 32 bit counter in the block starting at `start`.
+    fn counter(register: u16, start: u16, values: impl Iterator<Item = u32>) -> Option<u16> {
+        let offset = register.checked_sub(start)?;
+        let value = values.into_iter().nth(usize::from(offset / 2))?;
+        Some(if offset % 2 == 0 {
+            (value >> 16) as u16
+        } else {
+            value as u16
+        })
+    }
+
+    let uptime = snapshot.uptime.as_secs() as u32;
+    let activations = snapshot.activations.iter().copied();
+    let on_time = snapshot.on_time.iter().map(|on| on.as_secs() as u32);
+    match register {
+        UPTIME_REGISTER..ACTIVATIONS_REGISTER => {
+            counter(register, UPTIME_REGISTER, core::iter::once(uptime))
+        }
+        ACTIVATIONS_REGISTER..ON_TIME_REGISTER => {
+            counter(register, ACTIVATIONS_REGISTER, activations)
+        }
+        _ => counter(register, ON_TIME_REGISTER, on_time),
+    }
+}
+
+/// The fields from `address` for `quantity`, if they all exist.
+fn range(address: u16, quantity: u16, len: usize) -> Result<core::ops::Range<usize>, Exception> {
+    let start = usize::from(address);
+    let end = start + usize::from(quantity);
+    if end > len {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(start..end)
+}
+
+/// The two big-endian words that start most requests.
+fn words(request: &[u8]) -> Result<[u16; 2], Exception> {
+    match request {
+        [a, b, c, d, ..] => Ok([u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d])]),
+        _ => Err(Exception::IllegalDataValue),
+    }
+}
+
+async fn read_exact<R: Read>(socket: &mut R, buf: &mut [u8]) -> Result<(), Error<R::Error>> {
+    socket.read_exact(buf).await.map_err(|e| match e {
+        embedded_io_async::ReadExactError::UnexpectedEof => Error::Closed,
+        embedded_io_async::ReadExactError::Other(e) => Error::Io(e),
+    })
+}
+
+impl<E> From<embassy_time::TimeoutError> for Error<E> {
+    fn from(_: embassy_time::TimeoutError) -> Self {
+        Self::Timeout
+    }
+}
+
+// Keep the map in the docs honest.
+const _: () = core::assert!(Inputs::FIELDS.len() == 22 && Outputs::FIELDS.len() == 17);
+const _: () = core::assert!(Inputs::FIELDS.len() as u16 <= FORCED_INPUTS);
+const _: () =
+    core::assert!(ACTIVATIONS_REGISTER + 2 * Inputs::FIELDS.len() as u16 <= ON_TIME_REGISTER);
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::arbiter::Arbitration;
+    use crate::forces::Force;
+
+    const WRITER: &str = "10.0.0.2";
+
+    struct Fixture {
+        config: ModbusConfig<'static>,
+        io_state: IoState,
+        arbiter: Arbiter,
+        forces: InputForces,
+        metrics: Metrics,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            Self {
+                config: ModbusConfig { writers: WRITER },
+                io_state: IoState::new(),
+                arbiter: Arbiter::new(),
+                forces: InputForces::new(),
+                metrics: Metrics::new(),
+            }
+        }
+
+        fn server(&self) -> Server<'_> {
+            Server {
+                config: &self.config,
+                io_state: &self.io_state,
+                arbiter: &self.arbiter,
+                forces: &self.forces,
+                metrics: &self.metrics,
+            }
+        }
+
+        /// The response PDU to `request`.
+        fn respond(&self, can_write: bool, request: &[u8]) -> Vec<u8> {
+            let mut response = [0_u8; MAX_PDU_LEN];
+            let len = self.server().respond(can_write, request, &mut response);
+            response[..len].to_vec()
+        }
+    }
+
+    /// Plays `rx` to the server and collects what it sends back.
+    struct Socket<'a> {
+        rx: &'a [u8],
+        tx: Vec<u8>,
+    }
+
+    impl embedded_io_async::ErrorType for Socket<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for Socket<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = buf.len().min(self.rx.len());
+            buf[..len].copy_from_slice(&self.rx[..len]);
+            self.rx = &self.rx[len..];
+            Ok(len)
+        }
+    }
+
+    impl Write for Socket<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    /// Run a connection from `peer` over `rx`, returning how it ended and the
+    /// responses sent.
+    fn converse(
+        fixture: &Fixture,
+        peer: &str,
+        rx: &[u8],
+    ) -> (Error<core::convert::Infallible>, Vec<u8>) {
+        let mut socket = Socket { rx, tx: Vec::new() };
+        let peer = SocketAddr::new(peer.parse().unwrap(), 50_000);
+        let ended = block_on(fixture.server().connection(peer, &mut socket));
+        (ended.unwrap_err(), socket.tx)
+    }
+
+    /// A request with transaction id `transaction`, for unit 1.
+    fn frame(transaction: u16, pdu: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&transaction.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        frame.push(1);
+        frame.extend_from_slice(pdu);
+        frame
+    }
+
+    fn inputs(active: &[&str]) -> Inputs {
+        let mut inputs = Inputs::default();
+        for field in active {
+            inputs.set(field, true);
+        }
+        inputs
+    }
+
+    #[test]
+    fn answers_each_request_in_a_stream() {
+        let fixture = Fixture::new();
+        fixture
+            .io_state
+            .set_inputs(&inputs(&["checker_0_sensor", "tilt_switch"]));
+        let mut rx = frame(7, &[READ_DISCRETE_INPUTS, 0, 0, 0, 8]);
+        rx.extend(frame(8, &[READ_COILS, 0, 16, 0, 1]));
+
+        let (ended, tx) = converse(&fixture, WRITER, &rx);
+        assert!(matches!(ended, Error::Closed));
+        #[rustfmt::skip]
+        let expected = [
+            0, 7, 0, 0, 0, 4, 1, READ_DISCRETE_INPUTS, 1, 0b1000_0001,
+            0, 8, 0, 0, 0, 4, 1, READ_COILS, 1, 0,
+        ];
+        assert_eq!(tx, expected);
+    }
+
+    #[test]
+    fn drops_a_connection_that_isnt_modbus() {
+        let fixture = Fixture::new();
+        let mut rx = frame(1, &[READ_COILS, 0, 0, 0, 1]);
+        rx[2] = 1;
+        let (ended, tx) = converse(&fixture, WRITER, &rx);
+        assert!(matches!(ended, Error::Malformed));
+        assert!(tx.is_empty());
+    }
+
+    #[test]
+    fn drops_a_connection_with_an_impossible_length() {
+        let fixture = Fixture::new();
+        for length in [0, 1, MAX_PDU_LEN as u16 + 2] {
+            let mut rx = frame(1, &[READ_COILS, 0, 0, 0, 1]);
+            rx[4..6].copy_from_slice(&length.to_be_bytes());
+            let (ended, _) = converse(&fixture, WRITER, &rx);
+            assert!(matches!(ended, Error::Malformed), "length {length}");
+        }
+    }
+
+    #[test]
+    fn a_request_cut_short_closes_the_connection() {
+        let fixture = Fixture::new();
+        let rx = frame(1, &[READ_COILS, 0, 0, 0, 1]);
+        let (ended, tx) = converse(&fixture, WRITER, &rx[..rx.len() - 1]);
+        assert!(matches!(ended, Error::Closed));
+        assert!(tx.is_empty());
+    }
+
+    #[test]
+    fn unknown_functions_are_illegal() {
+        let fixture = Fixture::new();
+        assert_eq!(fixture.respond(true, &[0x2B, 0, 0]), [0xAB, 0x01]);
+    }
+
+    #[test]
+    fn reads_are_checked() {
+        let fixture = Fixture::new();
+        assert_eq!(
+            fixture.respond(false, &[READ_DISCRETE_INPUTS, 0, 0, 0, 1]),
+            [0x82, Exception::ServerDeviceFailure as u8]
+        );
+        fixture.io_state.set_inputs(&Inputs::default());
+        assert_eq!(
+            fixture.respond(false, &[READ_DISCRETE_INPUTS, 0, 21, 0, 2]),
+            [0x82, Exception::IllegalDataAddress as u8]
+        );
+        assert_eq!(
+            fixture.respond(false, &[READ_COILS, 0, 0, 0, 0]),
+            [0x81, Exception::IllegalDataValue as u8]
+        );
+        assert_eq!(
+            fixture.respond(false, &[READ_COILS, 0, 0]),
+            [0x81, Exception::IllegalDataValue as u8]
+        );
+    }
+
+    #[test]
+    fn forced_inputs_are_flagged_from_100() {
+        let fixture = Fixture::new();
+        fixture.forces.set("tilt_switch", Some(Force::On), false);
+        assert_eq!(
+            fixture.respond(false, &[READ_DISCRETE_INPUTS, 0, 100, 0, 22]),
+            [READ_DISCRETE_INPUTS, 3, 0b1000_0000, 0, 0]
+        );
+        assert_eq!(
+            fixture.respond(false, &[READ_DISCRETE_INPUTS, 0, 110, 0, 13]),
+            [0x82, Exception::IllegalDataAddress as u8]
+        );
+    }
+
+    #[test]
+    fn writes_need_a_writer() {
+        let fixture = Fixture::new();
+        assert_eq!(
+            fixture.respond(false, &[WRITE_SINGLE_COIL, 0, 16, 0xFF, 0x00]),
+            [0x85, Exception::IllegalFunction as u8]
+        );
+        assert_eq!(fixture.arbiter.arbitration(), Arbitration::default());
+    }
+
+    #[test]
+    fn a_single_coil_write_changes_only_that_coil() {
+        let fixture = Fixture::new();
+        let request = [WRITE_SINGLE_COIL, 0, 16, 0xFF, 0x00];
+        assert_eq!(fixture.respond(true, &request), request);
+        let arbitration = fixture.arbiter.arbitration();
+        assert!(arbitration.outputs.ray_lamp);
+        assert_eq!(
+            arbitration.owners.iter().flatten().count(),
+            1,
+            "{:?}",
+            arbitration.owners
+        );
+
+        assert_eq!(
+            fixture.respond(true, &[WRITE_SINGLE_COIL, 0, 16, 0x12, 0x34]),
+            [0x85, Exception::IllegalDataValue as u8]
+        );
+        assert_eq!(
+            fixture.respond(true, &[WRITE_SINGLE_COIL, 0, 17, 0xFF, 0x00]),
+            [0x85, Exception::IllegalDataAddress as u8]
+        );
+    }
+
+    #[test]
+    fn a_multiple_coil_write_is_checked_against_its_byte_count() {
+        let fixture = Fixture::new();
+        // table_motor and left_hopper on, from coil 7.
+        assert_eq!(
+            fixture.respond(true, &[WRITE_MULTIPLE_COILS, 0, 7, 0, 2, 1, 0b11]),
+            [WRITE_MULTIPLE_COILS, 0, 7, 0, 2]
+        );
+        let outputs = fixture.arbiter.arbitration().outputs;
+        assert!(outputs.table_motor && outputs.left_hopper && !outputs.right_hopper);
+
+        for request in [
+            [WRITE_MULTIPLE_COILS, 0, 7, 0, 2, 2, 0b11].as_slice(),
+            &[WRITE_MULTIPLE_COILS, 0, 7, 0, 9, 1, 0xFF],
+            &[WRITE_MULTIPLE_COILS, 0, 7, 0, 2, 1],
+            &[WRITE_MULTIPLE_COILS, 0, 7, 0, 0, 0],
+        ] {
+            assert_eq!(
+                fixture.respond(true, request),
+                [0x8F, Exception::IllegalDataValue as u8],
+                "{request:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Home Assistant MQTT discovery, so that every input appears as a `binary_sensor`
+//! and every output as a `switch`, grouped under one device.
+//!
+//! See <https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery>.
+
+use core::fmt::{self, Write as _};
+use core::net::IpAddr;
+
+use serde::{Serialize, Serializer};
+
+use super::{format_topic as format, packet, Topic, TOPIC_PREFIX};
+
+/// Shown as the device model, and before the id in the device name.
+const MODEL: &str = "Pac-Man Ball";
+
+pub struct Discovery<'a> {
+    /// The topic prefix Home Assistant listens on, `homeassistant` unless changed there.
+    pub prefix: &'a str,
+    pub sw_version: &'a str,
+    /// Where to find the web UI, linked from the device page.
+    pub ip: Option<IpAddr>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Component {
+    /// An input, read only.
+    BinarySensor,
+    /// An output, which Home Assistant can also drive.
+    Switch,
+}
+
+impl Component {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::BinarySensor => "binary_sensor",
+            Self::Switch => "switch",
+        }
+    }
+
+    /// The bridge's topic segment for fields of this component.
+    const fn kind(self) -> &'static str {
+        match self {
+            Self::BinarySensor => "inputs",
+            Self::Switch => "outputs",
+        }
+    }
+}
+
+/// The topic and payload announcing one field.
+pub(crate) struct Announcement {
+    pub topic: Topic,
+    pub size: usize,
+}
+
+#[derive(Serialize)]
+struct Config<'a> {
+    name: Label<'a>,
+    unique_id: &'a str,
+    state_topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<&'a str>,
+    availability_topic: &'a str,
+    device: Device<'a>,
+}
+
+#[derive(Serialize)]
+struct Device<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    model: &'a str,
+    sw_version: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    configuration_url: Option<&'a str>,
+}
+
+/// Write the discovery config for `field` into `buf`.
+pub(crate) fn announce(
+    buf: &mut [u8],
+    discovery: &Discovery,
+    id: &str,
+    component: Component,
+    field: &str,
+) -> Result<Announcement, packet::Error> {
+    let kind = component.kind();
+    let topic = format(format_args!(
+        "{}/{}/{}_{}/{}/config",
+        discovery.prefix,
+        component.name(),
+        TOPIC_PREFIX,
+        id,
+        field
+    ))?;
+    let device_id = format(format_args!("{}_{}", TOPIC_PREFIX, id))?;
+    let device_name = format(format_args!("{} {}", MODEL, id))?;
+    let unique_id = format(format_args!("{}_{}_{}", TOPIC_PREFIX, id, field))?;
+    let state_topic = format(format_args!("{}/{}/{}/{}", TOPIC_PREFIX, id, kind, field))?;
+    let command_topic = format(format_args!("{}/set", state_topic))?;
+    let availability_topic = format(format_args!("{}/{}/availability", TOPIC_PREFIX, id))?;
+    let configuration_url = discovery
+        .ip
+        .map(|ip| format(format_args!("http://{}/", ip)))
+        .transpose()?;
+
+    let config = Config {
+        name: Label(field),
+        unique_id: &unique_id,
+        state_topic: &state_topic,
+        command_topic: (component == Component::Switch).then_some(&command_topic),
+        availability_topic: &availability_topic,
+        device: Device {
+            identifiers: [&device_id],
+            name: &device_name,
+            model: MODEL,
+            sw_version: discovery.sw_version,
+            configuration_url: configuration_url.as_deref(),
+        },
+    };
+    let size = serde_json_core::to_slice(&config, buf).map_err(|_| packet::Error::TooLong)?;
+    Ok(Announcement { topic, size })
+}
+
+/// A field name as a human would write it, e.g. `left_in_sensor_1` as "Left in sensor 1".
+struct Label<'a>(&'a str);
+
+impl fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, c) in self.0.chars().enumerate() {
+            match c {
+                '_' => f.write_char(' ')?,
+                c if i == 0 => f.write_char(c.to_ascii_uppercase())?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Label<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
@@ -0,0 +1,506 @@
+//! Bridges the machine I/O to an MQTT broker, for venue monitoring.
+//!
+//! Topics are all under `pacman/<id>/`:
+//! - `availability`: `online` once connected, and `offline` as the last will
+//! - `inputs/<field>` and `outputs/<field>`: `ON` or `OFF`, retained
+//! - `forces/<field>`: how an input is forced, `on`, `off`, `ignore` or `none`,
+//!   retained
+//! - `events`: an `Event` as JSON for each input change
+//! - `outputs/<field>/set`: publish `ON` or `OFF` here to drive an output
+//!
+//! Home Assistant discovery configs for all of them are published on connecting,
+//! if enabled.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use edge_nal::TcpSplit;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Ticker, Timer};
+use embedded_io_async::{Read, Write};
+use serde::Serialize;
+
+use crate::arbiter::{self, Arbiter};
+use crate::clock::{Timestamp, WallClock};
+use crate::forces::{Forces, InputForces};
+use crate::pac_man_ball::{Inputs, Outputs};
+
+pub mod discovery;
+pub mod packet;
+
+use discovery::{Component, Discovery};
+use packet::Packet;
+
+pub const TOPIC_PREFIX: &str = "pacman";
+
+/// Announced to the broker, which drops us if it hears nothing for half as long again.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Without even a ping response for this long, the broker has gone.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(60);
+const CONNACK_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const TOPIC_LEN: usize = 96;
+/// Incoming publishes are only ever output commands, so this is plenty.
+const RX_LEN: usize = 256;
+/// The largest payload is a discovery config.
+const PAYLOAD_LEN: usize = 640;
+const TX_LEN: usize = PAYLOAD_LEN + 2 * TOPIC_LEN;
+
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+const ON: &[u8] = b"ON";
+const OFF: &[u8] = b"OFF";
+const NOT_FORCED: &str = "none";
+
+type Topic = heapless::String<TOPIC_LEN>;
+
+pub struct MqttConfig<'a> {
+    /// Identifies the machine in topics, so must be unique on the broker.
+    pub id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub discovery: Option<Discovery<'a>>,
+}
+
+/// Published to `events` when an input changes.
+#[derive(Serialize, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Event<'a> {
+    /// When the change was seen, if the wall clock has been set.
+    pub timestamp: Option<Timestamp>,
+    pub input: &'a str,
+    pub active: bool,
+    /// Whether the input reads as an operator forced it, not as its sensor says.
+    pub forced: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    Packet(packet::Error),
+    Json(serde_json_core::ser::Error),
+    /// The broker refused the connection, with the CONNACK return code.
+    Refused(u8),
+    /// The broker sent something other than a CONNACK first.
+    Unexpected,
+    Timeout,
+    /// The broker closed the connection.
+    Closed,
+}
+
+impl<E> From<packet::Error> for Error<E> {
+    fn from(e: packet::Error) -> Self {
+        Self::Packet(e)
+    }
+}
+
+impl<E> From<serde_json_core::ser::Error> for Error<E> {
+    fn from(e: serde_json_core::ser::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl<E> From<embassy_time::TimeoutError> for Error<E> {
+    fn from(_: embassy_time::TimeoutError) -> Self {
+        Self::Timeout
+    }
+}
+
+/// The latest I/O state, kept across connections so it can be republished on
+/// reconnecting.
+#[derive(Default)]
+struct State {
+    inputs: Option<Inputs>,
+    /// The forces when `inputs` were read.
+    forces: Option<Forces>,
+    outputs: Option<Outputs>,
+}
+
+/// Connect to the broker with `connect_fn` and bridge to it, reconnecting whenever
+/// the connection drops.
+///
+/// `inputs_signal` and `outputs_signal` report the inputs read and the outputs
+/// applied, and `forces` how the inputs were forced; commands from the broker go to `arbiter`, each changing one output.
+pub async fn run_bridge<F, Fut, S, E>(
+    mut connect_fn: F,
+    config: &MqttConfig<'_>,
+    inputs_signal: &Signal<CriticalSectionRawMutex, Inputs>,
+    outputs_signal: &Signal<CriticalSectionRawMutex, Outputs>,
+    forces: &InputForces,
+    arbiter: &Arbiter,
+    clock: &WallClock,
+) -> !
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<S, E>>,
+    S: TcpSplit,
+{
+    let bridge = Bridge {
+        config,
+        inputs_signal,
+        outputs_signal,
+        forces,
+        arbiter,
+        clock,
+        state: RefCell::new(State::default()),
+    };
+
+    loop {
+        match connect_fn().await {
+            Ok(mut socket) => {
+                info!("Connected to MQTT broker");
+                match bridge.session(&mut socket).await {
+                    Ok(()) => {}
+                    Err(Error::Refused(code)) => {
+                        warn!("MQTT broker refused the connection with code {}", code)
+                    }
+                    Err(_) => warn!("MQTT connection lost, reconnecting"),
+                }
+            }
+            Err(_) => warn!("Failed to connect to MQTT broker, retrying"),
+        }
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+struct Bridge<'a> {
+    config: &'a MqttConfig<'a>,
+    inputs_signal: &'a Signal<CriticalSectionRawMutex, Inputs>,
+    outputs_signal: &'a Signal<CriticalSectionRawMutex, Outputs>,
+    forces: &'a InputForces,
+    arbiter: &'a Arbiter,
+    clock: &'a WallClock,
+    state: RefCell<State>,
+}
+
+impl Bridge<'_> {
+    async fn session<S: TcpSplit>(&self, socket: &mut S) -> Result<(), Error<S::Error>> {
+        let (mut rx, mut tx) = socket.split();
+        let mut rx_buf = [0_u8; RX_LEN];
+        let mut tx_buf = [0_u8; TX_LEN];
+
+        let client_id = format_topic(format_args!("{}-{}", TOPIC_PREFIX, self.config.id))?;
+        let availability = self.topic(format_args!("availability"))?;
+        let connect = packet::Connect {
+            client_id: &client_id,
+            keep_alive_secs: KEEP_ALIVE.as_secs() as u16,
+            will: Some(packet::Will {
+                topic: &availability,
+                payload: OFFLINE,
+                retain: true,
+            }),
+            username: self.config.username,
+            password: self.config.password,
+        };
+        send(&mut tx, packet::connect(&mut tx_buf, &connect)?).await?;
+
+        match with_timeout(CONNACK_TIMEOUT, receive(&mut rx, &mut rx_buf)).await?? {
+            Some(Packet::ConnAck { return_code: 0 }) => {}
+            Some(Packet::ConnAck { return_code }) => return Err(Error::Refused(return_code)),
+            _ => return Err(Error::Unexpected),
+        }
+
+        let commands = self.topic(format_args!("outputs/+/set"))?;
+        send(&mut tx, packet::subscribe(&mut tx_buf, 1, &commands)?).await?;
+        if let Some(discovery) = &self.config.discovery {
+            self.publish_discovery(&mut tx, &mut tx_buf, discovery)
+                .await?;
+        }
+        self.publish(&mut tx, &mut tx_buf, &availability, ONLINE, true)
+            .await?;
+
+        // Bring the retained states up to date, in case they changed while disconnected.
+        let (inputs, forces, outputs) = {
+            let state = self.state.borrow();
+            (state.inputs.clone(), state.forces, state.outputs.clone())
+        };
+        if let (Some(inputs), Some(forces)) = (inputs, forces) {
+            self.publish_inputs(&mut tx, &mut tx_buf, None, &inputs, &forces)
+                .await?;
+            self.publish_forces(&mut tx, &mut tx_buf, None, &forces)
+                .await?;
+        }
+        if let Some(outputs) = outputs {
+            self.publish_outputs(&mut tx, &mut tx_buf, None, &outputs)
+                .await?;
+        }
+
+        match select(
+            self.receive_commands(&mut rx, &mut rx_buf),
+            self.transmit(&mut tx, &mut tx_buf),
+        )
+        .await
+        {
+            Either::First(result) | Either::Second(result) => result,
+        }
+    }
+
+    async fn receive_commands<R: Read>(
+        &self,
+        rx: &mut R,
+        buf: &mut [u8],
+    ) -> Result<(), Error<R::Error>> {
+        loop {
+            match with_timeout(RECEIVE_TIMEOUT, receive(rx, buf)).await?? {
+                Some(Packet::Publish { topic, payload }) => self.command(topic, payload),
+                Some(Packet::SubAck { granted: false, .. }) => {
+                    warn!("MQTT broker refused the subscription to output commands")
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn transmit<W: Write>(&self, tx: &mut W, buf: &mut [u8]) -> Result<(), Error<W::Error>> {
+        let mut ping_ticker = Ticker::every(PING_INTERVAL);
+        loop {
+            match select3(
+                self.inputs_signal.wait(),
+                self.outputs_signal.wait(),
+                ping_ticker.next(),
+            )
+            .await
+            {
+                Either3::First(inputs) => {
+                    let forces = self.forces.forces();
+                    let (previous, previous_forces) = {
+                        let mut state = self.state.borrow_mut();
+                        (
+                            state.inputs.replace(inputs.clone()),
+                            state.forces.replace(forces),
+                        )
+                    };
+                    self.publish_inputs(tx, buf, previous.as_ref(), &inputs, &forces)
+                        .await?;
+                    self.publish_forces(tx, buf, previous_forces.as_ref(), &forces)
+                        .await?;
+                }
+                Either3::Second(outputs) => {
+                    let previous = self.state.borrow_mut().outputs.replace(outputs.clone());
+                    self.publish_outputs(tx, buf, previous.as_ref(), &outputs)
+                        .await?;
+                }
+                Either3::Third(()) => send(tx, &packet::PINGREQ).await?,
+            }
+        }
+    }
+
+    /// Apply an `outputs/<field>/set` command, leaving the other outputs as they are.
+    fn command(&self, topic: &str, payload: &[u8]) {
+        let Some(field) = self.command_field(topic) else {
+            warn!("Ignoring MQTT message on an unexpected topic");
+            return;
+        };
+        let value = match payload {
+            b"ON" | b"on" | b"true" | b"1" => true,
+            b"OFF" | b"off" | b"false" | b"0" => false,
+            _ => {
+                warn!("Ignoring MQTT command for {} that isn't ON or OFF", field);
+                return;
+            }
+        };
+
+        let mut outputs = Outputs::default();
+        if !outputs.set(field, value) {
+            warn!("Ignoring MQTT command for unknown output {}", field);
+            return;
+        }
+        self.arbiter.request(&arbiter::mask(&[field]), &outputs);
+    }
+
+    fn command_field<'t>(&self, topic: &'t str) -> Option<&'t str> {
+        topic
+            .strip_prefix(TOPIC_PREFIX)?
+            .strip_prefix('/')?
+            .strip_prefix(self.config.id)?
+            .strip_prefix("/outputs/")?
+            .strip_suffix("/set")
+    }
+
+    async fn publish_discovery<W: Write>(
+        &self,
+        tx: &mut W,
+        buf: &mut [u8],
+        discovery: &Discovery<'_>,
+    ) -> Result<(), Error<W::Error>> {
+        let mut payload = [0_u8; PAYLOAD_LEN];
+        let fields = (Inputs::FIELDS
+            .iter()
+            .map(|field| (Component::BinarySensor, field)))
+        .chain(
+            Outputs::FIELDS
+                .iter()
+                .map(|field| (Component::Switch, field)),
+        );
+        for (component, field) in fields {
+            let announcement =
+                discovery::announce(&mut payload, discovery, self.config.id, component, field)?;
+            let payload = &payload[..announcement.size];
+            self.publish(tx, buf, &announcement.topic, payload, true)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the inputs that differ from `previous`, or all of them without it.
+    async fn publish_inputs<W: Write>(
+        &self,
+        tx: &mut W,
+        buf: &mut [u8],
+        previous: Option<&Inputs>,
+        inputs: &Inputs,
+        forces: &Forces,
+    ) -> Result<(), Error<W::Error>> {
+        let values = inputs.values();
+        let timestamp = self.clock.now();
+        for (index, (input, active)) in Inputs::FIELDS.iter().zip(values).enumerate() {
+            let changed = previous.map(|previous| previous.values()[index] != active);
+            if changed == Some(false) {
+                continue;
+            }
+
+            let topic = self.topic(format_args!("inputs/{}", input))?;
+            self.publish(tx, buf, &topic, state_payload(active), true)
+                .await?;
+
+            if changed == Some(true) {
+                let event = Event {
+                    timestamp,
+                    input,
+                    active,
+                    forced: forces[index].is_some(),
+                };
+                let mut payload = [0_u8; 128];
+                let size = serde_json_core::to_slice(&event, &mut payload)?;
+                let topic = self.topic(format_args!("events"))?;
+                self.publish(tx, buf, &topic, &payload[..size], false)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish the forces that differ from `previous`, or all of them without it.
+    async fn publish_forces<W: Write>(
+        &self,
+        tx: &mut W,
+        buf: &mut [u8],
+        previous: Option<&Forces>,
+        forces: &Forces,
+    ) -> Result<(), Error<W::Error>> {
+        for (index, (input, force)) in Inputs::FIELDS.iter().zip(forces).enumerate() {
+            if previous.is_some_and(|previous| previous[index] == *force) {
+                continue;
+            }
+            let topic = self.topic(format_args!("forces/{}", input))?;
+            let payload = force.map_or(NOT_FORCED, |force| force.name());
+            self.publish(tx, buf, &topic, payload.as_bytes(), true)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the outputs that differ from `previous`, or all of them without it.
+    async fn publish_outputs<W: Write>(
+        &self,
+        tx: &mut W,
+        buf: &mut [u8],
+        previous: Option<&Outputs>,
+        outputs: &Outputs,
+    ) -> Result<(), Error<W::Error>> {
+        let previous = previous.map(Outputs::values);
+        for (index, (output, on)) in Outputs::FIELDS.iter().zip(outputs.values()).enumerate() {
+            if previous.is_some_and(|previous| previous[index] == on) {
+                continue;
+            }
+            let topic = self.topic(format_args!("outputs/{}", output))?;
+            self.publish(tx, buf, &topic, state_payload(on), true)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn publish<W: Write>(
+        &self,
+        tx: &mut W,
+        buf: &mut [u8],
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<(), Error<W::Error>> {
+        send(tx, packet::publish(buf, topic, payload, retain)?).await
+    }
+
+    /// `pacman/<id>/` followed by `suffix`.
+    fn topic(&self, suffix: core::fmt::Arguments) -> Result<Topic, packet::Error> {
+        format_topic(format_args!(
+            "{}/{}/{}",
+            TOPIC_PREFIX, self.config.id, suffix
+        ))
+    }
+}
+
+fn format_topic(args: core::fmt::Arguments) -> Result<Topic, packet::Error> {
+    let mut topic = Topic::new();
+    topic.write_fmt(args).map_err(|_| packet::Error::TooLong)?;
+    Ok(topic)
+}
+
+fn state_payload(on: bool) -> &'static [u8] {
+    if on {
+        ON
+    } else {
+        OFF
+    }
+}
+
+async fn send<W: Write>(tx: &mut W, packet: &[u8]) -> Result<(), Error<W::Error>> {
+    tx.write_all(packet).await.map_err(Error::Io)?;
+    tx.flush().await.map_err(Error::Io)
+}
+
+/// Read the next packet, or `None` if it was too long for `buf` and was skipped.
+async fn receive<'b, R: Read>(
+    rx: &mut R,
+    buf: &'b mut [u8],
+) -> Result<Option<Packet<'b>>, Error<R::Error>> {
+    let mut byte = [0_u8];
+    read_exact(rx, &mut byte).await?;
+    let header = byte[0];
+
+    let mut length = 0_usize;
+    for shift in (0..packet::MAX_REMAINING_LENGTH_LEN).map(|i| i * 7) {
+        read_exact(rx, &mut byte).await?;
+        length |= usize::from(byte[0] & 0x7F) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if shift == (packet::MAX_REMAINING_LENGTH_LEN - 1) * 7 {
+            return Err(packet::Error::Malformed.into());
+        }
+    }
+
+    if length > buf.len() {
+        warn!("Skipping MQTT packet of {} bytes", length);
+        while length > 0 {
+            let chunk = length.min(buf.len());
+            read_exact(rx, &mut buf[..chunk]).await?;
+            length -= chunk;
+        }
+        return Ok(None);
+    }
+
+    read_exact(rx, &mut buf[..length]).await?;
+    Ok(Some(packet::decode(header, &buf[..length])?))
+}
+
+async fn read_exact<R: Read>(rx: &mut R, buf: &mut [u8]) -> Result<(), Error<R::Error>> {
+    rx.read_exact(buf).await.map_err(|e| match e {
+        embedded_io_async::ReadExactError::UnexpectedEof => Error::Closed,
+        embedded_io_async::ReadExactError::Other(e) => Error::Io(e),
+    })
+}
@@ -0,0 +1,424 @@
+//! Packet encoding for a minimal MQTT 3.1.1 client.
+//!
+//! Only QoS 0 is supported. States are retained and republished on change, so a
+//! lost message is put right by the next one.
+
+pub const PORT: u16 = 1883;
+
+/// The remaining length takes up to four bytes, seven bits in each.
+pub const MAX_REMAINING_LENGTH_LEN: usize = 4;
+/// Fixed header plus the longest remaining length encoding.
+const MAX_HEADER_LEN: usize = 1 + MAX_REMAINING_LENGTH_LEN;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGRESP: u8 = 0xD0;
+
+pub const PINGREQ: [u8; 2] = [0xC0, 0x00];
+pub const DISCONNECT: [u8; 2] = [0xE0, 0x00];
+
+const PROTOCOL_LEVEL: u8 = 4;
+const FLAG_CLEAN_SESSION: u8 = 0x02;
+const FLAG_WILL: u8 = 0x04;
+const FLAG_WILL_RETAIN: u8 = 0x20;
+const FLAG_PASSWORD: u8 = 0x40;
+const FLAG_USERNAME: u8 = 0x80;
+const FLAG_RETAIN: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The packet doesn't fit in the buffer.
+    TooLong,
+    /// A packet from the broker doesn't parse.
+    Malformed,
+}
+
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    pub keep_alive_secs: u16,
+    pub will: Option<Will<'a>>,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+}
+
+/// Published by the broker on our behalf if the connection drops.
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Packet<'a> {
+    ConnAck {
+        return_code: u8,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+    },
+    SubAck {
+        packet_id: u16,
+        granted: bool,
+    },
+    PingResp,
+    /// Anything else, identified by its fixed header byte, which a QoS 0 client can ignore.
+    Other(u8),
+}
+
+pub fn connect<'b>(buf: &'b mut [u8], connect: &Connect) -> Result<&'b [u8], Error> {
+    encode(buf, CONNECT, |e| {
+        let mut flags = FLAG_CLEAN_SESSION;
+        if let Some(will) = &connect.will {
+            flags |= FLAG_WILL;
+            if will.retain {
+                flags |= FLAG_WILL_RETAIN;
+            }
+        }
+        if connect.username.is_some() {
+            flags |= FLAG_USERNAME;
+        }
+        if connect.password.is_some() {
+            flags |= FLAG_PASSWORD;
+        }
+
+        e.str("MQTT")?;
+        e.u8(PROTOCOL_LEVEL)?;
+        e.u8(flags)?;
+        e.u16(connect.keep_alive_secs)?;
+        e.str(connect.client_id)?;
+        if let Some(will) = &connect.will {
+            e.str(will.topic)?;
+            e.u16(will.payload.len().try_into().map_err(|_| Error::TooLong)?)?;
+            e.bytes(will.payload)?;
+        }
+        if let Some(username) = connect.username {
+            e.str(username)?;
+        }
+        if let Some(password) = connect.password {
+            e.str(password)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn publish<'b>(
+    buf: &'b mut [u8],
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+) -> Result<&'b [u8], Error> {
+    let flags = if retain { FLAG_RETAIN } else { 0 };
+    encode(buf, PUBLISH | flags, |e| {
+        e.str(topic)?;
+        e.bytes(payload)
+    })
+}
+
+/// Subscribe to a single topic filter at QoS 0.
+pub fn subscribe<'b>(buf: &'b mut [u8], packet_id: u16, filter: &str) -> Result<&'b [u8], Error> {
+    encode(buf, SUBSCRIBE, |e| {
+        e.u16(packet_id)?;
+        e.str(filter)?;
+        e.u8(0)
+    })
+}
+
+/// Decode a packet from its fixed header byte and the body that followed the
+/// remaining length.
+pub fn decode(header: u8, body: &[u8]) -> Result<Packet<'_>, Error> {
+    let mut d = Decoder { body };
+    let packet = match header & 0xF0 {
+        CONNACK => {
+            let _session_present = d.u8()?;
+            Packet::ConnAck {
+                return_code: d.u8()?,
+            }
+        }
+        PUBLISH => {
+            let topic = d.str()?;
+            if header & 0x06 != 0 {
+                // QoS 1 or 2, which we never subscribe at, carry a packet id.
+                d.u16()?;
+            }
+            Packet::Publish {
+                topic,
+                payload: d.body,
+            }
+        }
+        SUBACK => Packet::SubAck {
+            packet_id: d.u16()?,
+            granted: d.u8()? & 0x80 == 0,
+        },
+        PINGRESP => Packet::PingResp,
+        _ => Packet::Other(header),
+    };
+    Ok(packet)
+}
+
+/// Encode the body after room for the fixed header, then slide it back once its
+/// length is known.
+fn encode(
+    buf: &mut [u8],
+    header: u8,
+    body: impl FnOnce(&mut Encoder) -> Result<(), Error>,
+) -> Result<&[u8], Error> {
+    let mut e = Encoder {
+        buf,
+        len: MAX_HEADER_LEN,
+    };
+    body(&mut e)?;
+    let Encoder { buf, len } = e;
+
+    let mut remaining = len - MAX_HEADER_LEN;
+    let mut length = [0_u8; MAX_REMAINING_LENGTH_LEN];
+    let mut length_len = 0;
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        *length.get_mut(length_len).ok_or(Error::TooLong)? = byte;
+        length_len += 1;
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    let start = MAX_HEADER_LEN - 1 - length_len;
+    buf[start] = header;
+    buf[start + 1..MAX_HEADER_LEN].copy_from_slice(&length[..length_len]);
+    Ok(&buf[start..len])
+}
+
+struct Encoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Encoder<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(Error::TooLong)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), Error> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn str(&mut self, value: &str) -> Result<(), Error> {
+        self.u16(value.len().try_into().map_err(|_| Error::TooLong)?)?;
+        self.bytes(value.as_bytes())
+    }
+}
+
+struct Decoder<'b> {
+    body: &'b [u8],
+}
+
+impl<'b> Decoder<'b> {
+    fn bytes(&mut self, len: usize) -> Result<&'b [u8], Error> {
+        if self.body.len() < len {
+            return Err(Error::Malformed);
+        }
+        let (bytes, rest) = self.body.split_at(len);
+        self.body = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn str(&mut self) -> Result<&'b str, Error> {
+        let len = self.u16()?;
+        core::str::from_utf8(self.bytes(len.into())?).map_err(|_| Error::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split an encoded packet into its fixed header byte and body.
+    fn split(packet: &[u8]) -> (u8, &[u8]) {
+        let mut length = 0;
+        for (i, byte) in packet[1..].iter().enumerate() {
+            length |= usize::from(byte & 0x7F) << (7 * i);
+            if byte & 0x80 == 0 {
+                let body = &packet[2 + i..];
+                assert_eq!(body.len(), length);
+                return (packet[0], body);
+            }
+        }
+        panic!("unterminated remaining length");
+    }
+
+    #[test]
+    fn publish_round_trips() {
+        let mut buf = [0_u8; 64];
+        let packet = publish(&mut buf, "pacman/std/inputs/tilt_switch", b"ON", true).unwrap();
+        let (header, body) = split(packet);
+        assert_eq!(header, PUBLISH | FLAG_RETAIN);
+        assert_eq!(
+            decode(header, body),
+            Ok(Packet::Publish {
+                topic: "pacman/std/inputs/tilt_switch",
+                payload: b"ON",
+            })
+        );
+    }
+
+    #[test]
+    fn long_publish_takes_two_length_bytes() {
+        let payload = [b'x'; 300];
+        let mut buf = [0_u8; 320];
+        let packet = publish(&mut buf, "events", &payload, false).unwrap();
+        // 2 + 6 for the topic and 300 of payload is 308, or 52 + 2 * 128.
+        assert_eq!(&packet[..3], &[PUBLISH, 0x80 | 52, 2]);
+        let (header, body) = split(packet);
+        assert_eq!(
+            decode(header, body),
+            Ok(Packet::Publish {
+                topic: "events",
+                payload: &payload,
+            })
+        );
+    }
+
+    #[test]
+    fn connect_encodes_every_field() {
+        let mut buf = [0_u8; 64];
+        let packet = connect(
+            &mut buf,
+            &Connect {
+                client_id: "id",
+                keep_alive_secs: 60,
+                will: Some(Will {
+                    topic: "w",
+                    payload: b"off",
+                    retain: true,
+                }),
+                username: Some("u"),
+                password: Some("p"),
+            },
+        )
+        .unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            CONNECT, 28,
+            0, 4, b'M', b'Q', b'T', b'T',
+            PROTOCOL_LEVEL,
+            FLAG_USERNAME | FLAG_PASSWORD | FLAG_WILL_RETAIN | FLAG_WILL | FLAG_CLEAN_SESSION,
+            0, 60,
+            0, 2, b'i', b'd',
+            0, 1, b'w',
+            0, 3, b'o', b'f', b'f',
+            0, 1, b'u',
+            0, 1, b'p',
+        ];
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn subscribe_encodes_at_qos_0() {
+        let mut buf = [0_u8; 32];
+        let packet = subscribe(&mut buf, 1, "a/+/set").unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            SUBSCRIBE, 12,
+            0, 1,
+            0, 7, b'a', b'/', b'+', b'/', b's', b'e', b't',
+            0,
+        ];
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn encoding_too_long_for_the_buffer_fails() {
+        let mut buf = [0_u8; 16];
+        assert_eq!(
+            publish(&mut buf, "topic", &[0; 16], false),
+            Err(Error::TooLong)
+        );
+        let long_topic = core::str::from_utf8(&[b't'; 70_000]).unwrap();
+        let mut buf = [0_u8; 16];
+        assert_eq!(
+            publish(&mut buf, long_topic, b"", false),
+            Err(Error::TooLong)
+        );
+    }
+
+    #[test]
+    fn decodes_broker_packets() {
+        assert_eq!(
+            decode(CONNACK, &[0, 5]),
+            Ok(Packet::ConnAck { return_code: 5 })
+        );
+        assert_eq!(
+            decode(SUBACK, &[0, 1, 0]),
+            Ok(Packet::SubAck {
+                packet_id: 1,
+                granted: true,
+            })
+        );
+        assert_eq!(
+            decode(SUBACK, &[0, 1, 0x80]),
+            Ok(Packet::SubAck {
+                packet_id: 1,
+                granted: false,
+            })
+        );
+        assert_eq!(decode(PINGRESP, &[]), Ok(Packet::PingResp));
+        assert_eq!(decode(0xB0, &[0, 1]), Ok(Packet::Other(0xB0)));
+    }
+
+    #[test]
+    fn qos_1_publish_skips_the_packet_id() {
+        assert_eq!(
+            decode(PUBLISH | 0x02, &[0, 1, b't', 0, 9, b'O', b'N']),
+            Ok(Packet::Publish {
+                topic: "t",
+                payload: b"ON",
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_packets_are_malformed() {
+        assert_eq!(decode(CONNACK, &[0]), Err(Error::Malformed));
+        assert_eq!(decode(SUBACK, &[0, 1]), Err(Error::Malformed));
+        // The topic claims five bytes but there are two.
+        assert_eq!(decode(PUBLISH, &[0, 5, b'a', b'b']), Err(Error::Malformed));
+        assert_eq!(decode(PUBLISH, &[0]), Err(Error::Malformed));
+        assert_eq!(
+            decode(PUBLISH | 0x02, &[0, 1, b't', 0]),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn topic_that_isnt_utf8_is_malformed() {
+        assert_eq!(decode(PUBLISH, &[0, 2, 0xC3, 0x28]), Err(Error::Malformed));
+    }
+}
@@ -1,3 +1,6 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
 use serde::{Deserialize, Serialize};
 
 #[allow(async_fn_in_trait)]
@@ -7,6 +10,43 @@ pub trait Io {
     async fn set_outputs(&mut self, outputs: Outputs) -> Result<(), Self::Error>;
 }
 
+/// Defines a struct of bool fields along with a table of their names and
+/// by-name access, for protocols that address fields individually.
+macro_rules! fields {
+    ($(#[$meta:meta])* pub struct $name:ident { $(pub $field:ident: bool,)* }) => {
+        $(#[$meta])*
+        pub struct $name {
+            $(pub $field: bool,)*
+        }
+
+        impl $name {
+            pub const FIELDS: &'static [&'static str] = &[$(stringify!($field)),*];
+
+            /// The field values, in the same order as `FIELDS`.
+            pub fn values(&self) -> [bool; $name::FIELDS.len()] {
+                [$(self.$field),*]
+            }
+
+            pub fn get(&self, field: &str) -> Option<bool> {
+                match field {
+                    $(stringify!($field) => Some(self.$field),)*
+                    _ => None,
+                }
+            }
+
+            /// Set a field by name, returning `false` if there is no such field.
+            pub fn set(&mut self, field: &str, value: bool) -> bool {
+                match field {
+                    $(stringify!($field) => self.$field = value,)*
+                    _ => return false,
+                }
+                true
+            }
+        }
+    };
+}
+
+fields! {
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Inputs {
@@ -33,7 +73,9 @@ pub struct Inputs {
     pub select_switch_down: bool,
     pub enter_switch: bool,
 }
+}
 
+fields! {
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Outputs {
@@ -55,3 +97,52 @@ pub struct Outputs {
     pub divider_solenoid_right: bool,
     pub ray_lamp: bool,
 }
+}
+
+/// The inputs last read and the outputs last applied, for anything that needs
+/// them on demand rather than as they change.
+pub struct IoState {
+    state: Mutex<CriticalSectionRawMutex, RefCell<Latest>>,
+}
+
+struct Latest {
+    inputs: Option<Inputs>,
+    outputs: Option<Outputs>,
+}
+
+impl IoState {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(Latest {
+                inputs: None,
+                outputs: None,
+            })),
+        }
+    }
+
+    pub fn set_inputs(&self, inputs: &Inputs) {
+        self.state
+            .lock(|state| state.borrow_mut().inputs = Some(inputs.clone()));
+    }
+
+    pub fn set_outputs(&self, outputs: &Outputs) {
+        self.state
+            .lock(|state| state.borrow_mut().outputs = Some(outputs.clone()));
+    }
+
+    /// `None` until the inputs have first been read.
+    pub fn inputs(&self) -> Option<Inputs> {
+        self.state.lock(|state| state.borrow().inputs.clone())
+    }
+
+    /// `None` until outputs have first been applied.
+    pub fn outputs(&self) -> Option<Outputs> {
+        self.state.lock(|state| state.borrow().outputs.clone())
+    }
+}
+
+impl Default for IoState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
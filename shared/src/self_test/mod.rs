@@ -0,0 +1,336 @@
+//! A self-test that energises each output in turn and checks that the sensors it
+//! should move respond, rather than a tech clicking through every output and
+//! watching by eye.
+//!
+//! The test holds every output through `Layer::Test`, so interlocks still apply,
+//! lighting the checker LED of the subsystem under test. Afterwards each LED shows
+//! how its subsystem did for a while - lit if it passed, flashing if it failed, and
+//! off if it has nothing to check - before the outputs are handed back.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use serde::Serialize;
+
+use crate::arbiter::{self, Arbiter, Layer};
+use crate::clock::{Timestamp, WallClock};
+use crate::pac_man_ball::{IoState, Outputs};
+
+/// How long to leave things to come to rest between steps.
+const SETTLE: Duration = Duration::from_millis(500);
+const POLL: Duration = Duration::from_millis(20);
+const SHOW_RESULTS: Duration = Duration::from_secs(30);
+const FLASH: Duration = Duration::from_millis(250);
+
+/// The subsystems, each shown on the checker LED at the same index.
+pub const SUBSYSTEMS: [&str; 7] = [
+    "table",
+    "left_hopper",
+    "right_hopper",
+    "out_hopper",
+    "dividers",
+    "solenoids",
+    "lamps",
+];
+const LEDS: [&str; SUBSYSTEMS.len()] = [
+    "checker_0_led",
+    "checker_1_led",
+    "checker_2_led",
+    "checker_3_led",
+    "checker_4_led",
+    "checker_5_led",
+    "checker_6_led",
+];
+
+/// The most steps in one subsystem.
+const MAX_STEPS: usize = 3;
+
+/// Energising an output, and the sensors that should respond.
+pub struct Step {
+    pub subsystem: &'static str,
+    pub output: &'static str,
+    /// Each must change within `window`. With none, the output is just energised
+    /// for `window`, for a tech to see or hear.
+    pub inputs: &'static [&'static str],
+    pub window: Duration,
+}
+
+pub const STEPS: [Step; 10] = [
+    Step {
+        subsystem: "table",
+        output: "table_motor",
+        inputs: &["table_sensor"],
+        window: Duration::from_secs(5),
+    },
+    Step {
+        subsystem: "left_hopper",
+        output: "left_hopper",
+        inputs: &["hopper_left_sensor"],
+        window: Duration::from_secs(3),
+    },
+    Step {
+        subsystem: "right_hopper",
+        output: "right_hopper",
+        inputs: &["hopper_right_sensor"],
+        window: Duration::from_secs(3),
+    },
+    Step {
+        subsystem: "out_hopper",
+        output: "out_hopper",
+        inputs: &["hopper_out_sensor"],
+        window: Duration::from_secs(3),
+    },
+    Step {
+        subsystem: "dividers",
+        output: "divider_solenoid_left",
+        inputs: &["left_divider_sensor"],
+        window: Duration::from_secs(1),
+    },
+    Step {
+        subsystem: "dividers",
+        output: "divider_solenoid_right",
+        inputs: &["right_divider_sensor"],
+        window: Duration::from_secs(1),
+    },
+    Step {
+        subsystem: "solenoids",
+        output: "lockout_solenoid_left",
+        inputs: &[],
+        window: Duration::from_millis(500),
+    },
+    Step {
+        subsystem: "solenoids",
+        output: "lockout_solenoid_right",
+        inputs: &[],
+        window: Duration::from_millis(500),
+    },
+    Step {
+        subsystem: "solenoids",
+        output: "payout_solenoid",
+        inputs: &[],
+        window: Duration::from_millis(500),
+    },
+    Step {
+        subsystem: "lamps",
+        output: "ray_lamp",
+        inputs: &[],
+        window: Duration::from_secs(2),
+    },
+];
+
+/// The most inputs checked in one step.
+const MAX_INPUTS: usize = 2;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Nothing to check it with.
+    Unchecked,
+}
+
+struct State {
+    running: bool,
+    started: Option<Timestamp>,
+    /// Indexed like `STEPS`, `None` until each has run.
+    outcomes: [Option<Outcome>; STEPS.len()],
+}
+
+pub struct SelfTest<'a> {
+    arbiter: &'a Arbiter,
+    io_state: &'a IoState,
+    clock: &'a WallClock,
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+    start: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<'a> SelfTest<'a> {
+    pub const fn new(arbiter: &'a Arbiter, io_state: &'a IoState, clock: &'a WallClock) -> Self {
+        Self {
+            arbiter,
+            io_state,
+            clock,
+            state: Mutex::new(RefCell::new(State {
+                running: false,
+                started: None,
+                outcomes: [None; STEPS.len()],
+            })),
+            start: Signal::new(),
+        }
+    }
+
+    /// Start a test, unless one is already running. Returns whether it started.
+    pub fn start(&self) -> bool {
+        let now = self.clock.now();
+        let started = self.lock(|state| {
+            if state.running {
+                return false;
+            }
+            *state = State {
+                running: true,
+                started: now,
+                outcomes: [None; STEPS.len()],
+            };
+            true
+        });
+        if started {
+            self.start.signal(());
+        }
+        started
+    }
+
+    pub fn report(&self) -> Report {
+        self.lock(|state| Report {
+            running: state.running,
+            started: state.started,
+            subsystems: core::array::from_fn(|i| {
+                let mut steps = heapless::Vec::new();
+                for (step, outcome) in STEPS.iter().zip(state.outcomes) {
+                    if step.subsystem == SUBSYSTEMS[i] {
+                        let _ = steps.push(StepReport {
+                            output: step.output,
+                            outcome,
+                        });
+                    }
+                }
+                SubsystemReport {
+                    name: SUBSYSTEMS[i],
+                    outcome: overall(steps.iter().map(|step| step.outcome)),
+                    steps,
+                }
+            }),
+        })
+    }
+
+    /// Run each test as it's started.
+    pub async fn run(&self) -> ! {
+        loop {
+            self.start.wait().await;
+            info!("Starting self-test");
+            for (i, step) in STEPS.iter().enumerate() {
+                let outcome = self.check(step).await;
+                match outcome {
+                    Outcome::Fail => warn!("Self-test of {} failed", step.output),
+                    _ => info!("Self-test of {}: {:?}", step.output, outcome),
+                }
+                self.lock(|state| state.outcomes[i] = Some(outcome));
+            }
+            self.lock(|state| state.running = false);
+            info!("Self-test finished");
+
+            self.show_results().await;
+            self.arbiter.release(Layer::Test, &arbiter::ALL);
+        }
+    }
+
+    async fn check(&self, step: &Step) -> Outcome {
+        let mut outputs = Outputs::default();
+        outputs.set(step.output, true);
+        if let Some(i) = SUBSYSTEMS.iter().position(|name| *name == step.subsystem) {
+            outputs.set(LEDS[i], true);
+        }
+        self.arbiter.claim(Layer::Test, &arbiter::ALL, &outputs);
+
+        let deadline = Instant::now() + step.window;
+        let mut before = self.io_state.inputs();
+        let mut responded = [false; MAX_INPUTS];
+        let responded = &mut responded[..step.inputs.len()];
+        while Instant::now() < deadline {
+            Timer::after(POLL).await;
+            let Some(inputs) = self.io_state.inputs() else {
+                continue;
+            };
+            let before = before.get_or_insert_with(|| inputs.clone());
+            for (responded, input) in responded.iter_mut().zip(step.inputs) {
+                *responded |= inputs.get(input) != before.get(input);
+            }
+            if !responded.is_empty() && responded.iter().all(|r| *r) {
+                break;
+            }
+        }
+
+        self.arbiter
+            .claim(Layer::Test, &arbiter::ALL, &Outputs::default());
+        Timer::after(SETTLE).await;
+
+        if responded.is_empty() {
+            Outcome::Unchecked
+        } else if responded.iter().all(|r| *r) {
+            Outcome::Pass
+        } else {
+            Outcome::Fail
+        }
+    }
+
+    /// Show the outcomes on the checker LEDs, until they time out or another test
+    /// is started.
+    async fn show_results(&self) {
+        let report = self.report();
+        let mut lit = false;
+        for _ in 0..SHOW_RESULTS.as_ticks() / FLASH.as_ticks() {
+            if self.start.signaled() {
+                break;
+            }
+            lit = !lit;
+            let mut outputs = Outputs::default();
+            for (led, subsystem) in LEDS.iter().zip(&report.subsystems) {
+                let on = match subsystem.outcome {
+                    Some(Outcome::Pass) => true,
+                    Some(Outcome::Fail) => lit,
+                    _ => false,
+                };
+                outputs.set(led, on);
+            }
+            self.arbiter.claim(Layer::Test, &arbiter::ALL, &outputs);
+            Timer::after(FLASH).await;
+        }
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+/// A subsystem fails if any step did, and is otherwise pending until all have run.
+fn overall(outcomes: impl Iterator<Item = Option<Outcome>> + Clone) -> Option<Outcome> {
+    if outcomes
+        .clone()
+        .any(|outcome| outcome == Some(Outcome::Fail))
+    {
+        return Some(Outcome::Fail);
+    }
+    let mut overall = Outcome::Unchecked;
+    for outcome in outcomes {
+        if outcome? == Outcome::Pass {
+            overall = Outcome::Pass;
+        }
+    }
+    Some(overall)
+}
+
+/// e.g. `{"running":false,"started":...,"subsystems":[{"name":"table","outcome":"pass","steps":[{"output":"table_motor","outcome":"pass"}]},...]}`,
+/// with a `null` outcome for anything still to run.
+#[derive(Serialize)]
+pub struct Report {
+    pub running: bool,
+    /// When the last test started, if the wall clock was set.
+    pub started: Option<Timestamp>,
+    pub subsystems: [SubsystemReport; SUBSYSTEMS.len()],
+}
+
+#[derive(Serialize)]
+pub struct SubsystemReport {
+    pub name: &'static str,
+    pub outcome: Option<Outcome>,
+    pub steps: heapless::Vec<StepReport, MAX_STEPS>,
+}
+
+#[derive(Serialize)]
+pub struct StepReport {
+    pub output: &'static str,
+    pub outcome: Option<Outcome>,
+}
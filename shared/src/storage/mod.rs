@@ -0,0 +1,95 @@
+//! Settings and counts kept across power cycles, in flash on the board and in
+//! files on the host.
+//!
+//! Each `Record` is saved whole, encoded with postcard, so a record that fails to
+//! decode after its type has changed is simply treated as never saved.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The largest encoded record.
+pub const MAX_RECORD_LEN: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Record {
+    /// Input forces that should outlast a restart.
+    InputForces,
+    /// Credits, and how coins buy them.
+    Credits,
+    /// Tickets won but not yet paid out.
+    TicketsOwed,
+    /// The audit counters.
+    Bookkeeping,
+}
+
+impl Record {
+    pub const ALL: [Self; 4] = [
+        Self::InputForces,
+        Self::Credits,
+        Self::TicketsOwed,
+        Self::Bookkeeping,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::InputForces => "input_forces",
+            Self::Credits => "credits",
+            Self::TicketsOwed => "tickets_owed",
+            Self::Bookkeeping => "bookkeeping",
+        }
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    type Error: core::fmt::Debug;
+
+    /// Read the last copy of `record` saved into `buf`, returning its length, or
+    /// `None` if it has never been saved.
+    async fn load(&mut self, record: Record, buf: &mut [u8]) -> Result<Option<usize>, Self::Error>;
+    /// Replace `record`, so that a power cut part way through leaves the old copy.
+    async fn save(&mut self, record: Record, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Load and decode `record`, or `None` if it has never been saved or can't be
+/// read, which is logged.
+pub async fn load<T, S>(storage: &Mutex<CriticalSectionRawMutex, S>, record: Record) -> Option<T>
+where
+    T: DeserializeOwned,
+    S: Storage,
+{
+    let mut buf = [0_u8; MAX_RECORD_LEN];
+    let len = match storage.lock().await.load(record, &mut buf).await {
+        Ok(len) => len?,
+        Err(e) => {
+            warn!("Failed to load {}: {:?}", record.name(), debug2format!(e));
+            return None;
+        }
+    };
+    match postcard::from_bytes(&buf[..len]) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Ignoring saved {} that no longer decodes", record.name());
+            None
+        }
+    }
+}
+
+/// Encode and save `record`, logging any failure.
+pub async fn save<T, S>(storage: &Mutex<CriticalSectionRawMutex, S>, record: Record, value: &T)
+where
+    T: Serialize,
+    S: Storage,
+{
+    let mut buf = [0_u8; MAX_RECORD_LEN];
+    let Ok(data) = postcard::to_slice(value, &mut buf) else {
+        error!("{} doesn't fit in a record", record.name());
+        return;
+    };
+    if let Err(e) = storage.lock().await.save(record, data).await {
+        warn!("Failed to save {}: {:?}", record.name(), debug2format!(e));
+    }
+}
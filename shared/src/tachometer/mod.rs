@@ -0,0 +1,168 @@
+//! The table's speed, from `table_sensor` pulsing once per revolution, and stall
+//! detection while `table_motor` is on.
+//!
+//! A revolution that takes longer than `STALL` while the motor is on, whether
+//! the table has jammed or the belt has slipped off, shuts the motor off through
+//! `Layer::Safety` until an operator clears the stall.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::arbiter::{self, Arbiter, Layer};
+use crate::clock::{Timestamp, WallClock};
+use crate::events::{EventLog, Fault};
+use crate::pac_man_ball::{Inputs, Outputs};
+
+/// The longest a revolution may take while the motor is on, including the first
+/// after starting.
+pub const STALL: Duration = Duration::from_secs(5);
+
+/// Revolutions kept for clients.
+pub const HISTORY: usize = 32;
+
+/// The speed over one revolution.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sample {
+    /// When the revolution ended, if the wall clock has been set.
+    pub timestamp: Option<Timestamp>,
+    pub rpm: f32,
+}
+
+struct State {
+    sensor: Option<bool>,
+    /// The last rising edge of `table_sensor`.
+    last_pulse: Option<Instant>,
+    /// The speed over the last revolution, unless it was the first in a while.
+    rpm: Option<f32>,
+    /// When the motor came on, or `None` while it's off.
+    running_since: Option<Instant>,
+    stalled: bool,
+    history: heapless::Deque<Sample, HISTORY>,
+}
+
+pub struct Tachometer<'a> {
+    arbiter: &'a Arbiter,
+    events: &'a EventLog<'a>,
+    clock: &'a WallClock,
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+}
+
+impl<'a> Tachometer<'a> {
+    pub const fn new(arbiter: &'a Arbiter, events: &'a EventLog<'a>, clock: &'a WallClock) -> Self {
+        Self {
+            arbiter,
+            events,
+            clock,
+            state: Mutex::new(RefCell::new(State {
+                sensor: None,
+                last_pulse: None,
+                rpm: None,
+                running_since: None,
+                stalled: false,
+                history: heapless::Deque::new(),
+            })),
+        }
+    }
+
+    /// Time the revolutions, and check the table is keeping up with the motor.
+    pub fn record_inputs(&self, inputs: &Inputs) {
+        let now = Instant::now();
+        let timestamp = self.clock.at(now);
+        let stalled = self.lock(|state| {
+            let pulse = inputs.table_sensor && state.sensor == Some(false);
+            state.sensor = Some(inputs.table_sensor);
+            if pulse {
+                // A gap longer than a stall is the table starting, not a speed.
+                let period = state.last_pulse.map(|last| now - last);
+                state.rpm = period.filter(|period| *period <= STALL).map(rpm);
+                if let Some(rpm) = state.rpm {
+                    if state.history.is_full() {
+                        state.history.pop_front();
+                    }
+                    let _ = state.history.push_back(Sample { timestamp, rpm });
+                }
+                state.last_pulse = Some(now);
+            }
+
+            let Some(since) = state.running_since else {
+                return false;
+            };
+            let since = state.last_pulse.map_or(since, |pulse| pulse.max(since));
+            if state.stalled || now - since <= STALL {
+                return false;
+            }
+            state.stalled = true;
+            true
+        });
+
+        if stalled {
+            warn!("Table stalled, shutting the motor off");
+            self.arbiter.claim(
+                Layer::Safety,
+                &arbiter::mask(&["table_motor"]),
+                &Outputs::default(),
+            );
+            self.events.record_fault(Fault::TableStall);
+        }
+    }
+
+    /// Note whether the motor is on, starting the clock for its first revolution.
+    pub fn record_outputs(&self, outputs: &Outputs) {
+        let now = Instant::now();
+        self.lock(|state| match (outputs.table_motor, state.running_since) {
+            (true, None) => state.running_since = Some(now),
+            (false, Some(_)) => state.running_since = None,
+            _ => {}
+        });
+    }
+
+    /// Whether the table has stalled, and not been cleared since.
+    pub fn stalled(&self) -> bool {
+        self.lock(|state| state.stalled)
+    }
+
+    /// Let the motor run again after a stall. Returns whether it had stalled.
+    pub fn clear_stall(&self) -> bool {
+        let stalled = self.lock(|state| core::mem::take(&mut state.stalled));
+        if stalled {
+            info!("Table stall cleared");
+            self.arbiter
+                .release(Layer::Safety, &arbiter::mask(&["table_motor"]));
+        }
+        stalled
+    }
+
+    /// e.g. `{"rpm":30.0,"stalled":false,"history":[{"timestamp":...,"rpm":29.8},...]}`,
+    /// oldest revolution first.
+    pub fn report(&self) -> Report {
+        let now = Instant::now();
+        self.lock(|state| {
+            // The last speed only holds while the next revolution could still come.
+            let turning = state.last_pulse.is_some_and(|pulse| now - pulse <= STALL);
+            Report {
+                rpm: state.rpm.filter(|_| turning).unwrap_or_default(),
+                stalled: state.stalled,
+                history: state.history.iter().copied().collect(),
+            }
+        })
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+fn rpm(period: Duration) -> f32 {
+    60_000_000.0 / period.as_micros().max(1) as f32
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub rpm: f32,
+    pub stalled: bool,
+    pub history: heapless::Vec<Sample, HISTORY>,
+}
@@ -0,0 +1,183 @@
+//! Paying out tickets from a redemption ticket dispenser.
+//!
+//! The dispenser feeds tickets while driven, and its notch sensor sees the notch
+//! between each pair go past, so each notch is a ticket paid. If no notch comes
+//! within `NOTCH_TIMEOUT` the dispenser is stopped: with the sensor still on a
+//! notch, the ticket has jammed, and with it off, the dispenser has run out. The
+//! tickets still owed are kept in flash, and paid once it has been refilled or
+//! unjammed and the payout resumed, or after a power cut.
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use serde::{Deserialize, Serialize};
+
+use crate::bookkeeping::Bookkeeping;
+use crate::events::{EventLog, Fault};
+use crate::storage::{self, Record, Storage};
+
+/// The longest a ticket may take to come out.
+pub const NOTCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many tickets to pay between saving what's owed. A power cut part way through
+/// pays up to this many twice, but each save wears the flash.
+const SAVE_EVERY: u32 = 10;
+
+/// A ticket dispenser's drive and notch sensor.
+#[allow(async_fn_in_trait)]
+pub trait Dispenser {
+    fn set_running(&mut self, running: bool);
+    /// Whether the sensor is on a notch.
+    fn at_notch(&mut self) -> bool;
+    /// Wait for the next notch to reach the sensor.
+    async fn wait_for_notch(&mut self);
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    Empty,
+    Jammed,
+}
+
+struct State {
+    owed: u32,
+    /// Paid since starting.
+    paid: u32,
+    /// Why the payout stopped, until it's resumed.
+    problem: Option<Problem>,
+}
+
+pub struct Tickets<'a> {
+    events: &'a EventLog<'a>,
+    bookkeeping: &'a Bookkeeping<'a>,
+    state: BlockingMutex<CriticalSectionRawMutex, RefCell<State>>,
+    /// Raised when there may be tickets to pay.
+    owed: Signal<CriticalSectionRawMutex, ()>,
+    /// Raised when what's owed should be saved.
+    persist: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<'a> Tickets<'a> {
+    pub const fn new(events: &'a EventLog<'a>, bookkeeping: &'a Bookkeeping<'a>) -> Self {
+        Self {
+            events,
+            bookkeeping,
+            state: BlockingMutex::new(RefCell::new(State {
+                owed: 0,
+                paid: 0,
+                problem: None,
+            })),
+            owed: Signal::new(),
+            persist: Signal::new(),
+        }
+    }
+
+    /// Pay out `tickets` more, and resume the payout if it had stopped.
+    pub fn pay(&self, tickets: u32) {
+        let owed = self.lock(|state| {
+            state.owed = state.owed.saturating_add(tickets);
+            state.problem = None;
+            state.owed
+        });
+        info!("Paying {} tickets, {} owed", tickets, owed);
+        self.persist.signal(());
+        self.owed.signal(());
+    }
+
+    /// e.g. `{"owed":12,"paid":40,"problem":"empty"}`.
+    pub fn report(&self) -> Report {
+        self.lock(|state| Report {
+            owed: state.owed,
+            paid: state.paid,
+            problem: state.problem,
+        })
+    }
+
+    /// Drive `dispenser` whenever tickets are owed.
+    pub async fn run<D: Dispenser>(&self, dispenser: &mut D) -> ! {
+        loop {
+            while self.lock(|state| state.owed == 0 || state.problem.is_some()) {
+                self.owed.wait().await;
+            }
+
+            dispenser.set_running(true);
+            let problem = self.dispense(dispenser).await;
+            dispenser.set_running(false);
+            self.persist.signal(());
+
+            if let Some(problem) = problem {
+                warn!("Ticket payout stopped, {:?}", problem);
+                self.lock(|state| state.problem = Some(problem));
+                self.events.record_fault(match problem {
+                    Problem::Empty => Fault::TicketsEmpty,
+                    Problem::Jammed => Fault::TicketJam,
+                });
+            }
+        }
+    }
+
+    /// Pay until nothing is owed, or a problem stops it.
+    async fn dispense<D: Dispenser>(&self, dispenser: &mut D) -> Option<Problem> {
+        let mut unsaved = 0;
+        loop {
+            match select(dispenser.wait_for_notch(), Timer::after(NOTCH_TIMEOUT)).await {
+                Either::First(()) => {}
+                Either::Second(()) if dispenser.at_notch() => return Some(Problem::Jammed),
+                Either::Second(()) => return Some(Problem::Empty),
+            }
+
+            let owed = self.lock(|state| {
+                state.owed = state.owed.saturating_sub(1);
+                state.paid = state.paid.wrapping_add(1);
+                state.owed
+            });
+            self.bookkeeping.record_ticket();
+            if owed == 0 {
+                return None;
+            }
+            unsaved += 1;
+            if unsaved == SAVE_EVERY {
+                unsaved = 0;
+                self.persist.signal(());
+            }
+        }
+    }
+
+    /// Pick up the tickets owed before the last power cut.
+    pub async fn restore<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) {
+        let Some(owed) = storage::load::<u32, _>(storage, Record::TicketsOwed).await else {
+            return;
+        };
+        if owed > 0 {
+            info!("{} tickets still owed", owed);
+            self.lock(|state| state.owed = owed);
+            self.owed.signal(());
+        }
+    }
+
+    /// Save the tickets owed whenever asked to.
+    pub async fn persist<S: Storage>(&self, storage: &Mutex<CriticalSectionRawMutex, S>) -> ! {
+        loop {
+            self.persist.wait().await;
+            let owed = self.lock(|state| state.owed);
+            storage::save(storage, Record::TicketsOwed, &owed).await;
+        }
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub owed: u32,
+    pub paid: u32,
+    pub problem: Option<Problem>,
+}
@@ -1,32 +1,213 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
 use anyhow::Error;
-use edge_nal::TcpBind;
+use edge_nal::{TcpBind, TcpConnect};
 use edge_nal_std::Stack;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
 use embassy_time::{Duration, Timer};
-use futures_lite::future::{block_on, or};
+use futures_lite::future::{block_on, or, pending};
 use log::info;
 use symmetrical_octo_chainsaw_shared::{
-    http::run_server,
-    pac_man_ball::{Inputs, Outputs},
+    arbiter::Arbiter,
+    ball_flow::BallFlow,
+    bookkeeping::Bookkeeping,
+    clock::{Timestamp, WallClock},
+    credits::Credits,
+    events::EventLog,
+    forces::InputForces,
+    health::SensorHealth,
+    http::{
+        auth::{Auth, AuthConfig},
+        ota::Updater,
+        run_server,
+    },
+    metrics::Metrics,
+    modbus::{run_modbus_server, ModbusConfig},
+    mqtt::{self, discovery::Discovery, run_bridge, MqttConfig},
+    pac_man_ball::{Inputs, IoState, Outputs},
+    self_test::SelfTest,
+    storage::{Record, Storage},
+    tachometer::Tachometer,
+    tickets::{Dispenser, Tickets},
 };
 
 fn main() {
-    let ingress_signal: Signal<CriticalSectionRawMutex, Outputs> = Signal::new();
     let egress_signal: Signal<CriticalSectionRawMutex, Inputs> = Signal::new();
+    let clock = WallClock::new();
+
+    // The host clock is already synchronised, so just take it as is.
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    clock.set(Timestamp(now.as_millis() as u64));
+
+    let updater = Mutex::new(FileUpdater::new("firmware-update.bin").unwrap());
+    let operator_pin = std::env::var("OPERATOR_PIN").unwrap_or_else(|_| "0000".into());
+    let viewer_pin = std::env::var("VIEWER_PIN").ok();
+    let auth = Auth::new(AuthConfig {
+        operator_pin: &operator_pin,
+        viewer_pin: viewer_pin.as_deref(),
+        entropy: rand::random::<u64>,
+    });
+    let metrics = Metrics::new();
+    let io_state = IoState::new();
+    let arbiter = Arbiter::new();
+    let forces = InputForces::new();
+    let storage = Mutex::new(FileStorage::new(
+        std::env::var("STATE_DIR").unwrap_or_else(|_| "state".into()),
+    ));
+    block_on(forces.restore(&storage));
+    let bookkeeping = Bookkeeping::new(&clock);
+    block_on(bookkeeping.restore(&storage));
+    let credits = Credits::new(&bookkeeping);
+    block_on(credits.restore(&storage));
+    let events = EventLog::new(&clock);
+    let self_test = SelfTest::new(&arbiter, &io_state, &clock);
+    let tachometer = Tachometer::new(&arbiter, &events, &clock);
+    let ball_flow = BallFlow::new(&events);
+    let health = SensorHealth::new(&events, &tachometer, &ball_flow);
+    let tickets = Tickets::new(&events, &bookkeeping);
+    block_on(tickets.restore(&storage));
+    let modbus_writers = std::env::var("MODBUS_WRITERS").unwrap_or_default();
+
+    let mqtt_inputs_signal: Signal<CriticalSectionRawMutex, Inputs> = Signal::new();
+    let mqtt_outputs_signal: Signal<CriticalSectionRawMutex, Outputs> = Signal::new();
+    let mqtt_broker = std::env::var("MQTT_BROKER").ok();
+    let mqtt_username = std::env::var("MQTT_USERNAME").ok();
+    let mqtt_password = std::env::var("MQTT_PASSWORD").ok();
+    let mqtt_id = std::env::var("MQTT_ID").unwrap_or_else(|_| "std".into());
+    let discovery_prefix =
+        std::env::var("MQTT_DISCOVERY_PREFIX").unwrap_or_else(|_| "homeassistant".into());
+    let mqtt_config = MqttConfig {
+        id: &mqtt_id,
+        username: mqtt_username.as_deref(),
+        password: mqtt_password.as_deref(),
+        discovery: (!discovery_prefix.is_empty()).then_some(Discovery {
+            prefix: &discovery_prefix,
+            sw_version: env!("CARGO_PKG_VERSION"),
+            ip: None,
+        }),
+    };
 
     block_on(or(
-        run(&ingress_signal, &egress_signal),
         or(
-            fake_inputs(&egress_signal),
-            print_outputs(&ingress_signal),
+            run(
+                &egress_signal,
+                &clock,
+                &updater,
+                &auth,
+                &metrics,
+                &arbiter,
+                &forces,
+                &self_test,
+                &tachometer,
+                &ball_flow,
+                &credits,
+                &tickets,
+                &bookkeeping,
+                &events,
+            ),
+            run_modbus_server(
+                || async {
+                    let addr = "0.0.0.0:5020";
+                    info!("Running Modbus server on {addr}");
+                    Stack::new().bind(addr.parse().unwrap()).await
+                },
+                &ModbusConfig {
+                    writers: &modbus_writers,
+                },
+                &io_state,
+                &arbiter,
+                &forces,
+                &metrics,
+            ),
+        ),
+        or(
+            or(
+                fake_inputs(
+                    &forces,
+                    &egress_signal,
+                    &mqtt_inputs_signal,
+                    &io_state,
+                    &metrics,
+                    &events,
+                    &health,
+                    &tachometer,
+                    &ball_flow,
+                    &credits,
+                    &bookkeeping,
+                ),
+                print_outputs(
+                    &arbiter,
+                    &mqtt_outputs_signal,
+                    &io_state,
+                    &metrics,
+                    &events,
+                    &health,
+                    &tachometer,
+                    &ball_flow,
+                ),
+            ),
+            or(
+                or(
+                    or(
+                        or(forces.persist(&storage), credits.persist(&storage)),
+                        or(
+                            tickets.persist(&storage),
+                            tickets.run(&mut FakeDispenser::default()),
+                        ),
+                    ),
+                    or(self_test.run(), bookkeeping.run(&storage)),
+                ),
+                async {
+                    match &mqtt_broker {
+                        Some(broker) => {
+                            run_bridge(
+                                || connect_mqtt(broker),
+                                &mqtt_config,
+                                &mqtt_inputs_signal,
+                                &mqtt_outputs_signal,
+                                &forces,
+                                &arbiter,
+                                &clock,
+                            )
+                            .await
+                        }
+                        None => pending().await,
+                    }
+                },
+            ),
         ),
     ));
 }
 
-pub async fn fake_inputs(egress_signal: &Signal<CriticalSectionRawMutex, Inputs>) -> ! {
+#[allow(clippy::too_many_arguments)]
+pub async fn fake_inputs(
+    forces: &InputForces,
+    egress_signal: &Signal<CriticalSectionRawMutex, Inputs>,
+    mqtt_inputs_signal: &Signal<CriticalSectionRawMutex, Inputs>,
+    io_state: &IoState,
+    metrics: &Metrics,
+    events: &EventLog<'_>,
+    health: &SensorHealth<'_>,
+    tachometer: &Tachometer<'_>,
+    ball_flow: &BallFlow<'_>,
+    credits: &Credits<'_>,
+    bookkeeping: &Bookkeeping<'_>,
+) -> ! {
+    // The table turns once every two seconds while its motor is on.
+    let mut table_sensor = false;
     loop {
         Timer::after(Duration::from_secs(1)).await;
-        egress_signal.signal(Inputs {
+        let table_motor = io_state
+            .outputs()
+            .is_some_and(|outputs| outputs.table_motor);
+        table_sensor = table_motor && !table_sensor;
+        let inputs = Inputs {
             checker_0_sensor: rand::random_bool(0.1),
             checker_1_sensor: rand::random_bool(0.1),
             checker_2_sensor: rand::random_bool(0.1),
@@ -42,27 +223,85 @@ pub async fn fake_inputs(egress_signal: &Signal<CriticalSectionRawMutex, Inputs>
             hopper_left_sensor: rand::random_bool(0.1),
             hopper_right_sensor: rand::random_bool(0.1),
             hopper_out_sensor: rand::random_bool(0.1),
-            table_sensor: rand::random_bool(0.1),
+            table_sensor,
             left_divider_sensor: rand::random_bool(0.1),
             right_divider_sensor: rand::random_bool(0.1),
             test_switch: rand::random_bool(0.1),
             select_switch_up: rand::random_bool(0.1),
             select_switch_down: rand::random_bool(0.1),
             enter_switch: rand::random_bool(0.1),
-        });
+        };
+        let inputs = forces.apply(inputs);
+        metrics.record_inputs(&inputs);
+        io_state.set_inputs(&inputs);
+        events.record_inputs(&inputs, &forces.forces());
+        tachometer.record_inputs(&inputs);
+        ball_flow.record_inputs(&inputs);
+        health.record_inputs(&inputs);
+        bookkeeping.record_inputs(&inputs);
+        // Now and then, someone puts a coin in.
+        if rand::random_bool(0.02) {
+            credits.record_pulse(0, Duration::from_millis(50));
+        }
+        mqtt_inputs_signal.signal(inputs.clone());
+        egress_signal.signal(inputs);
     }
 }
 
-pub async fn print_outputs(ingress_signal: &Signal<CriticalSectionRawMutex, Outputs>) -> ! {
+#[allow(clippy::too_many_arguments)]
+pub async fn print_outputs(
+    arbiter: &Arbiter,
+    mqtt_outputs_signal: &Signal<CriticalSectionRawMutex, Outputs>,
+    io_state: &IoState,
+    metrics: &Metrics,
+    events: &EventLog<'_>,
+    health: &SensorHealth<'_>,
+    tachometer: &Tachometer<'_>,
+    ball_flow: &BallFlow<'_>,
+) -> ! {
+    let mut arbitration = arbiter.subscribe().unwrap();
     loop {
-        let outputs = ingress_signal.wait().await;
+        let outputs = arbitration.changed().await.outputs;
         info!("Outputs: {outputs:?}");
+        metrics.record_outputs(&outputs);
+        io_state.set_outputs(&outputs);
+        events.record_outputs(&outputs);
+        health.record_outputs(&outputs);
+        tachometer.record_outputs(&outputs);
+        ball_flow.record_outputs(&outputs);
+        mqtt_outputs_signal.signal(outputs);
     }
 }
 
+/// Connect to `broker`, a host with an optional port.
+async fn connect_mqtt(broker: &str) -> Result<edge_nal_std::TcpSocket, Error> {
+    let address = if broker.contains(':') {
+        broker.to_socket_addrs()
+    } else {
+        (broker, mqtt::packet::PORT).to_socket_addrs()
+    }?
+    .next()
+    .ok_or_else(|| anyhow::anyhow!("No address for {broker}"))?;
+    info!("Connecting to MQTT broker at {address}");
+    Ok(Stack::new().connect(address).await?)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run<'a>(
-    ingress_signal: &'a Signal<CriticalSectionRawMutex, Outputs>,
     egress_signal: &'a Signal<CriticalSectionRawMutex, Inputs>,
+    clock: &'a WallClock,
+    updater: &'a Mutex<CriticalSectionRawMutex, FileUpdater>,
+    auth: &'a Auth<'a>,
+    metrics: &'a Metrics,
+    arbiter: &'a Arbiter,
+    forces: &'a InputForces,
+    self_test: &'a SelfTest<'a>,
+    tachometer: &'a Tachometer<'a>,
+    ball_flow: &'a BallFlow<'a>,
+    credits: &'a Credits<'a>,
+    tickets: &'a Tickets<'a>,
+    bookkeeping: &'a Bookkeeping<'a>,
+    events: &'a EventLog<'a>,
 ) -> ! {
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
@@ -77,8 +316,131 @@ pub async fn run<'a>(
             let acceptor = Stack::new().bind(addr.parse().unwrap()).await?;
             Ok::<_, Error>(acceptor)
         },
-        ingress_signal,
         egress_signal,
+        clock,
+        updater,
+        auth,
+        metrics,
+        arbiter,
+        forces,
+        self_test,
+        tachometer,
+        ball_flow,
+        credits,
+        tickets,
+        bookkeeping,
+        events,
     )
     .await
 }
+
+/// Stages uploaded firmware in a local file, standing in for the DFU partition.
+pub struct FileUpdater {
+    path: &'static str,
+    file: File,
+}
+
+impl FileUpdater {
+    pub fn new(path: &'static str) -> Result<Self, std::io::Error> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self { path, file })
+    }
+}
+
+impl Updater for FileUpdater {
+    type Error = std::io::Error;
+
+    fn capacity(&self) -> usize {
+        2 * 1024 * 1024
+    }
+
+    async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(data)
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.read_exact(buf)
+    }
+
+    async fn mark_updated(&mut self) -> Result<(), Self::Error> {
+        info!("Update staged in {}", self.path);
+        Ok(())
+    }
+
+    async fn reboot(&mut self) {
+        info!("Reboot requested, ignoring");
+    }
+}
+
+/// A ticket dispenser that never runs out, paying a ticket every 200ms.
+#[derive(Default)]
+pub struct FakeDispenser {
+    running: bool,
+}
+
+impl Dispenser for FakeDispenser {
+    fn set_running(&mut self, running: bool) {
+        info!(
+            "Ticket dispenser {}",
+            if running { "running" } else { "stopped" }
+        );
+        self.running = running;
+    }
+
+    fn at_notch(&mut self) -> bool {
+        false
+    }
+
+    async fn wait_for_notch(&mut self) {
+        if !self.running {
+            pending::<()>().await;
+        }
+        Timer::after(Duration::from_millis(200)).await;
+    }
+}
+
+/// Keeps each record in a file of its own, standing in for flash.
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, record: Record) -> PathBuf {
+        self.dir.join(record.name()).with_extension("bin")
+    }
+}
+
+impl Storage for FileStorage {
+    type Error = std::io::Error;
+
+    async fn load(&mut self, record: Record, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        let data = match fs::read(self.path(record)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(Some(len))
+    }
+
+    async fn save(&mut self, record: Record, data: &[u8]) -> Result<(), Self::Error> {
+        // Written aside and renamed into place, so a crash leaves the old copy.
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(record);
+        let staged = path.with_extension("tmp");
+        fs::write(&staged, data)?;
+        fs::rename(staged, path)
+    }
+}
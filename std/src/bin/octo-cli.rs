@@ -0,0 +1,146 @@
+//! Talks to a cabinet over its WebSocket, for scripts and quick checks from a terminal.
+//!
+//! ```text
+//! octo-cli watch
+//! octo-cli set ray_lamp on
+//! octo-cli pulse payout_solenoid 150ms
+//! octo-cli dump
+//! ```
+
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use clap::{ArgAction, Parser, Subcommand};
+use embassy_time::Duration;
+use futures_lite::future::block_on;
+use serde::Serialize;
+use symmetrical_octo_chainsaw_shared::{
+    arbiter::Layer,
+    clock::Timestamp,
+    http::auth::Role,
+    pac_man_ball::{Inputs, Outputs},
+};
+use symmetrical_octo_chainsaw_std::client::{time_of_day, Client};
+
+#[derive(Parser)]
+#[command(about = "Watch and drive a Pac-Man Ball cabinet over its WebSocket")]
+struct Args {
+    /// The cabinet, as host[:port].
+    #[arg(long, env = "OCTO_HOST", default_value = "localhost:8881")]
+    host: String,
+    /// PIN to log in with. Driving the outputs needs the operator PIN.
+    #[arg(long, env = "OCTO_PIN")]
+    pin: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print inputs as they change, `+` for active and `-` for inactive.
+    Watch,
+    /// Turn an output on or off, leaving the others as they are.
+    Set {
+        output: String,
+        #[arg(value_parser = parse_state, action = ArgAction::Set)]
+        state: bool,
+    },
+    /// Turn an output on for a while, e.g. `150ms` or `2s`, then off.
+    Pulse {
+        output: String,
+        #[arg(value_parser = parse_duration)]
+        duration: Duration,
+    },
+    /// Print the inputs and outputs as JSON.
+    Dump,
+}
+
+#[derive(Serialize)]
+struct Dump {
+    role: Role,
+    timestamp: Option<Timestamp>,
+    inputs: Inputs,
+    outputs: Outputs,
+    /// The outputs something has claimed, and which layer of the arbiter that was.
+    owners: BTreeMap<&'static str, Layer>,
+}
+
+fn main() -> Result<(), Error> {
+    let args = Args::parse();
+    block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    let mut client = Client::connect(&args.host, args.pin.as_deref()).await?;
+
+    match args.command {
+        Command::Watch => {
+            let mut previous = Inputs::default();
+            loop {
+                let (timestamp, inputs) = client.inputs().await?;
+                let changes = Inputs::FIELDS
+                    .iter()
+                    .zip(inputs.values())
+                    .zip(previous.values())
+                    .filter(|((_, active), was)| active != was)
+                    .map(|((field, active), _)| {
+                        format!("{}{field}", if active { '+' } else { '-' })
+                    })
+                    .collect::<Vec<_>>();
+                if !changes.is_empty() {
+                    println!("{} {}", time_of_day(timestamp), changes.join(" "));
+                }
+                previous = inputs;
+            }
+        }
+        Command::Set { output, state } => {
+            client.set(&output, state).await?;
+            client.close().await
+        }
+        Command::Pulse { output, duration } => {
+            client.set(&output, true).await?;
+            // Turn the output off again whatever happened while waiting, so a
+            // failure can't leave e.g. a solenoid energised.
+            let waited = client.wait(duration).await;
+            client.set(&output, false).await?;
+            waited?;
+            client.close().await
+        }
+        Command::Dump => {
+            let (timestamp, inputs) = client.inputs().await?;
+            let dump = Dump {
+                role: client.role(),
+                timestamp,
+                inputs,
+                outputs: client.outputs().clone(),
+                owners: Outputs::FIELDS
+                    .iter()
+                    .zip(client.owners())
+                    .filter_map(|(field, owner)| Some((*field, (*owner)?)))
+                    .collect(),
+            };
+            println!("{}", serde_json::to_string_pretty(&dump)?);
+            client.close().await
+        }
+    }
+}
+
+fn parse_state(state: &str) -> Result<bool, String> {
+    match state {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off".into()),
+    }
+}
+
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (value, scale) = if let Some(ms) = duration.strip_suffix("ms") {
+        (ms, 1)
+    } else if let Some(s) = duration.strip_suffix('s') {
+        (s, 1000)
+    } else {
+        return Err("expected a duration in ms or s, e.g. 150ms".into());
+    };
+    let value = value.parse::<u64>().map_err(|e| e.to_string())?;
+    Ok(Duration::from_millis(value * scale))
+}
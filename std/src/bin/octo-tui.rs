@@ -0,0 +1,509 @@
+//! A terminal dashboard for a cabinet, or the std server, for use on a laptop in the
+//! workshop or over SSH.
+//!
+//! Shows every input and output live, how often each input has been active
+//! lately, and a log of what changed. Outputs are driven from the keyboard.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::Error;
+use clap::Parser;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Timer};
+use futures_lite::future::{block_on, or};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use symmetrical_octo_chainsaw_shared::{
+    arbiter::Owners,
+    clock::Timestamp,
+    forces::Forces,
+    http::{auth::Role, ws::ServerMessage},
+    pac_man_ball::{Inputs, Outputs},
+};
+use symmetrical_octo_chainsaw_std::client::{time_of_day, Client};
+
+/// How long each bar of the activity histograms covers.
+const BUCKET: std::time::Duration = std::time::Duration::from_secs(1);
+/// Bars kept per input, more than any terminal is likely to show.
+const HISTORY: usize = 120;
+/// Lines kept in the event log.
+const LOG_LEN: usize = 200;
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// The server pings every 10s, so this long without a frame means it's gone.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Outputs for the connection thread to send.
+static COMMANDS: Channel<CriticalSectionRawMutex, Command, 4> = Channel::new();
+
+enum Command {
+    /// Turn one output on or off, leaving the others as they are.
+    Set(&'static str, bool),
+    SetAll(Outputs),
+}
+
+#[derive(Parser)]
+#[command(about = "Terminal dashboard for a Pac-Man Ball cabinet")]
+struct Args {
+    /// The cabinet, as host[:port].
+    #[arg(long, env = "OCTO_HOST", default_value = "localhost:8881")]
+    host: String,
+    /// PIN to log in with. Driving the outputs needs the operator PIN.
+    #[arg(long, env = "OCTO_PIN")]
+    pin: Option<String>,
+}
+
+/// What the connection thread tells the UI.
+enum Update {
+    Connecting,
+    Connected {
+        role: Role,
+        outputs: Outputs,
+        owners: Owners,
+    },
+    Message(ServerMessage),
+    Disconnected(String),
+}
+
+enum Status {
+    Connecting,
+    Connected(Role),
+    Disconnected(String),
+}
+
+fn main() -> Result<(), Error> {
+    let args = Args::parse();
+
+    let (updates, rx) = mpsc::channel();
+    let host = args.host.clone();
+    thread::spawn(move || block_on(connection(&host, args.pin.as_deref(), updates)));
+
+    let mut terminal = ratatui::init();
+    let result = App::new(args.host, rx).run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+/// Keep a session open, reconnecting whenever it drops, until the UI goes away.
+async fn connection(host: &str, pin: Option<&str>, updates: mpsc::Sender<Update>) {
+    loop {
+        if updates.send(Update::Connecting).is_err() {
+            return;
+        }
+        let reason = match session(host, pin, &updates).await {
+            Ok(()) => return,
+            Err(e) => format!("{e:#}"),
+        };
+        if updates.send(Update::Disconnected(reason)).is_err() {
+            return;
+        }
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+/// Run one session, returning `Ok` only once the UI has gone away.
+async fn session(
+    host: &str,
+    pin: Option<&str>,
+    updates: &mpsc::Sender<Update>,
+) -> Result<(), Error> {
+    let mut client = Client::connect(host, pin).await?;
+    let connected = Update::Connected {
+        role: client.role(),
+        outputs: client.outputs().clone(),
+        owners: *client.owners(),
+    };
+    if updates.send(connected).is_err() {
+        return Ok(());
+    }
+
+    // Commands that arrived while disconnected are stale.
+    COMMANDS.clear();
+
+    loop {
+        let command = or(
+            async {
+                client.readable().await?;
+                Ok(None)
+            },
+            or(async { Ok(Some(COMMANDS.receive().await)) }, async {
+                Timer::after(RECEIVE_TIMEOUT).await;
+                Err(anyhow::anyhow!(
+                    "Nothing received for {}s",
+                    RECEIVE_TIMEOUT.as_secs()
+                ))
+            }),
+        )
+        .await?;
+
+        match command {
+            Some(Command::Set(field, on)) => client.set(field, on).await?,
+            Some(Command::SetAll(outputs)) => client.set_outputs(outputs).await?,
+            None => {
+                let message = client.recv().await?;
+                if updates.send(Update::Message(message)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+struct App {
+    host: String,
+    updates: mpsc::Receiver<Update>,
+    status: Status,
+    timestamp: Option<Timestamp>,
+    /// The inputs, once the first have arrived this session.
+    inputs: Option<Inputs>,
+    forces: Forces,
+    outputs: Outputs,
+    owners: Owners,
+    selected: ListState,
+    /// Activations of each input per `BUCKET`, oldest first.
+    activity: Vec<VecDeque<u32>>,
+    totals: Vec<u32>,
+    bucket_start: Instant,
+    log: VecDeque<Line<'static>>,
+}
+
+impl App {
+    fn new(host: String, updates: mpsc::Receiver<Update>) -> Self {
+        Self {
+            host,
+            updates,
+            status: Status::Connecting,
+            timestamp: None,
+            inputs: None,
+            forces: Forces::default(),
+            outputs: Outputs::default(),
+            owners: Owners::default(),
+            selected: ListState::default().with_selected(Some(0)),
+            activity: vec![VecDeque::from([0]); Inputs::FIELDS.len()],
+            totals: vec![0; Inputs::FIELDS.len()],
+            bucket_start: Instant::now(),
+            log: VecDeque::new(),
+        }
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> Result<(), Error> {
+        loop {
+            while let Ok(update) = self.updates.try_recv() {
+                self.update(update);
+            }
+            self.advance_buckets();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(std::time::Duration::from_millis(50))? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(())
+                }
+                KeyCode::Up | KeyCode::Char('k') => self.selected.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => self.selected.select_next(),
+                KeyCode::Char(' ') | KeyCode::Enter => self.toggle(),
+                KeyCode::Char('0') => {
+                    self.send(Command::SetAll(Outputs::default()), "All outputs off")
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn update(&mut self, update: Update) {
+        match update {
+            // Keep showing why the last attempt failed while retrying.
+            Update::Connecting if matches!(self.status, Status::Disconnected(_)) => {}
+            Update::Connecting => self.status = Status::Connecting,
+            Update::Connected {
+                role,
+                outputs,
+                owners,
+            } => {
+                self.log(
+                    format!("Connected to {} as {:?}", self.host, role),
+                    Color::Cyan,
+                );
+                self.status = Status::Connected(role);
+                self.outputs = outputs;
+                self.owners = owners;
+                self.inputs = None;
+            }
+            Update::Disconnected(reason) => {
+                if !matches!(self.status, Status::Disconnected(_)) {
+                    self.log(format!("Disconnected: {reason}"), Color::Red);
+                }
+                self.status = Status::Disconnected(reason);
+            }
+            Update::Message(ServerMessage::Inputs {
+                timestamp,
+                inputs,
+                forces,
+            }) => {
+                self.timestamp = timestamp;
+                self.forces = forces;
+                if let Some(previous) = &self.inputs {
+                    self.record_inputs(previous.clone(), &inputs);
+                }
+                self.inputs = Some(inputs);
+            }
+            Update::Message(ServerMessage::Outputs { outputs, owners }) => {
+                self.outputs = outputs;
+                self.owners = owners;
+            }
+            Update::Message(ServerMessage::Error { message, field, .. }) => {
+                let text = match field {
+                    Some(field) => format!("Rejected {field}: {message}"),
+                    None => format!("Rejected: {message}"),
+                };
+                self.log(text, Color::Red);
+            }
+            Update::Message(ServerMessage::Session { .. }) => {}
+        }
+    }
+
+    fn record_inputs(&mut self, previous: Inputs, inputs: &Inputs) {
+        let mut changes = Vec::new();
+        for (i, ((field, active), was)) in Inputs::FIELDS
+            .iter()
+            .zip(inputs.values())
+            .zip(previous.values())
+            .enumerate()
+        {
+            if active == was {
+                continue;
+            }
+            if active {
+                *self.activity[i].back_mut().unwrap() += 1;
+                self.totals[i] += 1;
+                changes.push(Span::styled(format!("+{field} "), Color::Green));
+            } else {
+                changes.push(Span::styled(format!("-{field} "), Color::DarkGray));
+            }
+        }
+        if !changes.is_empty() {
+            let mut line = vec![Span::raw(format!("{} ", time_of_day(self.timestamp)))];
+            line.extend(changes);
+            self.push_log(Line::from(line));
+        }
+    }
+
+    fn toggle(&mut self) {
+        // The selection can run past the end, as it's only clamped when drawn.
+        let Some(field) = self
+            .selected
+            .selected()
+            .and_then(|index| Outputs::FIELDS.get(index).copied())
+        else {
+            return;
+        };
+        let on = !self.outputs.get(field).unwrap_or_default();
+        self.send(
+            Command::Set(field, on),
+            &format!("{field} {}", if on { "on" } else { "off" }),
+        );
+    }
+
+    fn send(&mut self, command: Command, description: &str) {
+        match self.status {
+            Status::Connected(role) if role >= Role::Operator => {}
+            Status::Connected(_) => {
+                return self.log(
+                    "Driving outputs needs the operator PIN, pass --pin".into(),
+                    Color::Red,
+                )
+            }
+            _ => return self.log("Not connected".into(), Color::Red),
+        }
+        let outputs = match &command {
+            Command::Set(field, on) => {
+                let mut outputs = self.outputs.clone();
+                outputs.set(field, *on);
+                outputs
+            }
+            Command::SetAll(outputs) => outputs.clone(),
+        };
+        if COMMANDS.try_send(command).is_err() {
+            return self.log("Still sending, try again".into(), Color::Red);
+        }
+        self.log(description.into(), Color::Yellow);
+        self.outputs = outputs;
+    }
+
+    /// Start a new bar in the histograms for every `BUCKET` that has passed.
+    fn advance_buckets(&mut self) {
+        while self.bucket_start.elapsed() >= BUCKET {
+            self.bucket_start += BUCKET;
+            for history in &mut self.activity {
+                if history.len() == HISTORY {
+                    history.pop_front();
+                }
+                history.push_back(0);
+            }
+        }
+    }
+
+    fn log(&mut self, text: String, color: Color) {
+        let time = time_of_day(self.timestamp);
+        self.push_log(Line::from(vec![
+            Span::raw(format!("{time} ")),
+            Span::styled(text, color),
+        ]));
+    }
+
+    fn push_log(&mut self, line: Line<'static>) {
+        if self.log.len() == LOG_LEN {
+            self.log.pop_back();
+        }
+        self.log.push_front(line);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [status, grid, log, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(Inputs::FIELDS.len() as u16 + 2),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [inputs, outputs] =
+            Layout::horizontal([Constraint::Min(40), Constraint::Length(32)]).areas(grid);
+
+        self.draw_status(frame, status);
+        self.draw_inputs(frame, inputs);
+        self.draw_outputs(frame, outputs);
+
+        let lines = self
+            .log
+            .iter()
+            .cloned()
+            .map(ListItem::new)
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            List::new(lines).block(Block::bordered().title(" Events ")),
+            log,
+        );
+
+        frame.render_widget(
+            Paragraph::new(" ↑/↓ select  space toggle  0 all off  q quit").dark_gray(),
+            help,
+        );
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let status = match &self.status {
+            Status::Connecting => Span::styled("connecting", Color::Yellow),
+            Status::Connected(role) => Span::styled(format!("connected as {role:?}"), Color::Green),
+            Status::Disconnected(reason) => {
+                Span::styled(format!("disconnected: {reason}"), Color::Red)
+            }
+        };
+        let line = Line::from(vec![
+            Span::raw(" octo-tui ").bold(),
+            Span::raw(format!("{}  ", self.host)),
+            status,
+            Span::raw(format!("  {}", time_of_day(self.timestamp))),
+        ]);
+        frame.render_widget(Paragraph::new(line), area);
+    }
+
+    fn draw_inputs(&self, frame: &mut Frame, area: Rect) {
+        const NAME_WIDTH: u16 = 20;
+        const FORCE_WIDTH: u16 = 6;
+        const TOTAL_WIDTH: u16 = 6;
+        // Borders, the indicator and the gaps between the columns.
+        let spark_width = area
+            .width
+            .saturating_sub(NAME_WIDTH + FORCE_WIDTH + TOTAL_WIDTH + 2 + 1 + 4)
+            as usize;
+
+        let values = self.inputs.as_ref().map(Inputs::values);
+        let rows = Inputs::FIELDS.iter().enumerate().map(|(i, field)| {
+            let indicator = match values.map(|values| values[i]) {
+                Some(true) => Span::styled("●", Color::Green),
+                Some(false) => Span::styled("○", Color::DarkGray),
+                None => Span::styled("?", Color::DarkGray),
+            };
+            // Forced inputs stand out, so nobody forgets they aren't real.
+            let (name, force) = match self.forces[i] {
+                Some(force) => (
+                    Line::styled(*field, Color::Magenta),
+                    Line::styled(force.name(), Color::Magenta),
+                ),
+                None => (Line::raw(*field), Line::default()),
+            };
+            Row::new(vec![
+                Line::from(indicator),
+                name,
+                force,
+                Line::styled(sparkline(&self.activity[i], spark_width), Color::Green),
+                Line::raw(self.totals[i].to_string()).right_aligned(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(1),
+                Constraint::Length(NAME_WIDTH),
+                Constraint::Length(FORCE_WIDTH),
+                Constraint::Min(0),
+                Constraint::Length(TOTAL_WIDTH),
+            ],
+        )
+        .block(Block::bordered().title(format!(" Inputs, activations per {}s ", BUCKET.as_secs())));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_outputs(&mut self, frame: &mut Frame, area: Rect) {
+        let items = Outputs::FIELDS
+            .iter()
+            .zip(self.outputs.values())
+            .zip(self.owners)
+            .map(|((field, on), owner)| {
+                let indicator = if on {
+                    Span::styled("● ", Color::Yellow)
+                } else {
+                    Span::styled("○ ", Color::DarkGray)
+                };
+                let mut spans = vec![indicator, Span::raw(*field)];
+                if let Some(owner) = owner {
+                    spans.push(Span::styled(format!(" {}", owner.name()), Color::DarkGray));
+                }
+                ListItem::new(Line::from(spans))
+            });
+        let list = List::new(items)
+            .block(Block::bordered().title(" Outputs "))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.selected);
+    }
+}
+
+/// The latest `width` counts as bars, scaled to the largest.
+fn sparkline(counts: &VecDeque<u32>, width: usize) -> String {
+    let counts = counts.iter().skip(counts.len().saturating_sub(width));
+    let max = counts.clone().copied().max().unwrap_or_default();
+    counts
+        .map(|&count| match count {
+            0 => ' ',
+            _ => BARS[(count * BARS.len() as u32).div_ceil(max) as usize - 1],
+        })
+        .collect()
+}
@@ -0,0 +1,390 @@
+//! A WS client for a cabinet, or the std server standing in for one.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use anyhow::{anyhow, bail, Context, Error};
+use edge_http::io::client::Connection;
+use edge_http::ws::{
+    upgrade_request_headers, MAX_BASE64_KEY_LEN, MAX_BASE64_KEY_RESPONSE_LEN, NONCE_LEN,
+};
+use edge_http::Method;
+use edge_nal::Readable;
+use edge_nal_std::{Stack, TcpSocket};
+use edge_ws::{FrameHeader, FrameType};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use futures_lite::future::or;
+use symmetrical_octo_chainsaw_shared::{
+    arbiter::Owners,
+    clock::Timestamp,
+    http::{
+        auth::{LoginResponse, Role},
+        ws::{CloseCode, ServerMessage},
+    },
+    pac_man_ball::{Inputs, Outputs},
+};
+
+/// Large enough for any message the server sends.
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// A session with a cabinet's WS endpoint, speaking JSON.
+pub struct Client {
+    socket: TcpSocket,
+    buf: Vec<u8>,
+    role: Role,
+    /// The outputs as the server last reported them, or as this client last set them.
+    outputs: Outputs,
+    owners: Owners,
+}
+
+impl Client {
+    /// Log in with `pin`, if given, and open a session with `host`, a host with an
+    /// optional port.
+    pub async fn connect(host: &str, pin: Option<&str>) -> Result<Self, Error> {
+        let addr = resolve(host)?;
+        let stack = Stack::new();
+        let mut buf = vec![0_u8; 2048];
+
+        let token = match pin {
+            Some(pin) => Some(login(&stack, &mut buf, addr, host, pin).await?.token),
+            None => None,
+        };
+        let authorization = token.map(|token| format!("Bearer {token}"));
+
+        let mut conn: Connection<_> = Connection::new(&mut buf, &stack, addr);
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let mut nonce_base64 = [0_u8; MAX_BASE64_KEY_LEN];
+        // Leave out the blank placeholder for the Origin we don't send.
+        let mut headers =
+            upgrade_request_headers(Some(host), None, None, &nonce, &mut nonce_base64)
+                .into_iter()
+                .filter(|(name, _)| !name.is_empty())
+                .collect::<Vec<_>>();
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+        conn.initiate_request(true, Method::Get, "/", &headers)
+            .await?;
+        conn.initiate_response().await?;
+
+        let code = conn.headers()?.code;
+        if code == 401 {
+            bail!("Unauthorized, log in with a PIN");
+        }
+        let mut accept = [0_u8; MAX_BASE64_KEY_RESPONSE_LEN];
+        if !conn.is_ws_upgrade_accepted(&nonce, &mut accept)? {
+            bail!("{host} refused the WebSocket upgrade with {code}");
+        }
+        let (socket, _) = conn.release();
+
+        let mut client = Self {
+            socket,
+            buf: vec![0_u8; MAX_MESSAGE_LEN],
+            role: Role::Viewer,
+            outputs: Outputs::default(),
+            owners: Owners::default(),
+        };
+
+        // The server introduces the session, then tells us the outputs.
+        client.role = match client.recv().await? {
+            ServerMessage::Session { role } => role,
+            message => bail!("Expected a session, got {message:?}"),
+        };
+        match client.recv().await? {
+            ServerMessage::Outputs { .. } => {}
+            message => bail!("Expected outputs, got {message:?}"),
+        }
+
+        Ok(client)
+    }
+
+    /// Wait for the next inputs.
+    pub async fn inputs(&mut self) -> Result<(Option<Timestamp>, Inputs), Error> {
+        loop {
+            if let ServerMessage::Inputs {
+                timestamp, inputs, ..
+            } = self.recv_ok().await?
+            {
+                return Ok((timestamp, inputs));
+            }
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The outputs as the server last reported them, or as this client last set them.
+    pub fn outputs(&self) -> &Outputs {
+        &self.outputs
+    }
+
+    /// Which layer of the cabinet's arbiter drives each output, as last reported.
+    pub fn owners(&self) -> &Owners {
+        &self.owners
+    }
+
+    /// Turn one output on or off, leaving the others as they are.
+    pub async fn set(&mut self, output: &str, on: bool) -> Result<(), Error> {
+        let mut outputs = self.outputs.clone();
+        if !outputs.set(output, on) {
+            bail!(
+                "No output called {output}, expected one of {}",
+                Outputs::FIELDS.join(", ")
+            );
+        }
+        // Only the one output, so as not to undo anyone else's changes to the rest.
+        let payload = serde_json::to_vec(&serde_json::json!({ output: on }))?;
+        self.send_outputs(&payload).await?;
+        self.outputs = outputs;
+        Ok(())
+    }
+
+    /// Set every output.
+    pub async fn set_outputs(&mut self, outputs: Outputs) -> Result<(), Error> {
+        let payload = serde_json::to_vec(&outputs)?;
+        self.send_outputs(&payload).await?;
+        self.outputs = outputs;
+        Ok(())
+    }
+
+    async fn send_outputs(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if self.role < Role::Operator {
+            bail!("Setting outputs needs the operator PIN");
+        }
+        self.send(FrameType::Text(false), payload).await
+    }
+
+    /// Keep the session alive for `duration`, failing if the server rejects anything.
+    pub async fn wait(&mut self, duration: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + duration;
+        loop {
+            // Only waiting for a message is raced, as a message that has started
+            // arriving has to be read to the end.
+            let readable = or(async { self.readable().await.map(|()| true) }, async {
+                Timer::at(deadline).await;
+                Ok(false)
+            })
+            .await?;
+            if !readable {
+                return Ok(());
+            }
+            self.recv_ok().await?;
+        }
+    }
+
+    /// Close the session, once the server has dealt with everything we sent.
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.send(FrameType::Close, &(CloseCode::Normal as u16).to_be_bytes())
+            .await?;
+        // The server answers in order, so any error for what we sent comes before
+        // its Close.
+        loop {
+            match self.recv_ok().await {
+                Err(e) if e.is::<Closed>() => return Ok(()),
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Wait until there is something to receive. Unlike `recv`, this can be
+    /// cancelled without losing part of a message.
+    pub async fn readable(&mut self) -> Result<(), Error> {
+        Ok(self.socket.readable().await?)
+    }
+
+    /// Receive the next message, answering pings on the way.
+    pub async fn recv(&mut self) -> Result<ServerMessage, Error> {
+        loop {
+            let header = FrameHeader::recv(&mut self.socket)
+                .await
+                .context("Connection lost")?;
+            let payload = header
+                .recv_payload(&mut self.socket, &mut self.buf)
+                .await?
+                .to_vec();
+            match header.frame_type {
+                FrameType::Text(false) => {
+                    let message = serde_json::from_slice(&payload)?;
+                    if let ServerMessage::Outputs { outputs, owners } = &message {
+                        self.outputs = outputs.clone();
+                        self.owners = *owners;
+                    }
+                    return Ok(message);
+                }
+                FrameType::Ping => self.send(FrameType::Pong, &payload).await?,
+                FrameType::Close => return Err(Closed.into()),
+                _ => bail!("Unexpected {header}"),
+            }
+        }
+    }
+
+    /// Receive the next message, failing if it says the server rejected something.
+    async fn recv_ok(&mut self) -> Result<ServerMessage, Error> {
+        match self.recv().await? {
+            ServerMessage::Error {
+                code,
+                message,
+                field: Some(field),
+            } => bail!("Server rejected {field}: {message} ({code:?})"),
+            ServerMessage::Error { code, message, .. } => {
+                bail!("Server rejected the request: {message} ({code:?})")
+            }
+            message => Ok(message),
+        }
+    }
+
+    async fn send(&mut self, frame_type: FrameType, payload: &[u8]) -> Result<(), Error> {
+        // Clients must mask every frame.
+        let header = FrameHeader {
+            frame_type,
+            payload_len: payload.len() as _,
+            mask_key: Some(rand::random()),
+        };
+        header.send(&mut self.socket).await?;
+        header.send_payload(&mut self.socket, payload).await?;
+        Ok(())
+    }
+}
+
+/// The server closed the connection.
+#[derive(Debug)]
+pub struct Closed;
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The server closed the connection")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+async fn login(
+    stack: &Stack,
+    buf: &mut [u8],
+    addr: SocketAddr,
+    host: &str,
+    pin: &str,
+) -> Result<LoginResponse, Error> {
+    let mut conn: Connection<_> = Connection::new(buf, stack, addr);
+    let content_length = pin.len().to_string();
+    conn.initiate_request(
+        true,
+        Method::Post,
+        "/login",
+        &[("Host", host), ("Content-Length", &content_length)],
+    )
+    .await?;
+    conn.write_all(pin.as_bytes()).await?;
+    conn.initiate_response().await?;
+
+    let code = conn.headers()?.code;
+    if code != 200 {
+        bail!("Login failed with {code}");
+    }
+    let mut body = Vec::new();
+    let mut chunk = [0_u8; 128];
+    loop {
+        let read = conn.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    conn.close().await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Resolve `host`, a host with an optional port.
+fn resolve(host: &str) -> Result<SocketAddr, Error> {
+    let mut addrs = if host.contains(':') {
+        host.to_socket_addrs()
+    } else {
+        (host, 80).to_socket_addrs()
+    }?;
+    addrs.next().ok_or_else(|| anyhow!("No address for {host}"))
+}
+
+/// The time of day in UTC, e.g. `13:04:05.678`, or dashes if the cabinet's clock
+/// hasn't been set.
+pub fn time_of_day(timestamp: Option<Timestamp>) -> String {
+    let Some(Timestamp(ms)) = timestamp else {
+        return "--:--:--.---".into();
+    };
+    let ms = ms % (24 * 60 * 60 * 1000);
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / (60 * 60 * 1000),
+        ms / (60 * 1000) % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use edge_nal::TcpConnect;
+    use futures_lite::future::block_on;
+
+    use super::*;
+
+    /// A message framed as the server sends it, unmasked.
+    fn frame(message: &ServerMessage) -> Vec<u8> {
+        let payload = serde_json::to_vec(message).unwrap();
+        let mut frame = vec![0x81, 126];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn outputs(on: &str) -> ServerMessage {
+        let mut outputs = Outputs::default();
+        outputs.set(on, true);
+        ServerMessage::Outputs {
+            outputs,
+            owners: Owners::default(),
+        }
+    }
+
+    #[test]
+    fn wait_finishes_a_message_that_has_started_arriving() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = block_on(Stack::new().connect(listener.local_addr().unwrap())).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut client = Client {
+            socket,
+            buf: vec![0_u8; MAX_MESSAGE_LEN],
+            role: Role::Operator,
+            outputs: Outputs::default(),
+            owners: Owners::default(),
+        };
+
+        // Half a message before the wait is up, and the rest after.
+        let first = frame(&outputs("table_motor"));
+        let (head, tail) = first.split_at(first.len() / 2);
+        server.write_all(head).unwrap();
+        let tail = tail.to_vec();
+        let sender = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(200));
+            server.write_all(&tail).unwrap();
+            server
+        });
+        block_on(client.wait(Duration::from_millis(50))).unwrap();
+        assert!(client.outputs().table_motor);
+
+        // The next message is read from its start.
+        let mut server = sender.join().unwrap();
+        server.write_all(&frame(&outputs("ray_lamp"))).unwrap();
+        match block_on(client.recv()).unwrap() {
+            ServerMessage::Outputs { outputs, .. } => {
+                assert!(outputs.ray_lamp && !outputs.table_motor)
+            }
+            message => panic!("Expected outputs, got {message:?}"),
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! Host-side tools for working with a cabinet, shared by the binaries.
+
+pub mod client;
+pub mod remote_io;
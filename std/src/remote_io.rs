@@ -0,0 +1,113 @@
+//! Drives a cabinet over its WS endpoint as if it were local I/O, so that logic
+//! written against `pac_man_ball::Io` can run on a laptop against the real machine.
+//!
+//! ```ignore
+//! let mut io = RemoteIo::new("pacman.local", Some("1234"));
+//! loop {
+//!     let inputs = io.inputs().await?;
+//!     io.set_outputs(game.step(inputs)).await?;
+//! }
+//! ```
+//!
+//! The cabinet pushes the inputs as it reads them, and `inputs` returns the latest,
+//! waiting for the next push if nothing has arrived since the last call. Pings are
+//! only answered while reading, so the logic should read the inputs regularly, as
+//! it would on the machine.
+
+use anyhow::Error;
+use embassy_time::{Duration, Instant, Timer};
+use futures_lite::future::poll_once;
+use log::{info, warn};
+use symmetrical_octo_chainsaw_shared::{
+    http::ws::ServerMessage,
+    pac_man_ball::{Inputs, Io, Outputs},
+};
+
+use crate::client::Client;
+
+/// The least time between connection attempts.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+pub struct RemoteIo {
+    host: String,
+    pin: Option<String>,
+    client: Option<Client>,
+    /// The latest inputs, if they haven't been returned yet.
+    latest: Option<Inputs>,
+    last_attempt: Option<Instant>,
+}
+
+impl RemoteIo {
+    /// Nothing happens until the I/O is first used, and a dropped connection is
+    /// reopened on the next use after an error.
+    pub fn new(host: &str, pin: Option<&str>) -> Self {
+        Self {
+            host: host.into(),
+            pin: pin.map(Into::into),
+            client: None,
+            latest: None,
+            last_attempt: None,
+        }
+    }
+
+    async fn client(&mut self) -> Result<&mut Client, Error> {
+        if self.client.is_none() {
+            if let Some(last_attempt) = self.last_attempt {
+                Timer::at(last_attempt + RECONNECT_DELAY).await;
+            }
+            self.last_attempt = Some(Instant::now());
+            let client = Client::connect(&self.host, self.pin.as_deref()).await?;
+            info!("Connected to {} as {:?}", self.host, client.role());
+            self.client = Some(client);
+            self.latest = None;
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Run `f` on the client, dropping the connection if it fails.
+    async fn with_client<R>(
+        &mut self,
+        f: impl AsyncFnOnce(&mut Client, &mut Option<Inputs>) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.client().await?;
+        let result = f(self.client.as_mut().unwrap(), &mut self.latest).await;
+        if let Err(e) = &result {
+            warn!("Dropping the connection to {}: {e:#}", self.host);
+            self.client = None;
+        }
+        result
+    }
+}
+
+impl Io for RemoteIo {
+    type Error = Error;
+
+    async fn inputs(&mut self) -> Result<Inputs, Self::Error> {
+        self.with_client(async |client, latest| {
+            // Catch up on whatever has been pushed since the last call, then wait
+            // for a push if that was nothing new.
+            while latest.is_none() || poll_once(client.readable()).await.is_some() {
+                match client.recv().await? {
+                    ServerMessage::Inputs { inputs, .. } => *latest = Some(inputs),
+                    ServerMessage::Error { message, field, .. } => {
+                        warn!("Cabinet rejected outputs: {message} {field:?}")
+                    }
+                    _ => {}
+                }
+            }
+            Ok(latest.take().unwrap())
+        })
+        .await
+    }
+
+    async fn set_outputs(&mut self, outputs: Outputs) -> Result<(), Self::Error> {
+        self.with_client(async |client, _| {
+            // Logic tends to set the outputs on every pass, changed or not.
+            if *client.outputs() == outputs {
+                return Ok(());
+            }
+            client.set_outputs(outputs).await
+        })
+        .await
+    }
+}